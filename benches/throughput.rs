@@ -0,0 +1,137 @@
+//! Rough timings for the paths that exist to be fast, run with `cargo bench`.
+//!
+//! Uses no benchmark harness so it builds on stable: each case runs a few
+//! times over a synthetic file in the temporary directory and the best time
+//! is printed. Set `PHSP_BENCH_RECORDS` to change the file size.
+
+extern crate egsphsp;
+
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use egsphsp::{Header, ParticleType, PHSPReader, PHSPWriter, Record, particle_type_of};
+
+const RUNS: usize = 3;
+
+fn fixture(records: usize) -> PathBuf {
+    let path = env::temp_dir().join(format!("phasespace-bench-{}.egsphsp1", records));
+    if fs::metadata(&path).map(|m| m.len()).ok() == Some(28 * (records as u64 + 1)) {
+        return path;
+    }
+    let header = Header::new_mode0(0, 0, records as f32).unwrap();
+    let mut writer = PHSPWriter::create(&path, &header).unwrap();
+    for i in 0..records {
+        // mostly photons, one in eight electrons, one in sixty four positrons
+        let latch = match i % 64 {
+            0 => 1 << 29,
+            n if n % 8 == 0 => 1 << 30,
+            _ => 0,
+        };
+        let energy = 0.1 + (i % 1000) as f32 * 0.006;
+        let record = Record::new(latch, energy, (i % 200) as f32 * 0.1 - 10.0,
+                                 (i % 150) as f32 * 0.1 - 7.5, 0.1, 0.2, 1.0);
+        writer.write(&record).unwrap();
+    }
+    writer.finalize().unwrap();
+    path
+}
+
+fn time<T, F: FnMut() -> T>(name: &str, records: usize, mut case: F) {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let _ = case();
+        best = best.min(start.elapsed());
+    }
+    println!("{:<40} {:>8.1} ms {:>8.1} Mrecords/s",
+             name,
+             best.as_secs_f64() * 1e3,
+             records as f64 / best.as_secs_f64() / 1e6);
+}
+
+fn latch_filter(path: &Path, records: usize) {
+    // most records are photons, few are positrons
+    for &(species, name) in &[(ParticleType::Photon, "photons"), (ParticleType::Positron, "positrons")] {
+        time(&format!("keep {}, decode every record", name), records, || {
+            PHSPReader::open(path)
+                .unwrap()
+                .map(|record| record.unwrap())
+                .filter(|record| record.particle_type() == species)
+                .map(|record| record.x_cm)
+                .sum::<f32>()
+        });
+        time(&format!("keep {}, filter_latch", name), records, || {
+            PHSPReader::open(path)
+                .unwrap()
+                .filter_latch(|latch| particle_type_of(latch) == species)
+                .map(|record| record.unwrap().x_cm)
+                .sum::<f32>()
+        });
+    }
+}
+
+fn buffer_capacity(path: &Path, records: usize) {
+    let output = path.with_extension("copy.egsphsp1");
+    for &capacity in &[8 << 10, 64 << 10, 1 << 20, 8 << 20] {
+        time(&format!("read, {} KiB buffer", capacity >> 10), records, || {
+            PHSPReader::from_with_capacity(File::open(path).unwrap(), capacity)
+                .unwrap()
+                .map(|record| record.unwrap().x_cm)
+                .sum::<f32>()
+        });
+        time(&format!("copy, {} KiB buffers", capacity >> 10), records, || {
+            let mut reader = PHSPReader::from_with_capacity(File::open(path).unwrap(), capacity)
+                .unwrap();
+            let mut writer = PHSPWriter::from_with_capacity(File::create(&output).unwrap(),
+                                                            &reader.header,
+                                                            capacity)
+                .unwrap();
+            for record in &mut reader {
+                writer.write(&record.unwrap()).unwrap();
+            }
+            writer.finalize().unwrap();
+        });
+    }
+    fs::remove_file(&output).unwrap();
+}
+
+#[cfg(feature = "parallel")]
+fn statistics(path: &Path, records: usize) {
+    time("statistics", records, || egsphsp::statistics(path).unwrap());
+    time("statistics_parallel", records, || egsphsp::statistics_parallel(path).unwrap());
+}
+
+#[cfg(not(feature = "parallel"))]
+fn statistics(path: &Path, records: usize) {
+    time("statistics (parallel feature off)", records, || egsphsp::statistics(path).unwrap());
+}
+
+fn sample(path: &Path, records: usize) {
+    // four inputs so there is something to spread over the threads
+    let inputs = [path; 4];
+    let output = path.with_extension("sample.egsphsp1");
+    time("sample 4 inputs at 1 in 4", 4 * records, || {
+        egsphsp::sample(&inputs, &output, 4, 0).unwrap()
+    });
+    for &threads in &[1, 2, 4] {
+        time(&format!("sample_parallel on {} threads", threads), 4 * records, || {
+            egsphsp::sample_parallel(&inputs, &output, 4, 0, threads).unwrap()
+        });
+    }
+    fs::remove_file(&output).unwrap();
+}
+
+fn main() {
+    // `cargo bench` passes --bench, nothing here takes arguments
+    let records = env::var("PHSP_BENCH_RECORDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(2_000_000);
+    let path = fixture(records);
+    latch_filter(&path, records);
+    buffer_capacity(&path, records);
+    statistics(&path, records);
+    sample(&path, records);
+}
@@ -2,16 +2,28 @@ extern crate clap;
 extern crate egsphsp;
 extern crate rand;
 extern crate cpu_time;
+#[cfg(test)]
+extern crate serde_json;
 
-use std::path::Path;
-use std::error::Error;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::f32;
-use std::fs::File;
 use clap::{App, AppSettings, SubCommand, Arg};
-use egsphsp::PHSPReader;
-use egsphsp::{transform, Transform, combine,sample};
-use rand::Rng;
+use egsphsp::{PHSPReader, Header, Record, Scan, scan, RecordCount, count_records};
+use egsphsp::{transform, Transform, ClampMode, combine, sample_exact, split};
+use egsphsp::{sample_by_type, sample_parallel, validate, c_layout, peaks};
+use egsphsp::{energy_spectrum_weighted, diff, centroid, fluence_map, angular_histogram};
+use egsphsp::{sample_with_progress, transform_with_progress};
+use egsphsp::{combine_tagged, combine_sorted, combine_filtered, combine_resumable, SortKey};
+use egsphsp::{angular_smear, clamp_radius, clamp_energy, shift_energy};
+use egsphsp::{russian_roulette, resample_to, convert_mode, flip_z, grid_thin, sample_stratified};
+use egsphsp::{combine_dedup, combine_interleaved, build_index, is_gzip, extract_range};
+use egsphsp::{energy_extents_by_type, ParticleType, EGSError, EGSResult, ByteOrderMode};
+use egsphsp::{particle_type_of, region_index_of, bremsstrahlung_or_annihilation_of};
+use egsphsp::{audit_weights, repair_weights, combine_weighted, combined_size, sampled_size};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use cpu_time::ProcessTime;
 use std::time::Duration;
 
@@ -19,6 +31,241 @@ fn floatify(s: &str) -> f32 {
     s.trim().trim_start_matches("(").trim_end_matches(")").trim().parse::<f32>().unwrap()
 }
 
+fn output_path_for(path: &str, gzip: bool) -> PathBuf {
+    let path = PathBuf::from(path);
+    if gzip && !is_gzip(&path) {
+        PathBuf::from(format!("{}.gz", path.display()))
+    } else {
+        path
+    }
+}
+
+// `-` is stdin for the commands that read their input once, front to back.
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+// Warns when the file isn't the length its header gives, it is read anyway.
+fn open_input(path: &Path) -> EGSResult<PHSPReader> {
+    let reader = if is_stdio(path) {
+        PHSPReader::from_stdin()?
+    } else {
+        PHSPReader::open(path)?
+    };
+    match reader.file_length() {
+        Some(length) if length != reader.header.expected_file_size() => {
+            eprintln!("Expected {} bytes in file, not {}",
+                      reader.header.expected_file_size(),
+                      length);
+        }
+        _ => (),
+    }
+    Ok(reader)
+}
+
+// Sampling normalises by the incident particles of each input, say when one
+// has none and `Header::effective_source_particles` has to guess. Inputs that
+// can't be opened are left for the sampling itself to report.
+fn warn_missing_source_particles(input_paths: &[&Path]) {
+    for path in input_paths.iter() {
+        if let Ok(reader) = PHSPReader::open(path) {
+            if !reader.header.has_source_particles() {
+                eprintln!("No incident particles from source in {}, assuming {}, one per particle",
+                          path.display(),
+                          reader.header.total_particles);
+            }
+        }
+    }
+}
+
+// A record field selected by name for `print`.
+enum Field {
+    Float(f32),
+    Bool(bool),
+    Missing,
+}
+
+impl Field {
+    fn of(record: &Record, name: &str) -> Field {
+        match name {
+            "weight" => Field::Float(record.get_weight()),
+            "energy" => Field::Float(record.total_energy()),
+            "x" => Field::Float(record.x_cm),
+            "y" => Field::Float(record.y_cm),
+            "x_cos" => Field::Float(record.x_cos),
+            "y_cos" => Field::Float(record.y_cos),
+            "produced" => Field::Bool(record.bremsstrahlung_or_annihilation()),
+            "charged" => Field::Bool(record.charged()),
+            "r" => Field::Float(record.radius()),
+            "zlast" => record.zlast.map_or(Field::Missing, Field::Float),
+            _ => panic!("Unknown field {}", name)
+        }
+    }
+
+    fn text(&self) -> String {
+        match *self {
+            Field::Float(value) => value.to_string(),
+            Field::Bool(value) => value.to_string(),
+            Field::Missing => "-".to_string(),
+        }
+    }
+
+    // JSON has no NaN or infinity so those become null too
+    fn json(&self) -> String {
+        match *self {
+            Field::Float(value) if value.is_finite() => value.to_string(),
+            Field::Bool(value) => value.to_string(),
+            _ => "null".to_string(),
+        }
+    }
+}
+
+// One record of `print --format json`, every stored field unless some were picked.
+fn record_json(record: &Record, fields: &[&str]) -> String {
+    if fields.is_empty() {
+        return record.to_json();
+    }
+    let values: Vec<String> = fields.iter()
+        .map(|field| format!("\"{}\": {}", field, Field::of(record, field).json()))
+        .collect();
+    format!("{{{}}}", values.join(", "))
+}
+
+// One line per field of a raw record for `hexdump`: the byte range, the bytes
+// in hex, the name and the value they decode to.
+fn hexdump_fields(bytes: &[u8], big_endian: bool) -> Vec<String> {
+    let names = ["latch", "energy", "x_cm", "y_cm", "x_cos", "y_cos", "weight", "zlast"];
+    names.iter()
+        .zip(bytes.chunks(4))
+        .enumerate()
+        .map(|(i, (name, field))| {
+            let hex: Vec<String> = field.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let word = [field[0], field[1], field[2], field[3]];
+            let word = if big_endian { u32::from_be_bytes(word) } else { u32::from_le_bytes(word) };
+            let value = if i == 0 {
+                format!("0x{:08x}", word)
+            } else {
+                f32::from_bits(word).to_string()
+            };
+            format!("{:>2}..{:<2}  {}  {:<8} {}", i * 4, i * 4 + 4, hex.join(" "), name, value)
+        })
+        .collect()
+}
+
+// The number of records of `path` matching every criterion given to `count`.
+fn count_matching(path: &Path,
+                  charged: bool,
+                  photons: bool,
+                  produced: bool,
+                  region: Option<u32>)
+                  -> EGSResult<u64> {
+    let mut reader = PHSPReader::open(path)?;
+    let mut count = 0u64;
+    let matching = reader.filter_latch(|latch| {
+        let photon = particle_type_of(latch) == ParticleType::Photon;
+        !((charged && photon) || (photons && !photon) ||
+          (produced && !bremsstrahlung_or_annihilation_of(latch)) ||
+          region.is_some_and(|region| region_index_of(latch) != region))
+    });
+    for record in matching {
+        record?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Draw a bar on stderr, redrawn only when the percentage changes.
+fn progress_bar() -> impl FnMut(u64, u64) {
+    let mut shown = None;
+    move |done, total| {
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        if shown != Some(percent) {
+            shown = Some(percent);
+            let filled = (percent / 2) as usize;
+            eprint!("\r[{}{}] {:>3}%", "#".repeat(filled), " ".repeat(50 - filled), percent);
+            if done >= total {
+                eprintln!();
+            }
+        }
+    }
+}
+
+// Extents by type, photons first, leaving out types with none.
+fn type_extents(extents: &HashMap<ParticleType, (f32, f32)>) -> Vec<(&'static str, f32, f32)> {
+    [(ParticleType::Photon, "photon"),
+     (ParticleType::Electron, "electron"),
+     (ParticleType::Positron, "positron")]
+        .iter()
+        .filter_map(|&(kind, name)| extents.get(&kind).map(|&(min, max)| (name, min, max)))
+        .collect()
+}
+
+fn print_info(header: &Header,
+              scanned: Option<Scan>,
+              extents: Option<HashMap<ParticleType, (f32, f32)>>,
+              counted: Option<RecordCount>,
+              json: bool) {
+    if json {
+        if scanned.is_none() && extents.is_none() && counted.is_none() {
+            println!("{}", header.to_json());
+            return;
+        }
+        let mut object = format!("{{\"header\": {}", header.to_json());
+        if let Some(scanned) = scanned {
+            object.push_str(&format!(", \"scan\": {}", scanned.to_json()));
+        }
+        if let Some(counted) = counted {
+            object.push_str(&format!(", \"count\": {}", counted.to_json()));
+        }
+        if let Some(extents) = extents {
+            let types: Vec<String> = type_extents(&extents)
+                .iter()
+                .map(|&(name, min, max)| {
+                    format!("\"{}\": {{\"min_energy\": {}, \"max_energy\": {}}}", name, min, max)
+                })
+                .collect();
+            object.push_str(&format!(", \"energy_by_type\": {{{}}}", types.join(", ")));
+        }
+        println!("{}}}", object);
+    } else {
+        println!("{}", header);
+        if let Some(scanned) = scanned {
+            println!("Scanned records: {}", scanned.records);
+            println!("Histories that scored a particle: {} (of {:.*} incident)",
+                     scanned.first_histories,
+                     1,
+                     header.incident_histories());
+            if scanned.first_histories as f32 > header.incident_histories() {
+                println!("Warning: more histories scored than the header says were incident");
+            }
+            println!("Bremsstrahlung/annihilation flag set: {}, clear: {}",
+                     scanned.produced,
+                     scanned.records - scanned.produced);
+            println!("Bit 29 set: {}, clear: {}",
+                     scanned.b29,
+                     scanned.records - scanned.b29);
+        }
+        if let Some(extents) = extents {
+            for (name, min, max) in type_extents(&extents) {
+                let mut label = name.to_string();
+                label[..1].make_ascii_uppercase();
+                println!("{} energy: {:.*} to {:.*} MeV", label, 4, min, 4, max);
+            }
+        }
+        if let Some(counted) = counted {
+            println!("Counted particles: {} (header says {})",
+                     counted.particles,
+                     counted.header_particles);
+            println!("Counted photons: {} (header says {})",
+                     counted.photons,
+                     counted.header_photons);
+            if !counted.particles_match() || !counted.photons_match() {
+                println!("Warning: the header counts don't match the records in the file");
+            }
+        }
+    }
+}
+
 fn main() {
     let matches = App::new("phasespace")
         .version("0.0.1")
@@ -32,13 +279,51 @@ fn main() {
                 .long("field")
                 .short("f")
                 .takes_value(true)
-                .required(true)
                 .multiple(true))
             .arg(Arg::with_name("number")
                 .long("number")
                 .short("n")
                 .takes_value(true)
                 .default_value("10"))
+            .arg(Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .help("With -n 1 print every decoded field and latch flag of the record"))
+            .arg(Arg::with_name("format")
+                .default_value("human")
+                .possible_values(&["human", "json"])
+                .long("format")
+                .takes_value(true)
+                .help("Print columns or a json array of objects"))
+            .arg(Arg::with_name("input")
+                .takes_value(true)
+                .required(true)
+                .help("Phase space file, - to read stdin")))
+        .subcommand(SubCommand::with_name("hexdump")
+            .about("Print the stored bytes of one record field by field")
+            .arg(Arg::with_name("record")
+                .long("record")
+                .short("r")
+                .takes_value(true)
+                .required(true)
+                .help("Record to dump, counting from zero"))
+            .arg(Arg::with_name("input")
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("latch")
+            .about("Decode the latch of records bit by bit")
+            .arg(Arg::with_name("record")
+                .long("record")
+                .short("r")
+                .takes_value(true)
+                .conflicts_with("number")
+                .help("Record to decode, counting from zero"))
+            .arg(Arg::with_name("number")
+                .long("number")
+                .short("n")
+                .takes_value(true)
+                .default_value("1")
+                .help("Decode this many records from the start"))
             .arg(Arg::with_name("input")
                 .takes_value(true)
                 .required(true)))
@@ -53,7 +338,11 @@ fn main() {
                 .takes_value(true)
                 .long("iterations")
                 .required(true)
-                .help("Number of iterations")))
+                .help("Number of iterations"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("Seed as an unsigned integer, random angles differ every run without it")))
         .subcommand(SubCommand::with_name("sample")
             .about("Sample particles from phase space - does not \
                     adjust weights")
@@ -75,16 +364,272 @@ fn main() {
                 .required(false)
                 .long("rate")
                 .takes_value(true)
-                .help("Inverse sample rate - 10 means take rougly 1 out of every 10 particles")))
+                .help("Inverse sample rate - 10 means take rougly 1 out of every 10 particles"))
+            .arg(Arg::with_name("photon-rate")
+                .long("photon-rate")
+                .takes_value(true)
+                .help("Inverse sample rate for photons, defaults to --rate"))
+            .arg(Arg::with_name("electron-rate")
+                .long("electron-rate")
+                .takes_value(true)
+                .help("Inverse sample rate for electrons and positrons, defaults to --rate"))
+            .arg(Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .conflicts_with_all(&["count", "photon-rate", "electron-rate"])
+                .help("Sample the inputs concurrently on this many threads, picks differ from \
+                       the sequential sample for the same seed"))
+            .arg(Arg::with_name("count")
+                .long("count")
+                .takes_value(true)
+                .help("Take exactly this many particles instead of sampling at --rate"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .conflicts_with_all(&["count", "threads", "photon-rate", "electron-rate"])
+                .help("Read everything and report what would happen, without writing"))
+            .arg(Arg::with_name("gzip")
+                .long("gzip")
+                .help("Compress the output, appending .gz to its name if missing")))
+        .subcommand(SubCommand::with_name("angular-smear")
+            .about("Add gaussian noise to the polar angle of every particle")
+            .arg(Arg::with_name("sigma")
+                .long("sigma")
+                .takes_value(true)
+                .required(true)
+                .help("Standard deviation of the noise in radians"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .default_value("0")
+                .help("Seed as an unsigned integer"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("convert")
+            .about("Rewrite as MODE0 or MODE2, adding or dropping zlast")
+            .arg(Arg::with_name("to")
+                .long("to")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&["mode0", "mode2"]))
+            .arg(Arg::with_name("zlast")
+                .long("zlast")
+                .takes_value(true)
+                .default_value("0")
+                .help("zlast in cm for records that don't have one"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("resample")
+            .about("Resample to a fixed number of equally weighted particles")
+            .arg(Arg::with_name("target")
+                .long("target")
+                .short("n")
+                .takes_value(true)
+                .required(true)
+                .help("Number of particles to write"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .default_value("0")
+                .help("Seed as an unsigned integer"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("grid-thin")
+            .about("Cap the number of particles in each cell of an x/y grid")
+            .arg(Arg::with_name("cell-size")
+                .long("cell-size")
+                .takes_value(true)
+                .required(true)
+                .help("Width of the square cells in cm"))
+            .arg(Arg::with_name("max-per-cell")
+                .long("max-per-cell")
+                .takes_value(true)
+                .required(true)
+                .help("Particles to keep in a cell holding more, their weights are scaled \
+                       up to keep the cell's total"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .default_value("0")
+                .help("Seed as an unsigned integer"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("roulette")
+            .about("Thin out particles below a weight by Russian roulette")
+            .arg(Arg::with_name("threshold")
+                .long("threshold")
+                .takes_value(true)
+                .required(true)
+                .help("Weight survivors are raised to, heavier particles are kept as they are"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .default_value("0")
+                .help("Seed as an unsigned integer"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("sample-stratified")
+            .about("Sample each energy band at its own rate, scaling weights to keep fluence")
+            .arg(Arg::with_name("band")
+                .long("band")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(true)
+                .help("min:max:rate, keep 1 in rate particles with total energy in [min, max) \
+                       MeV, the first matching band wins and particles in none are dropped"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .default_value("0")
+                .help("Seed as an unsigned integer"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("clamp-radius")
+            .about("Drop particles beyond a radius, or move them onto it")
+            .arg(Arg::with_name("max")
+                .long("max")
+                .takes_value(true)
+                .required(true)
+                .help("Maximum radius in cm"))
+            .arg(Arg::with_name("clamp")
+                .long("clamp")
+                .help("Project outside particles onto the circle instead of dropping them"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("clamp-energy")
+            .about("Drop particles above an energy, or lower them to it")
+            .arg(Arg::with_name("max")
+                .long("max")
+                .takes_value(true)
+                .required(true)
+                .help("Maximum total energy in MeV"))
+            .arg(Arg::with_name("clamp")
+                .long("clamp")
+                .help("Lower the energy of particles above it instead of dropping them"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("shift-energy")
+            .about("Add a constant to every particle's energy")
+            .arg(Arg::with_name("delta")
+                .long("delta")
+                .takes_value(true)
+                .required(true)
+                .allow_hyphen_values(true)
+                .help("MeV to add, energies that would go below zero stop at zero"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("flip-z")
+            .about("Reverse the z direction of every particle")
+            .arg(Arg::with_name("zlast")
+                .long("zlast")
+                .help("Negate zlast as well, MODE2 only"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("size")
+            .about("Estimate the size of the file combine or sample would write, to check for \
+                    disk space first")
+            .arg(Arg::with_name("input")
+                .required(true)
+                .multiple(true))
+            .arg(Arg::with_name("operation")
+                .long("operation")
+                .takes_value(true)
+                .possible_values(&["combine", "sample"])
+                .default_value("combine")
+                .help("Exact for combine, sample sizes vary with the seed so are estimated"))
+            .arg(Arg::with_name("rate")
+                .long("rate")
+                .takes_value(true)
+                .default_value("10")
+                .help("Inverse sample rate, as for sample"))
+            .arg(Arg::with_name("photon-rate")
+                .long("photon-rate")
+                .takes_value(true)
+                .help("Inverse sample rate for photons, defaults to --rate"))
+            .arg(Arg::with_name("electron-rate")
+                .long("electron-rate")
+                .takes_value(true)
+                .help("Inverse sample rate for electrons and positrons, defaults to --rate")))
+        .subcommand(SubCommand::with_name("weights")
+            .about("Count zero and non-finite weights, optionally writing a repaired copy")
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("repair")
+                .long("repair")
+                .short("o")
+                .takes_value(true)
+                .help("Write a copy with every zero weight made the smallest positive weight"))
+            .arg(Arg::with_name("direction")
+                .long("direction")
+                .takes_value(true)
+                .possible_values(&["+z", "-z"])
+                .requires("repair")
+                .help("Direction for repaired weights, by default the sign each zero was \
+                       stored with")))
         .subcommand(SubCommand::with_name("info")
             .about("Basic information on phase space file")
-            .arg(Arg::with_name("input").required(true))
+            .arg(Arg::with_name("input")
+                .required(true)
+                .help("Phase space file, - to read stdin"))
             .arg(Arg::with_name("format")
                 .default_value("human")
                 .possible_values(&["human", "json"])
                 .long("format")
                 .takes_value(true)
-                .help("Output information in json or human format")))
+                .help("Output information in json or human format"))
+            .arg(Arg::with_name("scan")
+                .long("scan")
+                .alias("stats")
+                .help("Read every record to count what the header doesn't say, such as \
+                       bremsstrahlung/annihilation products and primary histories"))
+            .arg(Arg::with_name("by-type")
+                .long("by-type")
+                .help("Read every record for the energy range of each particle type"))
+            .arg(Arg::with_name("verify-count")
+                .long("verify-count")
+                .help("Read the file to its end and compare the particle and photon counts \
+                       found with the header's, exiting with an error if they differ")))
         .subcommand(SubCommand::with_name("combine")
             .about("Combine phase space from one or more input files into outputfile")
             .arg(Arg::with_name("input")
@@ -98,7 +643,223 @@ fn main() {
             .arg(Arg::with_name("delete")
                 .short("d")
                 .long("delete")
-                .help("Delete input files as they are used (no going back!)")))
+                .help("Delete input files as they are used (no going back!)"))
+            .arg(Arg::with_name("dedup")
+                .long("dedup")
+                .help("Drop records that are byte for byte copies of an earlier one"))
+            .arg(Arg::with_name("bloom-bits")
+                .long("bloom-bits")
+                .takes_value(true)
+                .requires("dedup")
+                .help("Deduplicate approximately with a Bloom filter of this many bits"))
+            .arg(Arg::with_name("regions")
+                .long("regions")
+                .takes_value(true)
+                .use_delimiter(true)
+                .conflicts_with("dedup")
+                .help("Comma separated region to tag each input's records with, one per \
+                       input, - leaves an input's regions alone"))
+            .arg(Arg::with_name("sort-by")
+                .long("sort-by")
+                .takes_value(true)
+                .possible_values(&["energy", "radius", "region"])
+                .conflicts_with_all(&["dedup", "regions", "delete"])
+                .help("Order the output by this key, smallest first"))
+            .arg(Arg::with_name("photons-only")
+                .long("photons-only")
+                .conflicts_with_all(&["dedup", "regions", "sort-by"])
+                .help("Leave out electrons and positrons"))
+            .arg(Arg::with_name("interleave")
+                .long("interleave")
+                .conflicts_with_all(&["dedup", "regions", "sort-by", "photons-only"])
+                .help("Take one record from each input in turn instead of each input whole"))
+            .arg(Arg::with_name("mix")
+                .long("mix")
+                .takes_value(true)
+                .use_delimiter(true)
+                .conflicts_with_all(&["dedup", "regions", "sort-by", "photons-only", "interleave"])
+                .help("Comma separated relative weight of each input, record weights are \
+                       scaled so the output is this mix of each input's per history result"))
+            .arg(Arg::with_name("resume")
+                .long("resume")
+                .conflicts_with_all(&["dedup", "regions", "sort-by", "photons-only", "interleave",
+                                      "mix", "gzip"])
+                .help("Note progress in <output>.progress as it goes and carry on from there \
+                       if an earlier run with --resume was interrupted"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .conflicts_with_all(&["dedup", "sort-by", "photons-only", "interleave", "mix",
+                                      "resume"])
+                .help("Read everything and report what would happen, without writing or deleting"))
+            .arg(Arg::with_name("gzip")
+                .long("gzip")
+                .help("Compress the output, appending .gz to its name if missing")))
+        .subcommand(SubCommand::with_name("split")
+            .about("Split phase space into chunks of (nearly) equal particle counts")
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("parts")
+                .short("n")
+                .long("parts")
+                .takes_value(true)
+                .required(true)
+                .help("Number of files to split into"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output-prefix")
+                .takes_value(true)
+                .required(true)
+                .help("Chunks are written to <prefix>_1.egsphsp1, <prefix>_2.egsphsp1, ...")))
+        .subcommand(SubCommand::with_name("extract")
+            .about("Copy a run of consecutive records into a new file")
+            .arg(Arg::with_name("start")
+                .long("start")
+                .takes_value(true)
+                .default_value("0")
+                .help("First record to copy, counting from zero"))
+            .arg(Arg::with_name("end")
+                .long("end")
+                .takes_value(true)
+                .required(true)
+                .help("Record to stop before"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("index")
+            .about("Write a sidecar index used to validate random access")
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .help("Index file, defaults to <input>.idx")))
+        .subcommand(SubCommand::with_name("histogram")
+            .about("Histogram the energy spectrum")
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("bins")
+                .long("bins")
+                .takes_value(true)
+                .default_value("100")
+                .help("Number of energy bins"))
+            .arg(Arg::with_name("min")
+                .long("min")
+                .takes_value(true)
+                .help("Lowest energy in MeV, defaults to the lowest in the file"))
+            .arg(Arg::with_name("max")
+                .long("max")
+                .takes_value(true)
+                .help("Highest energy in MeV, defaults to the highest in the file"))
+            .arg(Arg::with_name("weighted")
+                .long("weighted")
+                .help("Sum particle weights in each bin instead of counting particles")))
+        .subcommand(SubCommand::with_name("angles")
+            .about("Histogram the angle particles make with the z axis")
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("bins")
+                .long("bins")
+                .takes_value(true)
+                .default_value("90")
+                .help("Number of bins from 0 to 90 degrees"))
+            .arg(Arg::with_name("weighted")
+                .long("weighted")
+                .help("Sum particle weights in each bin instead of counting particles")))
+        .subcommand(SubCommand::with_name("fluence")
+            .about("Map the weighted particle fluence over the x/y plane")
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("extent")
+                .long("extent")
+                .takes_value(true)
+                .required(true)
+                .help("Half width in cm of the square mapped, centred on the axis"))
+            .arg(Arg::with_name("nx")
+                .long("nx")
+                .takes_value(true)
+                .default_value("100")
+                .help("Number of bins in x"))
+            .arg(Arg::with_name("ny")
+                .long("ny")
+                .takes_value(true)
+                .default_value("100")
+                .help("Number of bins in y"))
+            .arg(Arg::with_name("edge")
+                .long("edge")
+                .help("Add particles outside the square to the nearest edge bin instead of \
+                       dropping them"))
+            .arg(Arg::with_name("format")
+                .default_value("human")
+                .possible_values(&["human", "csv"])
+                .long("format")
+                .takes_value(true)
+                .help("One line per bin, or the grid as CSV with a row per y bin from -y \
+                       up and a column per x bin from -x across")))
+        .subcommand(SubCommand::with_name("peaks")
+            .about("Report peaks in the energy spectrum")
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("bin-width")
+                .long("bin-width")
+                .takes_value(true)
+                .default_value("0.005")
+                .help("Histogram bin width in MeV"))
+            .arg(Arg::with_name("prominence")
+                .long("prominence")
+                .takes_value(true)
+                .default_value("0.5")
+                .help("Minimum prominence as a fraction of the tallest bin")))
+        .subcommand(SubCommand::with_name("diff")
+            .about("Compare the headers and records of two phase space files")
+            .arg(Arg::with_name("first")
+                .required(true))
+            .arg(Arg::with_name("second")
+                .required(true)))
+        .subcommand(SubCommand::with_name("count")
+            .about("Count the records matching every given latch criterion")
+            .arg(Arg::with_name("charged")
+                .long("charged")
+                .conflicts_with("photons")
+                .help("Electrons and positrons"))
+            .arg(Arg::with_name("photons")
+                .long("photons")
+                .help("Photons, as counted in the header"))
+            .arg(Arg::with_name("region")
+                .long("region")
+                .takes_value(true)
+                .help("Region number from latch bits 24-28"))
+            .arg(Arg::with_name("produced")
+                .long("produced")
+                .help("Bremsstrahlung or annihilation flag set"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("validate")
+            .about("Check every record of a phase space file against its header")
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("emit-layout")
+            .about("Print declarations of the binary layout used by a phase space file")
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("lang")
+                .long("lang")
+                .takes_value(true)
+                .possible_values(&["c"])
+                .default_value("c")
+                .help("Language to emit")))
         .subcommand(SubCommand::with_name("shout")
             .about("Combine phase space files from twist algorithm")
             .arg(Arg::with_name("input")
@@ -115,12 +876,54 @@ fn main() {
                 .short("i")
                 .long("in-place")
                 .help("Transform input file in-place"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Read everything and report what would happen, without writing or deleting"))
             .arg(Arg::with_name("angle")
                 .short("a")
                 .long("angle")
                 .takes_value(true)
                 .required(true)
                 .help("Counter clockwise angle in radians to rotate around Z axis"))
+            .arg(Arg::with_name("about-x")
+                .long("about-x")
+                .takes_value(true)
+                .default_value("0")
+                .help("X of the point to rotate about in cm"))
+            .arg(Arg::with_name("about-y")
+                .long("about-y")
+                .takes_value(true)
+                .default_value("0")
+                .help("Y of the point to rotate about in cm"))
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required_unless("in-place")))
+        .subcommand(SubCommand::with_name("recenter")
+            .about("Translate so the weighted centroid of the positions is at the origin")
+            .arg(Arg::with_name("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .help("Output file")
+                .required(true)))
+        .subcommand(SubCommand::with_name("transform")
+            .about("Apply a chain of operations, e.g. --ops rotate:1.57,translate:2,0,scale:1,-1")
+            .arg(Arg::with_name("in-place")
+                .short("i")
+                .long("in-place")
+                .help("Transform input file in-place"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Read everything and report what would happen, without writing or deleting"))
+            .arg(Arg::with_name("ops")
+                .long("ops")
+                .takes_value(true)
+                .required(true)
+                .help("Comma separated rotate:theta, translate:x,y and scale:x,y operations, \
+                       applied left to right"))
             .arg(Arg::with_name("input")
                 .help("Phase space file")
                 .required(true))
@@ -134,48 +937,362 @@ fn main() {
         let sub_matches = matches.subcommand_matches("combine").unwrap();
         let input_paths: Vec<&Path> = sub_matches.values_of("input")
             .unwrap()
-            .map(|s| Path::new(s))
+            .map(Path::new)
             .collect();
-        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let output_path = output_path_for(sub_matches.value_of("output").unwrap(),
+                                          sub_matches.is_present("gzip"));
         println!("combine {} files into {}",
                  input_paths.len(),
                  output_path.display());
-        combine(&input_paths, output_path, sub_matches.is_present("delete"))
+        if sub_matches.is_present("dedup") {
+            let bloom_bits = sub_matches.value_of("bloom-bits").map(|b| b.parse::<usize>().unwrap());
+            combine_dedup(&input_paths,
+                          &output_path,
+                          sub_matches.is_present("delete"),
+                          bloom_bits)
+        } else if let Some(key) = sub_matches.value_of("sort-by") {
+            let key = match key {
+                "energy" => SortKey::Energy,
+                "radius" => SortKey::Radius,
+                _ => SortKey::Region,
+            };
+            combine_sorted(&input_paths, &output_path, key)
+        } else if sub_matches.is_present("resume") {
+            combine_resumable(&input_paths,
+                              &output_path,
+                              sub_matches.is_present("delete"),
+                              progress_bar())
+        } else if let Some(mix) = sub_matches.values_of("mix") {
+            let weights: Vec<f64> = mix
+                .map(|w| {
+                    w.parse::<f64>().unwrap_or_else(|_| {
+                        println!("Weights are numbers, not {}", w);
+                        exit(1);
+                    })
+                })
+                .collect();
+            if weights.len() != input_paths.len() {
+                println!("Got {} weights for {} inputs", weights.len(), input_paths.len());
+                exit(1);
+            }
+            let negative = weights.iter().any(|w| !w.is_finite() || *w < 0.0);
+            if negative || weights.iter().sum::<f64>() <= 0.0 {
+                println!("Weights must be non-negative and not all zero");
+                exit(1);
+            }
+            combine_weighted(&input_paths, &weights, &output_path, sub_matches.is_present("delete"))
+        } else if sub_matches.is_present("interleave") {
+            combine_interleaved(&input_paths, &output_path, sub_matches.is_present("delete"))
+        } else if sub_matches.is_present("photons-only") {
+            combine_filtered(&input_paths,
+                             &output_path,
+                             sub_matches.is_present("delete"),
+                             |record| record.particle_type() == ParticleType::Photon)
+        } else {
+            let regions: Vec<Option<u32>> = match sub_matches.values_of("regions") {
+                Some(regions) => regions
+                    .map(|r| if r == "-" { None } else { Some(r.parse::<u32>().unwrap()) })
+                    .collect(),
+                None => vec![None; input_paths.len()],
+            };
+            if regions.len() != input_paths.len() {
+                println!("Got {} regions for {} inputs", regions.len(), input_paths.len());
+                exit(1);
+            }
+            combine_tagged(&input_paths,
+                           &regions,
+                           &output_path,
+                           sub_matches.is_present("delete"),
+                           sub_matches.is_present("dry-run"),
+                           progress_bar())
+        }
     } else if subcommand == "print" {
         // prints the fields specified?
         let sub_matches = matches.subcommand_matches("print").unwrap();
         let input_path = Path::new(sub_matches.value_of("input").unwrap());
         let number = sub_matches.value_of("number").unwrap().parse::<usize>().unwrap();
-        let fields: Vec<&str> = sub_matches.values_of("fields").unwrap().collect();
-        let file = File::open(input_path).unwrap();
-        let reader = PHSPReader::from(file).unwrap();
-        for field in fields.iter() {
-            print!("{:<16}", field);
-        }
-        println!("");
-        for record in reader.take(number).map(|r| r.unwrap()) {
-            for field in fields.iter() {
-                match field {
-                    &"weight" => print!("{:<16}", record.get_weight()),
-                    &"energy" => print!("{:<16}", record.total_energy()),
-                    &"x" => print!("{:<16}", record.x_cm),
-                    &"y" => print!("{:<16}", record.y_cm),
-                    &"x_cos" => print!("{:<16}", record.x_cos),
-                    &"y_cos" => print!("{:<16}", record.y_cos),
-                    &"produced" => print!("{:<16}", record.bremsstrahlung_or_annihilation()),
-                    &"charged" => print!("{:<16}", record.charged()),
-                    &"r" => print!("{:<16}", (record.x_cm * record.x_cm + record.y_cm * record.y_cm).sqrt()),
-                    _ => panic!("Unknown field {}", field)
-                };
+        let fields: Vec<&str> = sub_matches.values_of("fields").map_or(Vec::new(), |f| f.collect());
+        open_input(input_path).and_then(|reader| {
+            if sub_matches.is_present("verbose") && number == 1 {
+                for record in reader.take(1) {
+                    print!("{}", record?.describe());
+                }
+            } else if sub_matches.value_of("format").unwrap() == "json" {
+                println!("[");
+                let mut records = reader.take(number).peekable();
+                while let Some(record) = records.next() {
+                    let object = record_json(&record?, &fields);
+                    let separator = if records.peek().is_some() { "," } else { "" };
+                    println!("\t{}{}", object, separator);
+                }
+                println!("]");
+            } else if fields.is_empty() {
+                println!("--field is required unless printing json or a single record with \
+                          --verbose");
+                exit(1);
+            } else {
+                for field in fields.iter() {
+                    print!("{:<16}", field);
+                }
+                println!();
+                for record in reader.take(number) {
+                    let record = record?;
+                    for field in fields.iter() {
+                        print!("{:<16}", Field::of(&record, field).text());
+                    }
+                    println!();
+                }
             }
-            println!("");
-        }
-        Ok(())
+            Ok(())
+        })
+    } else if subcommand == "hexdump" {
+        let sub_matches = matches.subcommand_matches("hexdump").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let index = sub_matches.value_of("record").unwrap().parse::<u64>().unwrap();
+        PHSPReader::open(input_path).and_then(|mut reader| {
+            let bytes = reader.raw_record(index)?;
+            let mode = String::from_utf8_lossy(&reader.header.mode).into_owned();
+            println!("Record {} ({}, {} bytes at offset {})",
+                     index,
+                     mode,
+                     bytes.len(),
+                     (index + 1) * reader.header.record_size);
+            for line in hexdump_fields(&bytes, reader.byte_order() == ByteOrderMode::Big) {
+                println!("{}", line);
+            }
+            Ok(())
+        })
+    } else if subcommand == "latch" {
+        let sub_matches = matches.subcommand_matches("latch").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        PHSPReader::open(input_path).and_then(|mut reader| {
+            let (start, number) = match sub_matches.value_of("record") {
+                Some(record) => (record.parse::<u64>().unwrap(), 1),
+                None => (0, sub_matches.value_of("number").unwrap().parse::<usize>().unwrap()),
+            };
+            if start >= reader.header.total_particles.max(0) as u64 {
+                return Err(EGSError::RecordOutOfRange(start));
+            }
+            if start > 0 {
+                reader.seek_to_record(start)?;
+            }
+            for (i, record) in reader.take(number).enumerate() {
+                println!("Record {}", start + i as u64);
+                print!("{}", record?.describe_latch());
+            }
+            Ok(())
+        })
+    } else if subcommand == "split" {
+        let sub_matches = matches.subcommand_matches("split").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_prefix = Path::new(sub_matches.value_of("output").unwrap());
+        let parts = sub_matches.value_of("parts").unwrap().parse::<usize>().unwrap();
+        println!("split {} into {} files", input_path.display(), parts);
+        split(input_path, output_prefix, parts).map(|paths| {
+            for path in paths.iter() {
+                println!("{}", path.display());
+            }
+        })
+    } else if subcommand == "extract" {
+        let sub_matches = matches.subcommand_matches("extract").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let start = sub_matches.value_of("start").unwrap().parse::<u64>().unwrap();
+        let end = sub_matches.value_of("end").unwrap().parse::<u64>().unwrap();
+        println!("extract records {} to {} of {} into {}",
+                 start,
+                 end,
+                 input_path.display(),
+                 output_path.display());
+        extract_range(input_path, output_path, start, end)
+    } else if subcommand == "index" {
+        let sub_matches = matches.subcommand_matches("index").unwrap();
+        let input = sub_matches.value_of("input").unwrap();
+        let index_path = match sub_matches.value_of("output") {
+            Some(output) => PathBuf::from(output),
+            None => PathBuf::from(format!("{}.idx", input)),
+        };
+        println!("index {} into {}", input, index_path.display());
+        build_index(Path::new(input), &index_path)
+    } else if subcommand == "histogram" {
+        let sub_matches = matches.subcommand_matches("histogram").unwrap();
+        let path = Path::new(sub_matches.value_of("input").unwrap());
+        let bins = sub_matches.value_of("bins").unwrap().parse::<usize>().unwrap();
+        let weighted = sub_matches.is_present("weighted");
+        let range = match (sub_matches.value_of("min"), sub_matches.value_of("max")) {
+            (Some(min), Some(max)) => Ok((floatify(min), floatify(max))),
+            (min, max) => PHSPReader::open(path).and_then(|reader| {
+                // header energies aren't always kept up to date, take the range from the records
+                let (mut lowest, mut highest) = (f32::MAX, 0.0f32);
+                for record in reader {
+                    let energy = record?.total_energy();
+                    lowest = lowest.min(energy);
+                    highest = highest.max(energy);
+                }
+                if lowest > highest {
+                    lowest = 0.0;
+                    highest = 0.0;
+                }
+                // a single energy, or none at all, still gets a bin to land in
+                if highest == lowest {
+                    highest = lowest + 0.001;
+                }
+                Ok((min.map_or(lowest, floatify), max.map_or(highest, floatify)))
+            }),
+        };
+        range.and_then(|(min, max)| energy_spectrum_weighted(path, bins, min, max).map(|(weights, counts)| {
+            let width = (max - min) / bins as f32;
+            println!("{:>12} {:>12} {:>14}",
+                     "From MeV",
+                     "To MeV",
+                     if weighted { "Weight" } else { "Particles" });
+            for (i, (weight, count)) in weights.iter().zip(counts.iter()).enumerate() {
+                let from = min + i as f32 * width;
+                if weighted {
+                    println!("{:>12.4} {:>12.4} {:>14.4}", from, from + width, weight);
+                } else {
+                    println!("{:>12.4} {:>12.4} {:>14}", from, from + width, count);
+                }
+            }
+        }))
+    } else if subcommand == "angles" {
+        let sub_matches = matches.subcommand_matches("angles").unwrap();
+        let path = Path::new(sub_matches.value_of("input").unwrap());
+        let bins = sub_matches.value_of("bins").unwrap().parse::<usize>().unwrap();
+        let weighted = sub_matches.is_present("weighted");
+        angular_histogram(path, bins).map(|histogram| {
+            let width = 90.0 / bins as f32;
+            println!("{:>12} {:>12} {:>14}",
+                     "From degrees",
+                     "To degrees",
+                     if weighted { "Weight" } else { "Particles" });
+            for i in 0..bins {
+                let from = i as f32 * width;
+                if weighted {
+                    println!("{:>12.4} {:>12.4} {:>14.4}", from, from + width, histogram.weights[i]);
+                } else {
+                    println!("{:>12.4} {:>12.4} {:>14}", from, from + width, histogram.counts[i]);
+                }
+            }
+            if histogram.invalid > 0 {
+                println!("{} particles with invalid direction cosines left out", histogram.invalid);
+            }
+        })
+    } else if subcommand == "fluence" {
+        let sub_matches = matches.subcommand_matches("fluence").unwrap();
+        let path = Path::new(sub_matches.value_of("input").unwrap());
+        let extent = floatify(sub_matches.value_of("extent").unwrap());
+        let nx = sub_matches.value_of("nx").unwrap().parse::<usize>().unwrap();
+        let ny = sub_matches.value_of("ny").unwrap().parse::<usize>().unwrap();
+        let mode = if sub_matches.is_present("edge") {
+            ClampMode::Clamp
+        } else {
+            ClampMode::Discard
+        };
+        fluence_map(path, nx, ny, extent, mode).map(|grid| {
+            if sub_matches.value_of("format").unwrap() == "csv" {
+                for j in 0..ny {
+                    let row: Vec<String> = grid.iter().map(|column| column[j].to_string()).collect();
+                    println!("{}", row.join(","));
+                }
+            } else {
+                let width_x = 2.0 * extent / nx as f32;
+                let width_y = 2.0 * extent / ny as f32;
+                println!("{:>10} {:>10} {:>10} {:>10} {:>14}",
+                         "From x cm",
+                         "To x cm",
+                         "From y cm",
+                         "To y cm",
+                         "Weight");
+                for (i, column) in grid.iter().enumerate() {
+                    let x = -extent + i as f32 * width_x;
+                    for (j, weight) in column.iter().enumerate() {
+                        let y = -extent + j as f32 * width_y;
+                        println!("{:>10.4} {:>10.4} {:>10.4} {:>10.4} {:>14.4}",
+                                 x,
+                                 x + width_x,
+                                 y,
+                                 y + width_y,
+                                 weight);
+                    }
+                }
+            }
+        })
+    } else if subcommand == "peaks" {
+        let sub_matches = matches.subcommand_matches("peaks").unwrap();
+        let path = Path::new(sub_matches.value_of("input").unwrap());
+        let bin_width = floatify(sub_matches.value_of("bin-width").unwrap());
+        let prominence = floatify(sub_matches.value_of("prominence").unwrap()) as f64;
+        peaks(path, bin_width, prominence).map(|found| {
+            println!("{:>12} {:>14} {:>10}", "Energy MeV", "Weight", "Prominence");
+            for peak in found {
+                println!("{:>12.4} {:>14.2} {:>10.3}", peak.energy, peak.height, peak.prominence);
+            }
+        })
+    } else if subcommand == "diff" {
+        let sub_matches = matches.subcommand_matches("diff").unwrap();
+        let first = Path::new(sub_matches.value_of("first").unwrap());
+        let second = Path::new(sub_matches.value_of("second").unwrap());
+        diff(first, second).and_then(|report| {
+            for (field, a, b) in report.header_differences.iter() {
+                println!("Header {} differs: {} vs {}", field, a, b);
+            }
+            if report.records_a != report.records_b {
+                println!("Record counts differ: {} vs {}", report.records_a, report.records_b);
+            }
+            if let Some(index) = report.first_difference {
+                println!("{} records differ, the first is record {}", report.differing, index);
+            }
+            match report.mismatch() {
+                Some(err) => Err(err),
+                None => {
+                    println!("Files are the same");
+                    Ok(())
+                }
+            }
+        })
+    } else if subcommand == "count" {
+        let sub_matches = matches.subcommand_matches("count").unwrap();
+        let path = Path::new(sub_matches.value_of("input").unwrap());
+        let charged = sub_matches.is_present("charged");
+        let photons = sub_matches.is_present("photons");
+        let produced = sub_matches.is_present("produced");
+        let region = sub_matches.value_of("region").map(|r| r.parse::<u32>().unwrap());
+        count_matching(path, charged, photons, produced, region).map(|count| println!("{}", count))
+    } else if subcommand == "validate" {
+        let sub_matches = matches.subcommand_matches("validate").unwrap();
+        let path = Path::new(sub_matches.value_of("input").unwrap());
+        validate(path).map(|report| {
+            if !report.size_matches() {
+                println!("File is {} bytes, header expects {}",
+                         report.actual_size,
+                         report.expected_size);
+            }
+            if report.non_finite > 0 {
+                println!("{} records contain NaN or infinite values", report.non_finite);
+            }
+            if report.bad_cosines > 0 {
+                println!("{} records have x_cos^2 + y_cos^2 > 1", report.bad_cosines);
+            }
+            if !report.photons_match() {
+                println!("Found {} photons, header says {}",
+                         report.photons,
+                         report.header_photons);
+            }
+            if !report.is_valid() {
+                exit(1);
+            }
+            println!("{} is valid", path.display());
+        })
+    } else if subcommand == "emit-layout" {
+        let sub_matches = matches.subcommand_matches("emit-layout").unwrap();
+        let path = Path::new(sub_matches.value_of("input").unwrap());
+        PHSPReader::open(path).map(|reader| print!("{}", c_layout(&reader.header)))
     } else if subcommand == "shout" {
         let sub_matches = matches.subcommand_matches("shout").unwrap();
         let input_paths: Vec<&Path> = sub_matches.values_of("input")
             .unwrap()
-            .map(|s| Path::new(s))
+            .map(Path::new)
             .collect();
         let shout_output: String = "tns_output.egsphsp1".to_string();
         let shout_output_path = Path::new(&shout_output);
@@ -188,90 +1305,359 @@ fn main() {
         let sub_matches = matches.subcommand_matches("sample").unwrap();
         let input_paths: Vec<&Path> = sub_matches.values_of("input")
             .unwrap()
-            .map(|s| Path::new(s))
+            .map(Path::new)
             .collect();
-        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let output_path = output_path_for(sub_matches.value_of("output").unwrap(),
+                                          sub_matches.is_present("gzip"));
         let rate = sub_matches.value_of("rate").unwrap().parse::<u32>().unwrap();
-        let seed: &[_] = &[sub_matches.value_of("seed").unwrap().parse::<usize>().unwrap()];
-        println!("sample {} file into {} at 1 in {}",
-                 input_paths.len(),
-                 output_path.display(),
-                 rate);
-        sample(&input_paths, output_path, rate, seed)
+        let photon_rate = sub_matches.value_of("photon-rate")
+            .map_or(rate, |r| r.parse::<u32>().unwrap());
+        let electron_rate = sub_matches.value_of("electron-rate")
+            .map_or(rate, |r| r.parse::<u32>().unwrap());
+        let seed = sub_matches.value_of("seed").unwrap().parse::<u64>().unwrap();
+        if rate == 0 || photon_rate == 0 || electron_rate == 0 {
+            println!("Sample rates must be at least 1");
+            exit(1);
+        }
+        warn_missing_source_particles(&input_paths);
+        if let Some(count) = sub_matches.value_of("count") {
+            let count = count.parse::<usize>().unwrap();
+            println!("sample {} file into {} taking exactly {}",
+                     input_paths.len(),
+                     output_path.display(),
+                     count);
+            sample_exact(&input_paths, &output_path, count, seed)
+        } else if photon_rate != electron_rate {
+            println!("sample {} file into {} at 1 in {} photons and 1 in {} charged",
+                     input_paths.len(),
+                     output_path.display(),
+                     photon_rate,
+                     electron_rate);
+            sample_by_type(&input_paths, &output_path, photon_rate, electron_rate, seed)
+        } else if let Some(threads) = sub_matches.value_of("threads") {
+            let threads = threads.parse::<usize>().unwrap();
+            println!("sample {} file into {} at 1 in {} on {} threads",
+                     input_paths.len(),
+                     output_path.display(),
+                     rate,
+                     threads);
+            sample_parallel(&input_paths, &output_path, rate, seed, threads)
+        } else {
+            println!("sample {} file into {} at 1 in {}",
+                     input_paths.len(),
+                     output_path.display(),
+                     rate);
+            sample_with_progress(&input_paths,
+                                 &output_path,
+                                 rate,
+                                 seed,
+                                 sub_matches.is_present("dry-run"),
+                                 progress_bar())
+        }
+    }
+    else if subcommand == "angular-smear" {
+        let sub_matches = matches.subcommand_matches("angular-smear").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let sigma = floatify(sub_matches.value_of("sigma").unwrap());
+        let seed = sub_matches.value_of("seed").unwrap().parse::<u64>().unwrap();
+        println!("smear {} by {} radians and write to {}",
+                 input_path.display(),
+                 sigma,
+                 output_path.display());
+        angular_smear(input_path, output_path, sigma, seed)
+    }
+    else if subcommand == "convert" {
+        let sub_matches = matches.subcommand_matches("convert").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let mode = if sub_matches.value_of("to").unwrap() == "mode2" { *b"MODE2" } else { *b"MODE0" };
+        let zlast = floatify(sub_matches.value_of("zlast").unwrap());
+        println!("convert {} to {} and write to {}",
+                 input_path.display(),
+                 String::from_utf8_lossy(&mode),
+                 output_path.display());
+        convert_mode(input_path, output_path, mode, zlast)
+    }
+    else if subcommand == "resample" {
+        let sub_matches = matches.subcommand_matches("resample").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let target = sub_matches.value_of("target").unwrap().parse::<usize>().unwrap();
+        let seed = sub_matches.value_of("seed").unwrap().parse::<u64>().unwrap();
+        println!("resample {} to {} particles and write to {}",
+                 input_path.display(),
+                 target,
+                 output_path.display());
+        resample_to(input_path, output_path, target, seed)
+    }
+    else if subcommand == "grid-thin" {
+        let sub_matches = matches.subcommand_matches("grid-thin").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let cell_size = floatify(sub_matches.value_of("cell-size").unwrap());
+        let max_per_cell = sub_matches.value_of("max-per-cell").unwrap().parse::<u64>().unwrap();
+        let seed = sub_matches.value_of("seed").unwrap().parse::<u64>().unwrap();
+        println!("thin {} to {} particles per {} cm cell and write to {}",
+                 input_path.display(),
+                 max_per_cell,
+                 cell_size,
+                 output_path.display());
+        grid_thin(input_path, output_path, cell_size, max_per_cell, seed)
+    }
+    else if subcommand == "roulette" {
+        let sub_matches = matches.subcommand_matches("roulette").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let threshold = floatify(sub_matches.value_of("threshold").unwrap());
+        let seed = sub_matches.value_of("seed").unwrap().parse::<u64>().unwrap();
+        println!("roulette {} below weight {} and write to {}",
+                 input_path.display(),
+                 threshold,
+                 output_path.display());
+        russian_roulette(input_path, output_path, threshold, seed)
+    }
+    else if subcommand == "sample-stratified" {
+        let sub_matches = matches.subcommand_matches("sample-stratified").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let seed = sub_matches.value_of("seed").unwrap().parse::<u64>().unwrap();
+        let bands: Vec<(f32, f32, u32)> = sub_matches.values_of("band")
+            .unwrap()
+            .map(|band| {
+                let parts: Vec<&str> = band.split(':').collect();
+                match (parts.len(), parts.last().map(|rate| rate.parse::<u32>())) {
+                    (3, Some(Ok(rate))) if rate > 0 => (floatify(parts[0]), floatify(parts[1]), rate),
+                    _ => {
+                        println!("Bands are min:max:rate with a positive whole rate, not {}", band);
+                        exit(1);
+                    }
+                }
+            })
+            .collect();
+        println!("sample {} in {} energy bands and write to {}",
+                 input_path.display(),
+                 bands.len(),
+                 output_path.display());
+        sample_stratified(input_path, output_path, &bands, seed)
+    }
+    else if subcommand == "clamp-radius" {
+        let sub_matches = matches.subcommand_matches("clamp-radius").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let max_radius = floatify(sub_matches.value_of("max").unwrap());
+        let mode = if sub_matches.is_present("clamp") {
+            ClampMode::Clamp
+        } else {
+            ClampMode::Discard
+        };
+        println!("clamp {} to radius {} and write to {}",
+                 input_path.display(),
+                 max_radius,
+                 output_path.display());
+        clamp_radius(input_path, output_path, max_radius, mode)
+    }
+    else if subcommand == "clamp-energy" {
+        let sub_matches = matches.subcommand_matches("clamp-energy").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let max_energy = floatify(sub_matches.value_of("max").unwrap());
+        let mode = if sub_matches.is_present("clamp") {
+            ClampMode::Clamp
+        } else {
+            ClampMode::Discard
+        };
+        println!("clamp {} to energy {} and write to {}",
+                 input_path.display(),
+                 max_energy,
+                 output_path.display());
+        clamp_energy(input_path, output_path, max_energy, mode)
+    }
+    else if subcommand == "shift-energy" {
+        let sub_matches = matches.subcommand_matches("shift-energy").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        let delta = floatify(sub_matches.value_of("delta").unwrap());
+        println!("shift energies in {} by {} MeV and write to {}",
+                 input_path.display(),
+                 delta,
+                 output_path.display());
+        shift_energy(input_path, output_path, delta)
+    }
+    else if subcommand == "size" {
+        let sub_matches = matches.subcommand_matches("size").unwrap();
+        let input_paths: Vec<&Path> = sub_matches.values_of("input")
+            .unwrap()
+            .map(Path::new)
+            .collect();
+        if sub_matches.value_of("operation").unwrap() == "sample" {
+            let rate = sub_matches.value_of("rate").unwrap().parse::<u32>().unwrap();
+            let photon_rate = sub_matches.value_of("photon-rate")
+                .map_or(rate, |r| r.parse::<u32>().unwrap());
+            let electron_rate = sub_matches.value_of("electron-rate")
+                .map_or(rate, |r| r.parse::<u32>().unwrap());
+            if rate == 0 || photon_rate == 0 || electron_rate == 0 {
+                println!("Sample rates must be at least 1");
+                exit(1);
+            }
+            sampled_size(&input_paths, photon_rate, electron_rate)
+                .map(|size| println!("About {} bytes", size))
+        } else {
+            combined_size(&input_paths).map(|size| println!("{} bytes", size))
+        }
+    }
+    else if subcommand == "weights" {
+        let sub_matches = matches.subcommand_matches("weights").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        audit_weights(input_path).and_then(|audit| {
+            println!("{} records", audit.records);
+            println!("{} with zero weight", audit.zero);
+            println!("{} with NaN or infinite weight", audit.non_finite);
+            println!("{} heading towards -z", audit.negative_z);
+            match sub_matches.value_of("repair") {
+                Some(output) => {
+                    let positive_z = sub_matches.value_of("direction").map(|d| d == "+z");
+                    repair_weights(input_path, Path::new(output), positive_z).map(|repaired| {
+                        println!("repaired {} weights and wrote {}", repaired, output);
+                    })
+                }
+                None => Ok(()),
+            }
+        })
+    }
+    else if subcommand == "flip-z" {
+        let sub_matches = matches.subcommand_matches("flip-z").unwrap();
+        let input_path = Path::new(sub_matches.value_of("input").unwrap());
+        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+        println!("flip z direction of {} and write to {}",
+                 input_path.display(),
+                 output_path.display());
+        flip_z(input_path, output_path, sub_matches.is_present("zlast"))
     }
     else if subcommand == "info" {
         let sub_matches = matches.subcommand_matches("info").unwrap();
         let path = Path::new(sub_matches.value_of("input").unwrap());
-        let reader = PHSPReader::from(File::open(path).unwrap()).unwrap();
-        let header = reader.header;
-
-        if sub_matches.value_of("format").unwrap() == "json" {
-            println!("{{");
-            println!("\t\"total_particles\": {},", header.total_particles);
-            println!("\t\"total_photons\": {},", header.total_photons);
-            println!("\t\"maximum_energy\": {},", header.max_energy);
-            println!("\t\"minimum_energy\": {},", header.min_energy);
-            println!("\t\"total_particles_in_source\": {}",
-                     header.total_particles_in_source);
-            println!("}}");
-        } else {
-            println!("Total particles: {}", header.total_particles);
-            println!("Total photons: {}", header.total_photons);
-            println!("Total electrons/positrons: {}",
-                     header.total_particles - header.total_photons);
-            println!("Maximum energy: {:.*} MeV", 4, header.max_energy);
-            println!("Minimum energy: {:.*} MeV", 4, header.min_energy);
-            println!("Incident particles from source: {:.*}",
-                     1,
-                     header.total_particles_in_source);
+        let rereads = ["scan", "by-type", "verify-count"];
+        if is_stdio(path) && rereads.iter().any(|&flag| sub_matches.is_present(flag)) {
+            println!("--scan, --by-type and --verify-count read the file again, which stdin \
+                      can't do");
+            exit(1);
         }
-        Ok(())
+        open_input(path).and_then(|reader| {
+            let header = reader.header;
+            let scanned = if sub_matches.is_present("scan") {
+                Some(scan(path)?)
+            } else {
+                None
+            };
+            let extents = if sub_matches.is_present("by-type") {
+                Some(energy_extents_by_type(path)?)
+            } else {
+                None
+            };
+            let counted = if sub_matches.is_present("verify-count") {
+                Some(count_records(path)?)
+            } else {
+                None
+            };
+            let json = sub_matches.value_of("format").unwrap() == "json";
+            print_info(&header, scanned, extents, counted, json);
+            if counted.is_some_and(|c| !c.particles_match() || !c.photons_match()) {
+                exit(1);
+            }
+            Ok(())
+        })
     } else {
         let mut matrix = [[0.0; 3]; 3];
         match subcommand {
             "rotate" =>
             {
                 let sub_matches = matches.subcommand_matches("rotate").unwrap();
+                let dry_run = sub_matches.is_present("dry-run");
                 let angle = floatify(sub_matches.value_of("angle").unwrap());
-                Transform::rotation(&mut matrix, angle);
+                let about_x = floatify(sub_matches.value_of("about-x").unwrap());
+                let about_y = floatify(sub_matches.value_of("about-y").unwrap());
+                Transform::rotation_about(&mut matrix, angle, about_x, about_y);
                 let input_path = Path::new(sub_matches.value_of("input").unwrap());
                 if sub_matches.is_present("in-place") {
                     println!("rotate {} by {} radians", input_path.display(), angle);
-                    transform(input_path, input_path, &matrix)
+                    transform_with_progress(input_path, input_path, &matrix, dry_run, progress_bar())
                 } else {
                     let output_path = Path::new(sub_matches.value_of("output").unwrap());
                     println!("rotate {} by {} radians and write to {}",
                              input_path.display(),
                              angle,
                              output_path.display());
-                    transform(input_path, output_path, &matrix)
+                    transform_with_progress(input_path, output_path, &matrix, dry_run, progress_bar())
+                }
+            }
+            "recenter" =>
+            {
+                let sub_matches = matches.subcommand_matches("recenter").unwrap();
+                let input_path = Path::new(sub_matches.value_of("input").unwrap());
+                let output_path = Path::new(sub_matches.value_of("output").unwrap());
+                centroid(input_path).and_then(|(x, y)| {
+                    println!("recenter {} from ({}, {}) and write to {}",
+                             input_path.display(),
+                             x,
+                             y,
+                             output_path.display());
+                    Transform::translation(&mut matrix, -x, -y);
+                    transform_with_progress(input_path, output_path, &matrix, false, progress_bar())
+                })
+            }
+            "transform" =>
+            {
+                let sub_matches = matches.subcommand_matches("transform").unwrap();
+                let dry_run = sub_matches.is_present("dry-run");
+                let ops = sub_matches.value_of("ops").unwrap();
+                let input_path = Path::new(sub_matches.value_of("input").unwrap());
+                match Transform::parse(&mut matrix, ops) {
+                    Err(err) => Err(err),
+                    Ok(()) if sub_matches.is_present("in-place") => {
+                        println!("transform {} by {}", input_path.display(), ops);
+                        transform_with_progress(input_path, input_path, &matrix, dry_run, progress_bar())
+                    }
+                    Ok(()) => {
+                        let output_path = Path::new(sub_matches.value_of("output").unwrap());
+                        println!("transform {} by {} and write to {}",
+                                 input_path.display(),
+                                 ops,
+                                 output_path.display());
+                        transform_with_progress(input_path, output_path, &matrix, dry_run, progress_bar())
+                    }
                 }
             }
             "twist" =>
             {
                 let start = ProcessTime::now();
                 let sub_matches = matches.subcommand_matches("twist").unwrap();
-                let mut rng = rand::thread_rng();
+                let mut rng = match sub_matches.value_of("seed") {
+                    Some(seed) => StdRng::seed_from_u64(seed.parse::<u64>().unwrap()),
+                    None => StdRng::from_entropy(),
+                };
                 let iteration = floatify(sub_matches.value_of("iterations").unwrap()) as i32;
-                let mut count = 1 as i32;
+                let mut count = 1_i32;
                 let input_path = Path::new(sub_matches.value_of("input").unwrap());
                 loop
                 {
                     let rand_seed: f32 = rng.gen();
+                    #[allow(clippy::approx_constant)]
                     let rand_angle: f32 = 6.28318 * rand_seed;
                     Transform::rotation(&mut matrix, rand_angle);
-                    println!("");
+                    println!();
                     println!("✦ Random angle is {} radians", rand_angle);
                     let mut rotation_output: String = count.to_string();
                     rotation_output.push_str(".egsphsp");
                     let rotation_output_path = Path::new(&rotation_output);
-                    transform(input_path, rotation_output_path, &matrix); // Rotate file by random angle in radians & write to single_output_path
+                    // Rotate file by random angle in radians & write to single_output_path
+                    let _ = transform(input_path, rotation_output_path, &matrix);
                     if count == iteration
                     {
-                        println!("");
+                        println!();
                         break
                     }
-                    count = count + 1;
+                    count += 1;
                 }
                 let cpu_time: Duration = start.elapsed();
                 println!("CPU time: {:?}", cpu_time);
@@ -283,8 +1669,116 @@ fn main() {
     match result {
         Ok(()) => exit(0),
         Err(err) => {
-            println!("Error: {}", err.description());
+            println!("Error: {}", err);
             exit(1);
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egsphsp::PHSPWriter;
+    use serde_json::Value;
+
+    #[test]
+    fn picked_fields_parse_back_as_json() {
+        let mut record = Record::new(0, 1.5, 2.0, -3.0, 0.6, 0.0, 1.0);
+        record.set_charged(true);
+        let fields = ["energy", "x", "charged", "produced", "zlast"];
+        let parsed: Value = serde_json::from_str(&record_json(&record, &fields)).unwrap();
+        assert_eq!(parsed["energy"].as_f64(), Some(1.5));
+        assert_eq!(parsed["x"].as_f64(), Some(2.0));
+        // booleans stay booleans and MODE0 has no zlast
+        assert_eq!(parsed["charged"], Value::Bool(true));
+        assert_eq!(parsed["produced"], Value::Bool(false));
+        assert_eq!(parsed["zlast"], Value::Null);
+    }
+
+    #[test]
+    fn every_field_parses_back_as_json() {
+        let mut record = Record::new(7, -1.25, 0.0, 0.0, 0.0, 0.0, 1.0);
+        record.zlast = Some(4.5);
+        let parsed: Value = serde_json::from_str(&record_json(&record, &[])).unwrap();
+        assert_eq!(parsed["latch"], Value::from(7));
+        assert_eq!(parsed["total_energy"].as_f64(), Some(-1.25));
+        assert_eq!(parsed["zlast"].as_f64(), Some(4.5));
+        record.weight = f32::NAN;
+        let parsed: Value = serde_json::from_str(&record_json(&record, &["weight"])).unwrap();
+        assert_eq!(parsed["weight"], Value::Null);
+    }
+
+    // The bytes of `record` as the one record of an in-memory MODE2 file.
+    fn mode2_bytes(record: &Record) -> Vec<u8> {
+        let mut writer = PHSPWriter::in_memory(&Header::new_mode2(0, 0, 1.0).unwrap()).unwrap();
+        writer.write(record).unwrap();
+        writer.into_bytes().unwrap()[32..].to_vec()
+    }
+
+    #[test]
+    fn hexdump_shows_the_bytes_written() {
+        let mut record = Record::new(0x0100_0041, 1.5, -2.0, 0.25, 0.6, 0.0, 1.0);
+        record.zlast = Some(12.5);
+        let bytes = mode2_bytes(&record);
+        let lines = hexdump_fields(&bytes, false);
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], " 0..4   41 00 00 01  latch    0x01000041");
+        assert_eq!(lines[1], " 4..8   00 00 c0 3f  energy   1.5");
+        assert_eq!(lines[2], " 8..12  00 00 00 c0  x_cm     -2");
+        assert_eq!(lines[7], "28..32  00 00 48 41  zlast    12.5");
+        for (line, field) in lines.iter().zip(bytes.chunks(4)) {
+            let hex: Vec<String> = field.iter().map(|byte| format!("{:02x}", byte)).collect();
+            assert!(line.contains(&hex.join(" ")));
+        }
+        // MODE0 records stop before zlast
+        assert_eq!(hexdump_fields(&bytes[..28], false).len(), 7);
+    }
+
+    #[test]
+    fn hexdump_decodes_big_endian_words() {
+        let mut bytes = mode2_bytes(&Record::new(7, 1.5, 0.0, 0.0, 0.0, 0.0, 1.0));
+        for word in bytes.chunks_mut(4) {
+            word.reverse();
+        }
+        let lines = hexdump_fields(&bytes, true);
+        assert_eq!(lines[0], " 0..4   00 00 00 07  latch    0x00000007");
+        assert_eq!(lines[1], " 4..8   3f c0 00 00  energy   1.5");
+    }
+
+    #[test]
+    fn count_matches_the_header_photons() {
+        let path = std::env::temp_dir().join(format!("count-{}.egsphsp1", std::process::id()));
+        let header = Header::new_mode0(0, 0, 10.0).unwrap();
+        let mut writer = PHSPWriter::create(&path, &header).unwrap();
+        for i in 0..10 {
+            let mut record = Record::new(0, 1.0 + i as f32, 0.0, 0.0, 0.0, 0.0, 1.0);
+            record.set_charged(i % 3 == 0);
+            record.set_region(i % 2).unwrap();
+            record.set_bremsstrahlung_or_annihilation(i >= 6);
+            writer.write(&record).unwrap();
+        }
+        writer.finalize().unwrap();
+        let header = PHSPReader::open(&path).unwrap().header;
+        let photons = count_matching(&path, false, true, false, None).unwrap();
+        assert_eq!(photons, header.total_photons as u64);
+        assert_eq!(photons, 6);
+        assert_eq!(count_matching(&path, true, false, false, None).unwrap(), 4);
+        assert_eq!(count_matching(&path, false, false, false, None).unwrap(), 10);
+        // criteria combine, 7 is the only produced photon in region 1
+        assert_eq!(count_matching(&path, false, true, true, Some(1)).unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn info_reads_the_same_from_a_pipe() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("sample.egsphsp1");
+        let bytes = std::fs::read(&path).unwrap();
+        // stdin is read just like any other stream that can't seek
+        let piped = PHSPReader::from_reader(std::io::Cursor::new(bytes)).unwrap().header;
+        let file = open_input(&path).unwrap().header;
+        assert_eq!(format!("{}", piped), format!("{}", file));
+        assert_eq!(piped.to_json(), file.to_json());
+        assert!(is_stdio(Path::new("-")));
+        assert!(!is_stdio(Path::new("./-")));
+    }
+}
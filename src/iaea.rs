@@ -0,0 +1,111 @@
+//! Export to the IAEA phase space format, a `.IAEAphsp` file of binary
+//! records described by a `.IAEAheader` text file.
+//!
+//! Each EGSnrc record maps onto one IAEA record as follows:
+//!
+//! | IAEA field  | bytes | EGSnrc source                                              |
+//! |-------------|-------|------------------------------------------------------------|
+//! | type        | 1     | `particle_type()`: 1 photon, 2 electron, 3 positron, negated when z direction is negative |
+//! | energy      | 4     | kinetic energy, total energy less the rest mass for charged particles, negated for the first particle of a history |
+//! | x, y        | 4 + 4 | `x_cm`, `y_cm`                                             |
+//! | u, v        | 4 + 4 | `x_cos`, `y_cos`, w is rebuilt from these by readers       |
+//! | weight      | 4     | `get_weight()`                                             |
+//! | extra float | 4     | `zlast`, MODE2 only                                        |
+//! | extra long  | 4     | the latch                                                  |
+//!
+//! EGSnrc files don't record where the scoring plane is, so z is written as a
+//! constant 0 in the header and should be corrected there if it matters.
+//! Records are little endian.
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{EGSResult, ParticleType, PHSPReader, BUFFER_CAPACITY};
+
+// IAEA codes for the extra variables we store.
+const EXTRA_FLOAT_ZLAST: u32 = 3;
+const EXTRA_LONG_LATCH: u32 = 2;
+
+fn with_suffix(base: &Path, suffix: &str) -> PathBuf {
+    PathBuf::from(format!("{}{}", base.display(), suffix))
+}
+
+/// Write `input` as `<output_base>.IAEAphsp` and `<output_base>.IAEAheader`.
+pub fn export_iaea(input: &Path, output_base: &Path) -> EGSResult<()> {
+    let reader = PHSPReader::open(input)?;
+    let header = reader.header;
+    // type, six floats and the latch, plus zlast for MODE2
+    let record_length = 29 + if header.using_zlast { 4 } else { 0 };
+    let mut writer = BufWriter::with_capacity(BUFFER_CAPACITY,
+                                              File::create(with_suffix(output_base, ".IAEAphsp"))?);
+    let mut counts = [0u64; 3];
+    let mut buffer = [0u8; 33];
+    for record in reader {
+        let record = record?;
+        let code = match record.particle_type() {
+            ParticleType::Photon => 1i8,
+            ParticleType::Electron => 2,
+            ParticleType::Positron => 3,
+        };
+        let mut energy = record.kinetic_energy();
+        counts[code as usize - 1] += 1;
+        if record.first_scored_by_primary_history() {
+            energy = -energy;
+        }
+        let code = if record.z_positive() { code } else { -code };
+        buffer[0] = code as u8;
+        LittleEndian::write_f32(&mut buffer[1..5], energy);
+        LittleEndian::write_f32(&mut buffer[5..9], record.x_cm);
+        LittleEndian::write_f32(&mut buffer[9..13], record.y_cm);
+        LittleEndian::write_f32(&mut buffer[13..17], record.x_cos);
+        LittleEndian::write_f32(&mut buffer[17..21], record.y_cos);
+        LittleEndian::write_f32(&mut buffer[21..25], record.get_weight());
+        let mut offset = 25;
+        if let Some(zlast) = record.zlast {
+            LittleEndian::write_f32(&mut buffer[offset..offset + 4], zlast);
+            offset += 4;
+        }
+        LittleEndian::write_u32(&mut buffer[offset..offset + 4], record.latch);
+        writer.write_all(&buffer[..offset + 4])?;
+    }
+    writer.flush()?;
+
+    let particles: u64 = counts.iter().sum();
+    let mut text = String::new();
+    text.push_str("$IAEA_INDEX:\n   0 // Exported from an EGSnrc phase space\n\n");
+    text.push_str(&format!("$TITLE:\n{}\n\n", input.display()));
+    text.push_str("$FILE_TYPE:\n   0\n\n");
+    text.push_str(&format!("$CHECKSUM:\n{}\n\n", particles * record_length));
+    text.push_str("$RECORD_CONTENTS:\n");
+    text.push_str("   1 // X is stored ?\n");
+    text.push_str("   1 // Y is stored ?\n");
+    text.push_str("   0 // Z is stored ?\n");
+    text.push_str("   1 // U is stored ?\n");
+    text.push_str("   1 // V is stored ?\n");
+    text.push_str("   1 // W is stored ?\n");
+    text.push_str("   1 // Weight is stored ?\n");
+    if header.using_zlast {
+        text.push_str("   1 // Extra floats stored ?\n");
+    } else {
+        text.push_str("   0 // Extra floats stored ?\n");
+    }
+    text.push_str("   1 // Extra longs stored ?\n");
+    if header.using_zlast {
+        text.push_str(&format!("   {} // ZLAST in extrafloat [ 0]\n", EXTRA_FLOAT_ZLAST));
+    }
+    text.push_str(&format!("   {} // LATCH in extralong [ 0]\n\n", EXTRA_LONG_LATCH));
+    text.push_str("$RECORD_CONSTANT:\n   0.0000 // Constant Z\n\n");
+    text.push_str(&format!("$RECORD_LENGTH:\n{}\n\n", record_length));
+    text.push_str("$BYTE_ORDER:\n1234\n\n");
+    text.push_str(&format!("$ORIG_HISTORIES:\n{}\n\n", header.incident_histories().round() as u64));
+    text.push_str(&format!("$PARTICLES:\n{}\n\n", particles));
+    text.push_str(&format!("$PHOTONS:\n{}\n\n", counts[0]));
+    text.push_str(&format!("$ELECTRONS:\n{}\n\n", counts[1]));
+    text.push_str(&format!("$POSITRONS:\n{}\n", counts[2]));
+    File::create(with_suffix(output_base, ".IAEAheader"))?.write_all(text.as_bytes())?;
+    Ok(())
+}
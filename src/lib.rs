@@ -1,30 +1,99 @@
 extern crate float_cmp;
 extern crate byteorder;
 extern crate rand;
+extern crate rand_distr;
 extern crate cpu_time;
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+#[cfg(feature = "parallel")]
+extern crate memmap2;
+#[cfg(test)]
+extern crate serde_json;
 
 use std::error::Error;
-use std::fs::{File, OpenOptions, remove_file};
-use std::io::{BufReader, BufWriter};
+use std::fs::{File, OpenOptions, remove_file, rename, canonicalize};
+use std::io::{BufReader, BufWriter, Cursor, SeekFrom};
+use std::ops::{Deref, DerefMut};
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::io;
 use std::fmt;
+use std::convert::TryFrom;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::f32::consts::PI;
 
 use cpu_time::ProcessTime;
 use std::time::Duration;
-use byteorder::{ByteOrder, LittleEndian};
-use rand::{SeedableRng, StdRng, Rng};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use rand::{SeedableRng, Rng};
+use rand::rngs::StdRng;
+use rand_distr::{Distribution, Normal};
 use float_cmp::ApproxEqUlps;
+#[cfg(feature = "gzip")]
+use flate2::Compression;
+#[cfg(feature = "gzip")]
+use flate2::read::GzDecoder;
+#[cfg(feature = "gzip")]
+use flate2::write::GzEncoder;
+#[cfg(feature = "parallel")]
+use memmap2::Mmap;
+#[cfg(feature = "parallel")]
+use std::sync::Arc;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+pub mod iaea;
+#[cfg(test)]
+mod tests;
 
 const HEADER_LENGTH: usize = 25;
 const MAX_RECORD_LENGTH: usize = 32;
-const BUFFER_CAPACITY: usize = 1 * 1024 * 1024;
+const BUFFER_CAPACITY: usize = 1024 * 1024;
+const MIN_BUFFER_CAPACITY: usize = 64 * 1024;
 const MODE_LENGTH: usize = 5;
+const INDEX_MAGIC: &[u8; 8] = b"EGSINDX1";
+const INDEX_LENGTH: usize = 56;
+// Record fields in on-disk order, each FIELD_LENGTH bytes, MODE0 stops before zlast.
+const RECORD_FIELDS: [(&str, &str); 8] = [("latch", "uint32_t"),
+                                          ("total_energy", "float"),
+                                          ("x_cm", "float"),
+                                          ("y_cm", "float"),
+                                          ("x_cos", "float"),
+                                          ("y_cos", "float"),
+                                          ("weight", "float"),
+                                          ("zlast", "float")];
+const FIELD_LENGTH: usize = 4;
+// Latch bits holding the region a particle was created in.
+const REGION_MASK: u32 = 0x1f00_0000;
+const REGION_SHIFT: u32 = 24;
+// Rounding slack allowed on x_cos^2 + y_cos^2 before a record counts as bad.
+const COSINE_TOLERANCE: f32 = 1e-5;
+// Header fields with their offsets and array lengths, the rest of the record is padding.
+const HEADER_FIELDS: [(&str, &str, usize, usize); 6] =
+    [("mode", "char", 0, MODE_LENGTH),
+     ("total_particles", "int32_t", 5, 1),
+     ("total_photons", "int32_t", 9, 1),
+     ("max_energy", "float", 13, 1),
+     ("min_energy", "float", 17, 1),
+     ("total_particles_in_source", "float", 21, 1)];
+/// Electron rest mass energy in MeV.
+pub const ELECTRON_REST_MASS: f32 = 0.510_998_95;
+/// Relative change in total weight, as returned by `reweight`, that still
+/// counts as conserving fluence.
+pub const FLUENCE_TOLERANCE: f64 = 1e-6;
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Header {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_mode"))]
     pub mode: [u8; 5],
     pub total_particles: i32,
     pub total_photons: i32,
@@ -36,6 +105,7 @@ pub struct Header {
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Record {
     pub latch: u32,
     total_energy: f32,
@@ -50,7 +120,127 @@ pub struct Record {
 #[derive(Debug)]
 pub struct Transform;
 
+/// Byte order of the numbers in a phase space file. EGSnrc writes the order of
+/// the machine it runs on, which is little endian everywhere now, but some old
+/// builds left big endian files behind.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ByteOrderMode {
+    #[default]
+    Little,
+    Big,
+}
+
+impl ByteOrderMode {
+    fn read_u32(self, buffer: &[u8]) -> u32 {
+        match self {
+            ByteOrderMode::Little => LittleEndian::read_u32(buffer),
+            ByteOrderMode::Big => BigEndian::read_u32(buffer),
+        }
+    }
+
+    fn read_i32(self, buffer: &[u8]) -> i32 {
+        self.read_u32(buffer) as i32
+    }
+
+    fn read_f32(self, buffer: &[u8]) -> f32 {
+        f32::from_bits(self.read_u32(buffer))
+    }
+
+    fn write_u32(self, buffer: &mut [u8], n: u32) {
+        match self {
+            ByteOrderMode::Little => LittleEndian::write_u32(buffer, n),
+            ByteOrderMode::Big => BigEndian::write_u32(buffer, n),
+        }
+    }
+
+    fn write_i32(self, buffer: &mut [u8], n: i32) {
+        self.write_u32(buffer, n as u32)
+    }
+
+    fn write_f32(self, buffer: &mut [u8], n: f32) {
+        self.write_u32(buffer, n.to_bits())
+    }
+}
+
+/// What to do with a record that falls outside a limit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClampMode {
+    /// Drop the record, the header counts are updated to match.
+    Discard,
+    /// Pull the offending value back onto the limit.
+    Clamp,
+}
+
+/// How far apart values may be for `similar_to_within` to call them the same.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tolerance {
+    /// MeV.
+    pub energy: f32,
+    /// cm.
+    pub position: f32,
+    /// Direction cosines.
+    pub direction: f32,
+    pub weight: f32,
+    /// Relative, for the header's incident histories.
+    pub histories: f32,
+}
+
+impl Tolerance {
+    pub fn uniform(tolerance: f32) -> Tolerance {
+        Tolerance {
+            energy: tolerance,
+            position: tolerance,
+            direction: tolerance,
+            weight: tolerance,
+            histories: tolerance,
+        }
+    }
+}
+
+impl Default for Tolerance {
+    /// 0.01 either way on every field, what `diff` allows.
+    fn default() -> Tolerance {
+        Tolerance::uniform(0.01)
+    }
+}
+
+/// Species of a record, taken from the charge bits of the latch.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ParticleType {
+    Photon,
+    Electron,
+    Positron,
+}
+
+/// Species encoded in the charge bits of `latch`, for callers that only have the
+/// latch, such as `PHSPReader::filter_latch` predicates.
+pub fn particle_type_of(latch: u32) -> ParticleType {
+    if latch & (1 << 30) != 0 {
+        ParticleType::Electron
+    } else if latch & (1 << 29) != 0 {
+        ParticleType::Positron
+    } else {
+        ParticleType::Photon
+    }
+}
+
+/// Region the particle was created in, bits 24 to 28 of `latch`.
+pub fn region_index_of(latch: u32) -> u32 {
+    (latch & REGION_MASK) >> REGION_SHIFT
+}
+
+/// Whether bit 0 of `latch`, set for bremsstrahlung or annihilation photons, is set.
+pub fn bremsstrahlung_or_annihilation_of(latch: u32) -> bool {
+    latch & 1 != 0
+}
+
+/// Everything that can go wrong in this crate.
+///
+/// New variants get added as checks are added, so the enum is marked
+/// `#[non_exhaustive]` and matches on it need a wildcard arm. It implements
+/// `std::error::Error`, so `?` converts it into a `Box<dyn Error>`.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum EGSError {
     Io(io::Error),
     BadMode,
@@ -58,6 +248,23 @@ pub enum EGSError {
     ModeMismatch,
     HeaderMismatch,
     RecordMismatch,
+    BadHeader,
+    ReadFailed { record: u64, offset: u64, err: io::Error },
+    BadTransform(String),
+    OutputIsInput(PathBuf),
+    Unsupported(&'static str),
+    RecordOutOfRange(u64),
+    StaleIndex(&'static str),
+    BadRecord(u64),
+    ZeroWeight,
+    BadRegion(u32),
+    TooManyParticles(u64),
+    PartialRecord { record: u64, bytes: u64 },
+    EmptyRange { start: u64, end: u64 },
+    WrongRecordSize { expected: u64, actual: u64 },
+    NonFinite { record: u64, field: &'static str },
+    StaleCheckpoint(PathBuf),
+    InvalidArgument(&'static str),
 }
 
 pub type EGSResult<T> = Result<T, EGSError>;
@@ -84,333 +291,4105 @@ impl fmt::Display for EGSError {
             EGSError::ModeMismatch => write!(f, "Input file MODE0/MODE2 do not match"),
             EGSError::HeaderMismatch => write!(f, "Headers are different"),
             EGSError::RecordMismatch => write!(f, "Records are different"),
+            EGSError::BadHeader => {
+                write!(f,
+                       "Header values are inconsistent (negative counts or more photons than particles)")
+            }
+            EGSError::ReadFailed { record, offset, ref err } => {
+                write!(f,
+                       "Failed to read record {} at byte offset {}: {}",
+                       record,
+                       offset,
+                       err)
+            }
+            EGSError::BadTransform(ref op) => write!(f, "Invalid transform operation: {}", op),
+            EGSError::OutputIsInput(ref path) => {
+                write!(f, "Output file {} is also an input", path.display())
+            }
+            EGSError::Unsupported(what) => write!(f, "Not supported: {}", what),
+            EGSError::RecordOutOfRange(index) => {
+                write!(f, "Record {} is past the end of the file", index)
+            }
+            EGSError::StaleIndex(what) => {
+                write!(f, "Index does not match the phase space file ({} differs)", what)
+            }
+            EGSError::BadRecord(index) => {
+                write!(f, "Record {} has direction cosines outside the unit circle", index)
+            }
+            EGSError::ZeroWeight => write!(f, "Total weight is zero"),
+            EGSError::BadRegion(region) => {
+                write!(f, "Region {} does not fit in the latch, regions go up to 31", region)
+            }
+            EGSError::TooManyParticles(count) => {
+                write!(f,
+                       "{} particles do not fit the 32 bit count in an EGSnrc header",
+                       count)
+            }
+            EGSError::PartialRecord { record, bytes } => {
+                write!(f,
+                       "File ends part way through record {}, only {} of its bytes are there",
+                       record,
+                       bytes)
+            }
+            EGSError::EmptyRange { start, end } => {
+                write!(f, "Record range {}..{} is empty", start, end)
+            }
+            EGSError::WrongRecordSize { expected, actual } => {
+                write!(f, "Got a {} byte record, the file takes {} bytes", actual, expected)
+            }
+            EGSError::NonFinite { record, field } => {
+                write!(f, "Field {} of record {} is NaN or infinite", field, record)
+            }
+            EGSError::StaleCheckpoint(ref path) => {
+                write!(f,
+                       "{} does not match these inputs, delete it to start over",
+                       path.display())
+            }
+            EGSError::InvalidArgument(what) => write!(f, "Invalid argument: {}", what),
         }
     }
 }
 
 impl Error for EGSError {
-    fn description(&self) -> &str {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         match *self {
-            EGSError::Io(ref err) => err.description(),
-            EGSError::BadMode => "invalid mode",
-            EGSError::BadLength => "bad file length",
-            EGSError::ModeMismatch => "mode mismatch",
-            EGSError::HeaderMismatch => "header mismatch",
-            EGSError::RecordMismatch => "record mismatch",
+            EGSError::Io(ref err) => Some(err),
+            EGSError::ReadFailed { ref err, .. } => Some(err),
+            _ => None,
         }
     }
+}
 
-    fn cause(&self) -> Option<&dyn Error> {
-        match *self {
-            EGSError::Io(ref err) => Some(err),
-            EGSError::BadMode => None,
-            EGSError::BadLength => None,
-            EGSError::ModeMismatch => None,
-            EGSError::HeaderMismatch => None,
-            EGSError::RecordMismatch => None,
+/// What `PHSPReader::open` reads from: a plain file, a compressed one or stdin.
+/// Only plain files can seek, the others fail with `io::ErrorKind::Unsupported`.
+pub struct Source(Input);
+
+enum Input {
+    File(File),
+    Stdin(io::Stdin),
+    #[cfg(feature = "gzip")]
+    Gzip(GzDecoder<File>),
+}
+
+impl Seek for Source {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self.0 {
+            Input::File(ref mut file) => file.seek(pos),
+            Input::Stdin(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "stdin cannot seek")),
+            #[cfg(feature = "gzip")]
+            Input::Gzip(_) => {
+                Err(io::Error::new(io::ErrorKind::Unsupported, "gzip streams cannot seek"))
+            }
+        }
+    }
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0 {
+            Input::File(ref mut file) => file.read(buf),
+            Input::Stdin(ref mut stdin) => stdin.read(buf),
+            #[cfg(feature = "gzip")]
+            Input::Gzip(ref mut decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// What `PHSPWriter::create` writes to: a plain file, a compressed one or nothing
+/// at all for dry runs. Compressed files can't seek back to patch the header.
+pub struct Sink(Output);
+
+enum Output {
+    File(File),
+    // dry runs
+    Discard(io::Sink),
+    #[cfg(feature = "gzip")]
+    Gzip(GzEncoder<File>),
+}
+
+impl Seek for Sink {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self.0 {
+            Output::File(ref mut file) => file.seek(pos),
+            Output::Discard(_) => Ok(0),
+            #[cfg(feature = "gzip")]
+            Output::Gzip(_) => {
+                Err(io::Error::new(io::ErrorKind::Unsupported, "gzip streams cannot seek"))
+            }
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0 {
+            Output::File(ref mut file) => file.write(buf),
+            Output::Discard(ref mut sink) => sink.write(buf),
+            #[cfg(feature = "gzip")]
+            Output::Gzip(ref mut encoder) => encoder.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0 {
+            Output::File(ref mut file) => file.flush(),
+            Output::Discard(ref mut sink) => sink.flush(),
+            #[cfg(feature = "gzip")]
+            Output::Gzip(ref mut encoder) => encoder.flush(),
         }
     }
 }
 
-pub struct PHSPReader {
-    reader: BufReader<File>,
+// Seeks on sources and sinks that can only go forwards fail with
+// `ErrorKind::Unsupported`, reported as `EGSError::Unsupported(action)`.
+fn unless_unsupported(action: &'static str) -> impl FnOnce(io::Error) -> EGSError {
+    move |err| if err.kind() == io::ErrorKind::Unsupported {
+        EGSError::Unsupported(action)
+    } else {
+        EGSError::Io(err)
+    }
+}
+
+/// True when `path` ends in `.gz` and is read/written compressed.
+pub fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Reads records from any `Read`, by default the files of `open` or stdin.
+/// Random access needs `R: Seek`.
+pub struct PHSPReader<R = Source> {
+    reader: BufReader<R>,
     pub header: Header,
+    byte_order: ByteOrderMode,
+    file_length: Option<u64>,
     next_record: u64,
+    // read until the data runs out instead of stopping at the header's count
+    until_eof: bool,
 }
 
-pub struct PHSPWriter {
-    writer: BufWriter<File>,
+/// Writes records to any `Write`, by default the files of `create`. Patching
+/// the header in `finalize` needs `W: Seek`.
+pub struct PHSPWriter<W: Write = Sink> {
+    writer: BufWriter<W>,
     pub header: Header,
+    byte_order: ByteOrderMode,
+    // running totals over everything passed to write, used by finalize
+    particles: u64,
+    photons: u64,
+    min_energy: f32,
+    max_energy: f32,
+    sanitize: bool,
 }
 
 
 impl PHSPReader {
     pub fn from(file: File) -> EGSResult<PHSPReader> {
+        PHSPReader::from_with_capacity(file, BUFFER_CAPACITY)
+    }
+
+    /// Like `from` but read through a buffer of `capacity` bytes rather than the
+    /// default 1 MiB, larger for fast disks and network filesystems, smaller
+    /// when memory is tight.
+    pub fn from_with_capacity(file: File, capacity: usize) -> EGSResult<PHSPReader> {
         let actual_size = file.metadata()?.len();
-        let mut reader = BufReader::with_capacity(BUFFER_CAPACITY, file);
-        let mut buffer = [0; HEADER_LENGTH];
-        reader.read_exact(&mut buffer)?;
-        let mut mode = [0; MODE_LENGTH];
-        mode.clone_from_slice(&buffer[0..5]);
-        let header = Header {
-            mode: mode,
-            total_particles: LittleEndian::read_i32(&buffer[5..9]),
-            total_photons: LittleEndian::read_i32(&buffer[9..13]),
-            max_energy: LittleEndian::read_f32(&buffer[13..17]),
-            min_energy: LittleEndian::read_f32(&buffer[17..21]),
-            total_particles_in_source: LittleEndian::read_f32(&buffer[21..25]),
-            using_zlast: &mode == b"MODE2",
-            record_size: if &mode == b"MODE0" {
-                28
-            } else if &mode == b"MODE2" {
-                32
-            } else {
-                return Err(EGSError::BadMode);
-            },
-        };
-        if actual_size != header.expected_size() as u64 {
-            writeln!(&mut std::io::stderr(),
-                     "Expected {} bytes in file, not {}",
-                     header.expected_size(),
-                     actual_size)
-                .unwrap();
-            //return Err(EGSError::BadLength);
-        }
-        reader.consume(header.record_size as usize - HEADER_LENGTH);
-        Ok(PHSPReader {
-            reader: reader,
-            header: header,
-            next_record: 0,
-        })
+        PHSPReader::from_source(Source(Input::File(file)), Some(actual_size), capacity)
     }
-}
 
-impl Iterator for PHSPReader {
-    type Item = EGSResult<Record>;
-    fn next(&mut self) -> Option<EGSResult<Record>> {
-        if self.next_record >= self.header.total_particles as u64 {
-            return None;
-        }
-        let mut buffer = [0; MAX_RECORD_LENGTH];
-        match self.reader.read_exact(&mut buffer[..self.header.record_size as usize]) {
-            Ok(()) => (),
-            Err(err) => return Some(Err(EGSError::Io(err))),
-        };
-        self.next_record += 1;
-        Some(Ok(Record {
-            latch: LittleEndian::read_u32(&buffer[0..4]),
-            total_energy: LittleEndian::read_f32(&buffer[4..8]),
-            x_cm: LittleEndian::read_f32(&buffer[8..12]),
-            y_cm: LittleEndian::read_f32(&buffer[12..16]),
-            x_cos: LittleEndian::read_f32(&buffer[16..20]),
-            y_cos: LittleEndian::read_f32(&buffer[20..24]),
-            weight: LittleEndian::read_f32(&buffer[24..28]),
-            zlast: if self.header.using_zlast {
-                Some(LittleEndian::read_f32(&buffer[28..32]))
-            } else {
-                None
-            },
-        }))
+    /// Like `from` but ignore the header's particle count, for recovering files whose
+    /// header was never updated. The count is taken from the file length instead,
+    /// a partial trailing record is skipped, and iteration runs to end of file.
+    pub fn from_infer_count(file: File) -> EGSResult<PHSPReader> {
+        let actual_size = file.metadata()?.len();
+        let mut reader = PHSPReader::from_source(Source(Input::File(file)), None, BUFFER_CAPACITY)?;
+        let record_size = reader.header.record_size;
+        let count = actual_size.saturating_sub(record_size) / record_size;
+        reader.header.total_particles = i32::try_from(count).map_err(|_| EGSError::BadHeader)?;
+        reader.until_eof = true;
+        Ok(reader)
     }
-}
 
-impl PHSPWriter {
-    pub fn from(file: File, header: &Header) -> EGSResult<PHSPWriter> {
-        let mut writer = BufWriter::with_capacity(BUFFER_CAPACITY, file);
-        let mut buffer = [0; MAX_RECORD_LENGTH];
-        buffer[0..5].clone_from_slice(&header.mode);
-        LittleEndian::write_i32(&mut buffer[5..9], header.total_particles);
-        LittleEndian::write_i32(&mut buffer[9..13], header.total_photons);
-        LittleEndian::write_f32(&mut buffer[13..17], header.max_energy);
-        LittleEndian::write_f32(&mut buffer[17..21], header.min_energy);
-        LittleEndian::write_f32(&mut buffer[21..25], header.total_particles_in_source);
-        writer.write_all(&buffer[..header.record_size as usize])?;
-        Ok(PHSPWriter {
-            header: *header,
-            writer: writer,
-        })
+    /// Open `path`, decompressing on the fly if it ends in `.gz` (needs the `gzip` feature).
+    ///
+    /// Compressed readers can only be read front to back, they do not support
+    /// `seek_to_record` and their length is not checked against the header.
+    pub fn open(path: &Path) -> EGSResult<PHSPReader> {
+        PHSPReader::open_with_capacity(path, BUFFER_CAPACITY)
     }
 
-    pub fn write(&mut self, record: &Record) -> EGSResult<()> {
-        let mut buffer = [0; 32];
-        LittleEndian::write_u32(&mut buffer[0..4], record.latch);
-        LittleEndian::write_f32(&mut buffer[4..8], record.total_energy);
-        LittleEndian::write_f32(&mut buffer[8..12], record.x_cm);
-        LittleEndian::write_f32(&mut buffer[12..16], record.y_cm);
-        LittleEndian::write_f32(&mut buffer[16..20], record.x_cos);
-        LittleEndian::write_f32(&mut buffer[20..24], record.y_cos);
-        LittleEndian::write_f32(&mut buffer[24..28], record.weight);
-        if self.header.using_zlast {
-            LittleEndian::write_f32(&mut buffer[28..32], record.weight);
+    /// `open` reading through a buffer of `capacity` bytes, see `from_with_capacity`.
+    pub fn open_with_capacity(path: &Path, capacity: usize) -> EGSResult<PHSPReader> {
+        let file = File::open(path)?;
+        if is_gzip(path) {
+            PHSPReader::from_gzip(file, capacity)
+        } else {
+            PHSPReader::from_with_capacity(file, capacity)
         }
-        self.writer.write_all(&buffer[..self.header.record_size as usize])?;
-        Ok(())
     }
-}
 
-impl Header {
-    fn expected_size(&self) -> usize {
-        (self.total_particles as usize + 1) * self.record_size as usize
+    /// Read a phase space piped into stdin. Like a compressed reader it can only be
+    /// read front to back and its length is not checked against the header. Functions
+    /// taking paths read their inputs more than once, so they can't be given stdin.
+    pub fn from_stdin() -> EGSResult<PHSPReader> {
+        PHSPReader::from_source(Source(Input::Stdin(io::stdin())), None, BUFFER_CAPACITY)
     }
-    pub fn similar_to(&self, other: &Header) -> bool {
-        self.mode == other.mode && self.total_particles == other.total_particles &&
-        self.total_photons == other.total_photons &&
-        self.max_energy.approx_eq_ulps(&other.max_energy, 10) &&
-        self.min_energy.approx_eq_ulps(&other.min_energy, 10) &&
-        self.total_particles_in_source.approx_eq_ulps(&other.total_particles_in_source, 2)
+
+    #[cfg(feature = "gzip")]
+    fn from_gzip(file: File, capacity: usize) -> EGSResult<PHSPReader> {
+        PHSPReader::from_source(Source(Input::Gzip(GzDecoder::new(file))), None, capacity)
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    fn from_gzip(_: File, _: usize) -> EGSResult<PHSPReader> {
+        Err(EGSError::Unsupported("gzip files, rebuild with --features gzip"))
     }
-    fn merge(&mut self, other: &Header) {
-        assert!(&self.mode == &other.mode, "Merge mode mismatch");
-        self.total_particles = self.total_particles
-            .checked_add(other.total_particles)
-            .expect("Too many particles, i32 overflow");
-        self.total_photons += other.total_photons;
-        self.min_energy = self.min_energy.min(other.min_energy);
-        self.max_energy = self.max_energy.max(other.max_energy);
-        self.total_particles_in_source += other.total_particles_in_source;
+
+    /// Open `file` after checking it against the sidecar written by `build_index`,
+    /// failing with `StaleIndex` if the file has changed since.
+    pub fn with_index(file: File, index_path: &Path) -> EGSResult<PHSPReader> {
+        let mut buffer = [0; INDEX_LENGTH];
+        File::open(index_path)?.read_exact(&mut buffer)?;
+        if &buffer[0..8] != INDEX_MAGIC {
+            return Err(EGSError::StaleIndex("format"));
+        }
+        let file_length = file.metadata()?.len();
+        if LittleEndian::read_u64(&buffer[8..16]) != file_length {
+            return Err(EGSError::StaleIndex("file length"));
+        }
+        let mut reader = PHSPReader::from(file)?;
+        if LittleEndian::read_u64(&buffer[16..24]) != reader.header.record_size {
+            return Err(EGSError::StaleIndex("record size"));
+        }
+        if LittleEndian::read_u64(&buffer[24..32]) != reader.header.total_particles as u64 {
+            return Err(EGSError::StaleIndex("particle count"));
+        }
+        let checksum = match reader.reader.get_mut().0 {
+            Input::File(ref mut file) => checksum(file)?,
+            _ => unreachable!(),
+        };
+        if LittleEndian::read_u64(&buffer[32..40]) != checksum {
+            return Err(EGSError::StaleIndex("checksum"));
+        }
+        reader.seek_to_record(0)?;
+        Ok(reader)
     }
 }
 
+impl<'a> PHSPReader<Cursor<&'a [u8]>> {
+    /// Read a phase space held in memory. Unlike `from_reader` the length is known,
+    /// so it is checked against the header and big endian phase spaces are
+    /// recognised just as they are for files.
+    pub fn from_bytes(bytes: &'a [u8]) -> EGSResult<PHSPReader<Cursor<&'a [u8]>>> {
+        let capacity = bytes.len().min(BUFFER_CAPACITY);
+        PHSPReader::from_source(Cursor::new(bytes), Some(bytes.len() as u64), capacity)
+    }
+}
 
-impl Record {
-    pub fn similar_to(&self, other: &Record) -> bool {
-        self.latch == other.latch && self.total_energy() - other.total_energy() < 0.01 &&
-        self.x_cm - other.x_cm < 0.01 && self.y_cm - other.y_cm < 0.01 &&
-        self.x_cos - other.x_cos < 0.01 && self.y_cos - other.y_cos < 0.01 &&
-        self.weight - other.weight < 0.01 && self.zlast == other.zlast
+impl<R: Read> PHSPReader<R> {
+    /// Read a phase space from anything that is `Read`, such as a pipe or a
+    /// `Cursor` over bytes in memory. The length isn't known, so it is not checked
+    /// against the header and the numbers are taken as little endian.
+    pub fn from_reader(reader: R) -> EGSResult<PHSPReader<R>> {
+        PHSPReader::from_source(reader, None, BUFFER_CAPACITY)
     }
-    pub fn bremsstrahlung_or_annihilation(&self) -> bool {
-        self.latch & 1 != 0
+
+    /// Iterate like the reader itself but fail with `BadRecord` on any record
+    /// whose `x_cos^2 + y_cos^2` exceeds 1, where `z_cos` would be NaN.
+    pub fn validated(self) -> Validated<R> {
+        Validated { reader: self }
     }
-    pub fn bit_region(&self) -> u32 {
-        self.latch & 0xfffffe
+
+    /// Records the header says are still to come. A file shorter than its header
+    /// claims ends in a `ReadFailed` error before this reaches zero.
+    pub fn records_remaining(&self) -> u64 {
+        (self.header.total_particles.max(0) as u64).saturating_sub(self.next_record)
     }
-    pub fn region_number(&self) -> u32 {
-        self.latch & 0xf000000
+
+    /// Byte order of the file, found from its length when it was opened. Files
+    /// whose length says nothing, like compressed ones, are taken as little endian.
+    pub fn byte_order(&self) -> ByteOrderMode {
+        self.byte_order
     }
-    pub fn b29(&self) -> bool {
-        self.latch & (1 << 29) != 0
+
+    /// Length of the file in bytes when it was opened, `None` where it isn't
+    /// known, like for compressed ones. Files whose header miscounts their
+    /// records are still read, compare this with `Header::expected_file_size`
+    /// to find them.
+    pub fn file_length(&self) -> Option<u64> {
+        self.file_length
     }
-    pub fn charged(&self) -> bool {
-        self.latch & (1 << 30) != 0
+
+    /// Iterate over the remaining records as stored, `record_size` bytes each
+    /// zero padded to 32, skipping the decode for callers that only pass bytes on.
+    /// Errors are the same as iterating the reader itself.
+    pub fn raw_records(&mut self) -> RawRecords<'_, R> {
+        RawRecords { reader: self }
     }
-    pub fn crossed_multiple(&self) -> bool {
-        self.latch & (1 << 30) != 0
+
+    /// Iterate over the remaining records whose latch passes `predicate`. Only the
+    /// latch of each record is read until it passes, so filters on particle type
+    /// or region skip decoding the floats of everything they leave out.
+    pub fn filter_latch<F>(&mut self, predicate: F) -> LatchFilter<'_, F, R>
+        where F: FnMut(u32) -> bool
+    {
+        LatchFilter {
+            reader: self,
+            predicate,
+        }
     }
-    pub fn get_weight(&self) -> f32 {
-        self.weight.abs()
+
+    fn from_source(source: R,
+                   actual_size: Option<u64>,
+                   capacity: usize)
+                   -> EGSResult<PHSPReader<R>> {
+        let mut reader = BufReader::with_capacity(capacity, source);
+        let mut buffer = [0; MAX_RECORD_LENGTH];
+        reader.read_exact(&mut buffer[..HEADER_LENGTH])?;
+        let mut byte_order = ByteOrderMode::Little;
+        let mut header = Header::decode(&buffer, byte_order)?;
+        // files that only add up read the other way round are big endian
+        if let Some(actual_size) = actual_size {
+            let swapped = Header::decode(&buffer, ByteOrderMode::Big)?;
+            if !header.fits(actual_size) && swapped.fits(actual_size) {
+                byte_order = ByteOrderMode::Big;
+                header = swapped;
+            }
+        }
+        if let Some(actual_size) = actual_size {
+            if actual_size < header.record_size {
+                return Err(EGSError::BadLength);
+            }
+            // a body that isn't a whole number of records ends in a partial record
+            let body = actual_size - header.record_size;
+            if !body.is_multiple_of(header.record_size) {
+                return Err(EGSError::PartialRecord {
+                    record: body / header.record_size,
+                    bytes: body % header.record_size,
+                });
+            }
+        }
+        // rest of the header record is padding
+        reader.read_exact(&mut buffer[HEADER_LENGTH..header.record_size as usize])?;
+        Ok(PHSPReader {
+            reader,
+            header,
+            byte_order,
+            file_length: actual_size,
+            next_record: 0,
+            until_eof: false,
+        })
     }
-    pub fn set_weight(&mut self, new_weight: f32) {
-        self.weight = new_weight * self.weight.signum();
+}
+
+impl<R: Read + Seek> PHSPReader<R> {
+    /// Iterate over windows of `size` consecutive records, each starting `step`
+    /// records after the last, by seeking back `size - step` records between
+    /// windows. Only full windows are returned and only plain files can seek. A
+    /// `size` or `step` of zero fails with `InvalidArgument`.
+    pub fn windows(self, size: usize, step: usize) -> EGSResult<Windows<R>> {
+        if size == 0 || step == 0 {
+            return Err(EGSError::InvalidArgument("window size and step must be positive"));
+        }
+        Ok(Windows {
+            reader: self,
+            size,
+            step,
+            done: false,
+        })
     }
-    pub fn total_energy(&self) -> f32 {
-        self.total_energy.abs()
+
+    /// Iterate over every record from the last to the first, whatever has been
+    /// read already. Records are read a buffer's worth at a time by seeking back
+    /// with `seek_to_record`, so compressed files return `Unsupported`.
+    pub fn rev(self) -> Reversed<R> {
+        let next = self.header.total_particles.max(0) as u64;
+        Reversed {
+            reader: self,
+            next,
+            block: Vec::new(),
+            done: false,
+        }
     }
-    pub fn z_positive(&self) -> bool {
-        self.weight.is_sign_positive()
+
+    /// Position the reader so the next record returned is record `index` (counting
+    /// from zero). Only plain files can seek, compressed ones return `Unsupported`.
+    pub fn seek_to_record(&mut self, index: u64) -> EGSResult<()> {
+        if index > self.header.total_particles as u64 {
+            return Err(EGSError::RecordOutOfRange(index));
+        }
+        self.reader
+            .seek(SeekFrom::Start((index + 1) * self.header.record_size))
+            .map_err(unless_unsupported("seeking in a gzip file or stdin"))?;
+        self.next_record = index;
+        Ok(())
     }
-    pub fn z_cos(&self) -> f32 {
-        (1.0 - (self.x_cos * self.x_cos + self.y_cos * self.y_cos)).sqrt()
+
+    /// The next record without moving past it, so the following `next` or `peek`
+    /// returns it again. `None` at the end of the file. Peeking seeks back over
+    /// the record, so compressed files return `Unsupported`.
+    pub fn peek(&mut self) -> Option<EGSResult<Record>> {
+        // find out the source can't seek before reading past the record
+        if let Err(err) = self.reader
+            .get_mut()
+            .stream_position()
+            .map_err(unless_unsupported("peeking in a gzip file or stdin")) {
+            return Some(Err(err));
+        }
+        let index = self.next_record;
+        let record = self.next()?;
+        if record.is_ok() {
+            if let Err(err) = self.skip_to_record(index) {
+                return Some(Err(err));
+            }
+        }
+        Some(record)
     }
-    pub fn first_scored_by_primary_history(&self) -> bool {
-        return self.total_energy.is_sign_negative();
+
+    /// The bytes of record `index` exactly as stored, `record_size` of them, for
+    /// files that don't decode as expected. The reader is left just after it.
+    pub fn raw_record(&mut self, index: u64) -> EGSResult<Vec<u8>> {
+        if index >= self.header.total_particles as u64 {
+            return Err(EGSError::RecordOutOfRange(index));
+        }
+        self.seek_to_record(index)?;
+        let mut buffer = vec![0; self.header.record_size as usize];
+        match fill(&mut self.reader, &mut buffer) {
+            (read, Ok(())) if read == buffer.len() => (),
+            (read, result) => return Err(self.read_failed(index, read, result)),
+        }
+        self.next_record += 1;
+        Ok(buffer)
     }
 
-    fn transform(&mut self, matrix: &[[f32; 3]; 3]) {
-        let x_cm = self.x_cm;
-        let y_cm = self.y_cm;
-        self.x_cm = matrix[0][0] * x_cm + matrix[0][1] * y_cm + matrix[0][2] * 1.0;
-        self.y_cm = matrix[1][0] * x_cm + matrix[1][1] * y_cm + matrix[1][2] * 1.0;
-        let x_cos = self.x_cos;
-        let y_cos = self.y_cos;
-        self.x_cos = matrix[0][0] * x_cos + matrix[0][1] * y_cos + matrix[0][2] * self.z_cos();
-        self.y_cos = matrix[1][0] * x_cos + matrix[1][1] * y_cos + matrix[1][2] * self.z_cos();
+    // Like `seek_to_record` for a target near the current record, the buffered
+    // bytes are kept when the target is already in them.
+    fn skip_to_record(&mut self, index: u64) -> EGSResult<()> {
+        self.reader
+            .get_mut()
+            .stream_position()
+            .map_err(unless_unsupported("seeking in a gzip file or stdin"))?;
+        let records = index as i64 - self.next_record as i64;
+        self.reader.seek_relative(records * self.header.record_size as i64)?;
+        self.next_record = index;
+        Ok(())
     }
 }
 
-impl Transform {
-    pub fn rotation(matrix: &mut [[f32; 3]; 3], theta: f32) {
-        *matrix =
-            [[theta.cos(), -theta.sin(), 0.0], [theta.sin(), theta.cos(), 0.0], [0.0, 0.0, 1.0]];
-    }
+/// Iterator returned by `PHSPReader::validated`.
+pub struct Validated<R = Source> {
+    reader: PHSPReader<R>,
 }
 
+impl<R: Read> Iterator for Validated<R> {
+    type Item = EGSResult<Record>;
+    fn next(&mut self) -> Option<EGSResult<Record>> {
+        let index = self.reader.next_record;
+        self.reader.next().map(|record| {
+            record.and_then(|record| {
+                let transverse = record.x_cos * record.x_cos + record.y_cos * record.y_cos;
+                if transverse > 1.0 + COSINE_TOLERANCE {
+                    Err(EGSError::BadRecord(index))
+                } else {
+                    Ok(record)
+                }
+            })
+        })
+    }
+}
 
+/// Iterator returned by `PHSPReader::windows`.
+pub struct Windows<R = Source> {
+    reader: PHSPReader<R>,
+    size: usize,
+    step: usize,
+    done: bool,
+}
 
-pub fn combine(input_paths: &[&Path], output_path: &Path, delete: bool) -> EGSResult<()> {
-    assert!(input_paths.len() > 0, "Cannot combine zero files");
-    let start = ProcessTime::now();
-    let reader = PHSPReader::from(File::open(input_paths[0])?)?;
-    let mut final_header = reader.header;
-    for path in input_paths[1..].iter() {
-        let reader = PHSPReader::from(File::open(path)?)?;
-        final_header.merge(&reader.header);
-    }
-    println!("");
-    println!("Final header: {:?}", final_header);
-    println!("");
-    let ofile = File::create(output_path)?;
-    let mut writer = PHSPWriter::from(ofile, &final_header)?;
-    for path in input_paths.iter() {
-        let reader = PHSPReader::from(File::open(path)?)?;
-        for record in reader {
-            writer.write(&record.unwrap())?
+impl<R: Read + Seek> Iterator for Windows<R> {
+    type Item = EGSResult<Vec<Record>>;
+    fn next(&mut self) -> Option<EGSResult<Vec<Record>>> {
+        let start = self.reader.next_record;
+        if self.done || start + self.size as u64 > self.reader.header.total_particles as u64 {
+            return None;
         }
-        if delete {
-            remove_file(path)?;
+        let window = self.reader.by_ref().take(self.size).collect::<EGSResult<Vec<Record>>>();
+        // the next window starts `step` records in, which may be past this one
+        let result = window.and_then(|window| {
+            let next = start + self.step as u64;
+            if next > self.reader.header.total_particles as u64 {
+                self.done = true;
+            } else {
+                self.reader.skip_to_record(next)?;
+            }
+            Ok(window)
+        });
+        if result.is_err() {
+            self.done = true;
         }
+        Some(result)
     }
-    let cpu_time: Duration = start.elapsed();
-    println!("CPU time: {:?}", cpu_time);
-    Ok(())
 }
 
-pub fn sample(ipaths: &[&Path], opath: &Path, rate: u32, seed: &[usize]) -> EGSResult<()> {
-    assert!(ipaths.len() > 0, "Cannot combine zero files");
-    let mut rng: StdRng = SeedableRng::from_seed(seed);
-    let mut header = Header {
-        mode: *b"MODE0",
-        record_size: 28,
-        using_zlast: false,
-        total_particles: 0,
-        total_photons: 0,
-        min_energy: 1000.0,
-        max_energy: 0.0,
-        total_particles_in_source: 0.0,
-    };
-    let mut writer = PHSPWriter::from(File::create(opath)?, &header)?;
-    for path in ipaths.iter() {
-        let reader = PHSPReader::from(File::open(path)?)?;
-        assert!(!reader.header.using_zlast);
-        println!("Found {} particles", reader.header.total_particles);
-        header.total_particles_in_source += reader.header.total_particles_in_source;
-        let records = reader.filter(|_| rng.gen_weighted_bool(rate));
-        for record in records.map(|r| r.unwrap()) {
-            header.total_particles =
-                header.total_particles.checked_add(1).expect("Total particles overflow");
-            if !record.charged() {
-                header.total_photons += 1;
-            }
-            if record.total_energy > 0.0 {
-                header.min_energy = header.min_energy.min(record.total_energy);
-                header.max_energy = header.max_energy.max(record.total_energy);
+/// Iterator returned by `PHSPReader::rev`.
+pub struct Reversed<R = Source> {
+    reader: PHSPReader<R>,
+    // records before this one are still to be read
+    next: u64,
+    block: Vec<Record>,
+    done: bool,
+}
+
+impl<R: Read + Seek> Iterator for Reversed<R> {
+    type Item = EGSResult<Record>;
+    fn next(&mut self) -> Option<EGSResult<Record>> {
+        if self.block.is_empty() {
+            if self.done || self.next == 0 {
+                return None;
             }
-            writer.write(&record)?;
+            let records = BUFFER_CAPACITY as u64 / self.reader.header.record_size;
+            let start = self.next.saturating_sub(records);
+            let block = self.reader
+                .seek_to_record(start)
+                .and_then(|_| {
+                    self.reader.by_ref().take((self.next - start) as usize).collect()
+                });
+            match block {
+                Ok(block) => self.block = block,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+            self.next = start;
         }
-        println!("Now have {} particles", header.total_particles);
+        self.block.pop().map(Ok)
     }
-    header.total_particles_in_source /= rate as f32;
-    drop(writer);
-    // write out the header
-    let ofile = OpenOptions::new().write(true).create(true).open(opath)?;
-    PHSPWriter::from(ofile, &header)?;
-    Ok(())
 }
 
-pub fn transform(input_path: &Path, output_path: &Path, matrix: &[[f32; 3]; 3]) -> EGSResult<()> {
-    let ifile = File::open(input_path)?;
-    let reader = PHSPReader::from(ifile)?;
-    let ofile;
-    if input_path == output_path {
-        println!("Transforming {} in place", input_path.display());
-        ofile = OpenOptions::new().write(true).create(true).open(output_path)?;
-    } else {
-        // different path (create/truncate destination)
-        println!("Transforming {} and saving to {}",
-                 input_path.display(),
-                 output_path.display());
-        ofile = File::create(output_path)?;
-    }
-    let mut writer = PHSPWriter::from(ofile, &reader.header)?;
-    let n_particles = reader.header.total_particles;
-    let mut records_transformed = 0;
-    for mut record in reader.map(|r| r.unwrap()) {
-        record.transform(&matrix);
-        writer.write(&record)?;
-        records_transformed += 1;
-    }
+impl<R: Read> PHSPReader<R> {
+    // The next record's bytes, zero padded, without decoding them.
+    fn next_raw(&mut self) -> Option<EGSResult<[u8; MAX_RECORD_LENGTH]>> {
+        if !self.until_eof && self.next_record >= self.header.total_particles as u64 {
+            return None;
+        }
+        let mut buffer = [0; MAX_RECORD_LENGTH];
+        let record_size = self.header.record_size as usize;
+        match fill(&mut self.reader, &mut buffer[..record_size]) {
+            (read, Ok(())) if read == record_size => (),
+            // a partial trailing record is expected when the count was inferred
+            (_, Ok(())) if self.until_eof => return None,
+            (read, Ok(())) if read > 0 => {
+                return Some(Err(EGSError::PartialRecord {
+                    record: self.next_record,
+                    bytes: read as u64,
+                }));
+            }
+            (read, result) => {
+                return Some(Err(self.read_failed(self.next_record, read, result)));
+            }
+        };
+        self.next_record += 1;
+        Some(Ok(buffer))
+    }
+
+    // `ReadFailed` for record `index` after `read` of its bytes came in, the
+    // offset being where reading stopped. `result` is what the last read gave,
+    // `Ok` meaning the file ended.
+    fn read_failed(&self, index: u64, read: usize, result: io::Result<()>) -> EGSError {
+        let err = result.err().unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof,
+                           "file ends before the last record the header counts")
+        });
+        // the header occupies the first record slot
+        EGSError::ReadFailed {
+            record: index,
+            offset: (index + 1) * self.header.record_size + read as u64,
+            err,
+        }
+    }
+}
+
+/// Iterator returned by `PHSPReader::raw_records`.
+pub struct RawRecords<'a, R = Source> {
+    reader: &'a mut PHSPReader<R>,
+}
+
+impl<'a, R: Read> Iterator for RawRecords<'a, R> {
+    type Item = EGSResult<[u8; MAX_RECORD_LENGTH]>;
+    fn next(&mut self) -> Option<EGSResult<[u8; MAX_RECORD_LENGTH]>> {
+        self.reader.next_raw()
+    }
+}
+
+/// Iterator returned by `PHSPReader::filter_latch`.
+pub struct LatchFilter<'a, F, R = Source> {
+    reader: &'a mut PHSPReader<R>,
+    predicate: F,
+}
+
+impl<'a, F, R: Read> Iterator for LatchFilter<'a, F, R>
+    where F: FnMut(u32) -> bool
+{
+    type Item = EGSResult<Record>;
+    fn next(&mut self) -> Option<EGSResult<Record>> {
+        loop {
+            let buffer = match self.reader.next_raw()? {
+                Ok(buffer) => buffer,
+                Err(err) => return Some(Err(err)),
+            };
+            let order = self.reader.byte_order;
+            if (self.predicate)(Record::latch_only(&buffer, order)) {
+                return Some(Ok(Record::decode(&buffer, self.reader.header.using_zlast, order)));
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for PHSPReader<R> {
+    type Item = EGSResult<Record>;
+    fn next(&mut self) -> Option<EGSResult<Record>> {
+        self.next_raw().map(|buffer| {
+            buffer.map(|buffer| Record::decode(&buffer, self.header.using_zlast, self.byte_order))
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.records_remaining() as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+/// `len()` is `records_remaining()`, which trusts the header.
+impl<R: Read> ExactSizeIterator for PHSPReader<R> {}
+
+impl PHSPWriter {
+    pub fn from(file: File, header: &Header) -> EGSResult<PHSPWriter> {
+        PHSPWriter::from_with_capacity(file, header, BUFFER_CAPACITY)
+    }
+
+    /// Like `from` but write through a buffer of `capacity` bytes rather than the
+    /// default 1 MiB.
+    pub fn from_with_capacity(file: File,
+                              header: &Header,
+                              capacity: usize)
+                              -> EGSResult<PHSPWriter> {
+        PHSPWriter::from_sink(Sink(Output::File(file)), header, capacity, ByteOrderMode::Little)
+    }
+
+    /// Like `from` but write the numbers in `byte_order`, for programs that can
+    /// only read big endian files.
+    pub fn from_with_byte_order(file: File,
+                                header: &Header,
+                                byte_order: ByteOrderMode)
+                                -> EGSResult<PHSPWriter> {
+        PHSPWriter::from_sink(Sink(Output::File(file)), header, BUFFER_CAPACITY, byte_order)
+    }
+
+    /// Create `path`, compressing on the fly if it ends in `.gz` (needs the `gzip` feature).
+    pub fn create(path: &Path, header: &Header) -> EGSResult<PHSPWriter> {
+        let file = File::create(path)?;
+        if is_gzip(path) {
+            PHSPWriter::from_gzip(file, header)
+        } else {
+            PHSPWriter::from(file, header)
+        }
+    }
+
+    /// Add records to the end of an existing phase space, `file` must be open for
+    /// reading and writing. The counts and energy extents start from its header,
+    /// so `finalize` leaves a header covering old and new records alike, and
+    /// records are written in the file's own byte order. Fails
+    /// with `BadLength` when the file length doesn't match its header, as
+    /// appending would then leave records the header can't account for.
+    pub fn append(mut file: File) -> EGSResult<PHSPWriter> {
+        let actual_size = file.metadata()?.len();
+        let mut buffer = [0; MAX_RECORD_LENGTH];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buffer[..HEADER_LENGTH])?;
+        // whichever byte order makes the header match the length
+        let (header, byte_order) = [ByteOrderMode::Little, ByteOrderMode::Big]
+            .iter()
+            .map(|&order| Header::decode(&buffer, order).map(|header| (header, order)))
+            .collect::<EGSResult<Vec<_>>>()?
+            .into_iter()
+            .find(|&(header, _)| header.fits(actual_size))
+            .ok_or(EGSError::BadLength)?;
+        if header.total_photons < 0 {
+            return Err(EGSError::BadHeader);
+        }
+        file.seek(SeekFrom::End(0))?;
+        let (min_energy, max_energy) = if header.total_particles > 0 {
+            (header.min_energy, header.max_energy)
+        } else {
+            (f32::MAX, 0.0)
+        };
+        Ok(PHSPWriter {
+            header,
+            byte_order,
+            writer: BufWriter::with_capacity(BUFFER_CAPACITY, Sink(Output::File(file))),
+            particles: header.total_particles as u64,
+            photons: header.total_photons as u64,
+            min_energy,
+            max_energy,
+            sanitize: false,
+        })
+    }
+
+    /// A writer that keeps count of what it is given but throws the bytes away,
+    /// for dry runs.
+    pub fn discard(header: &Header) -> EGSResult<PHSPWriter> {
+        PHSPWriter::from_sink(Sink(Output::Discard(io::sink())),
+                              header,
+                              BUFFER_CAPACITY,
+                              ByteOrderMode::Little)
+    }
+
+    #[cfg(feature = "gzip")]
+    fn from_gzip(file: File, header: &Header) -> EGSResult<PHSPWriter> {
+        let encoder = GzEncoder::new(file, Compression::default());
+        PHSPWriter::from_sink(Sink(Output::Gzip(encoder)),
+                              header,
+                              BUFFER_CAPACITY,
+                              ByteOrderMode::Little)
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    fn from_gzip(_: File, _: &Header) -> EGSResult<PHSPWriter> {
+        Err(EGSError::Unsupported("gzip files, rebuild with --features gzip"))
+    }
+
+}
+
+impl<W: Write> PHSPWriter<W> {
+    /// Write a phase space to anything that is `Write`, such as stdout, a pipe or
+    /// a `Vec<u8>`. Without `Seek` there is no `finalize` to patch the header, so
+    /// `header` should already hold the final counts and energy extents; call
+    /// `into_inner` once everything is written.
+    pub fn to_writer(writer: W, header: &Header) -> EGSResult<PHSPWriter<W>> {
+        PHSPWriter::from_sink(writer, header, BUFFER_CAPACITY, ByteOrderMode::Little)
+    }
+
+    fn from_sink(sink: W,
+                 header: &Header,
+                 capacity: usize,
+                 byte_order: ByteOrderMode)
+                 -> EGSResult<PHSPWriter<W>> {
+        let mut writer = BufWriter::with_capacity(capacity, sink);
+        writer.write_all(&header.encode(byte_order)[..header.record_size as usize])?;
+        Ok(PHSPWriter {
+            header: *header,
+            byte_order,
+            writer,
+            particles: 0,
+            photons: 0,
+            min_energy: f32::MAX,
+            max_energy: 0.0,
+            sanitize: false,
+        })
+    }
+
+    /// Check every float of each record before writing it and fail with
+    /// `NonFinite` naming the field instead of writing a NaN or infinity. Off
+    /// by default as it costs a little on every write.
+    pub fn with_sanitize(mut self, sanitize: bool) -> PHSPWriter<W> {
+        self.sanitize = sanitize;
+        self
+    }
+
+    pub fn byte_order(&self) -> ByteOrderMode {
+        self.byte_order
+    }
+
+    pub fn particles_written(&self) -> u64 {
+        self.particles
+    }
+
+    /// Records written that are photons, see `Record::particle_type`.
+    pub fn photons_written(&self) -> u64 {
+        self.photons
+    }
+
+    /// Smallest energy written, records with a non-positive stored energy don't count.
+    pub fn min_energy_written(&self) -> Option<f32> {
+        if self.min_energy <= self.max_energy {
+            Some(self.min_energy)
+        } else {
+            None
+        }
+    }
+
+    /// Largest energy written, records with a non-positive stored energy don't count.
+    pub fn max_energy_written(&self) -> Option<f32> {
+        if self.min_energy <= self.max_energy {
+            Some(self.max_energy)
+        } else {
+            None
+        }
+    }
+
+    /// Flush the buffered records and hand back what they were written to.
+    pub fn into_inner(self) -> EGSResult<W> {
+        self.writer.into_inner().map_err(|err| EGSError::Io(err.into_error()))
+    }
+
+    pub fn write(&mut self, record: &Record) -> EGSResult<()> {
+        self.check(record)?;
+        let buffer = record.encode(self.header.using_zlast, self.byte_order);
+        self.writer.write_all(&buffer[..self.header.record_size as usize])?;
+        self.tally(record);
+        Ok(())
+    }
+
+    /// Write one record's bytes as they are, they must be exactly `record_size`
+    /// long and in the writer's `byte_order`. Counts and energy extents are kept
+    /// up to date as with `write`.
+    pub fn write_raw(&mut self, bytes: &[u8]) -> EGSResult<()> {
+        if bytes.len() as u64 != self.header.record_size {
+            return Err(EGSError::WrongRecordSize {
+                expected: self.header.record_size,
+                actual: bytes.len() as u64,
+            });
+        }
+        let record = Record::decode(bytes, self.header.using_zlast, self.byte_order);
+        self.check(&record)?;
+        self.writer.write_all(bytes)?;
+        self.tally(&record);
+        Ok(())
+    }
+
+    fn check(&self, record: &Record) -> EGSResult<()> {
+        if !self.sanitize {
+            return Ok(());
+        }
+        let fields = [("total_energy", record.total_energy),
+                      ("x_cm", record.x_cm),
+                      ("y_cm", record.y_cm),
+                      ("x_cos", record.x_cos),
+                      ("y_cos", record.y_cos),
+                      ("weight", record.weight),
+                      ("zlast", record.zlast.unwrap_or(0.0))];
+        match fields.iter().find(|&&(_, value)| !value.is_finite()) {
+            Some(&(field, _)) => Err(EGSError::NonFinite { record: self.particles, field }),
+            None => Ok(()),
+        }
+    }
+
+    fn tally(&mut self, record: &Record) {
+        self.particles += 1;
+        if record.particle_type() == ParticleType::Photon {
+            self.photons += 1;
+        }
+        if record.total_energy > 0.0 {
+            self.min_energy = self.min_energy.min(record.total_energy);
+            self.max_energy = self.max_energy.max(record.total_energy);
+        }
+    }
+}
+
+impl PHSPWriter {
+    // Flush, and for a plain file also wait until the data is on disk.
+    fn sync_all(&mut self) -> EGSResult<()> {
+        self.writer.flush()?;
+        if let Output::File(ref file) = self.writer.get_ref().0 {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Flush everything to disk, reporting the errors that dropping the writer would swallow.
+    pub fn finish(self) -> EGSResult<()> {
+        match self.into_inner()?.0 {
+            Output::File(ref mut file) => file.flush()?,
+            Output::Discard(_) => (),
+            #[cfg(feature = "gzip")]
+            Output::Gzip(ref mut encoder) => encoder.try_finish()?,
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> PHSPWriter<W> {
+    /// Rewrite the header at the start of the file with the particle and photon
+    /// counts and energy extents of the records written so far. Everything else,
+    /// like `total_particles_in_source`, comes from `self.header`.
+    pub fn finalize(mut self) -> EGSResult<()> {
+        self.sync_header()
+    }
+
+    /// Like `finalize` but keep writing after it, so the file on disk is a
+    /// complete phase space of everything written so far.
+    pub fn sync_header(&mut self) -> EGSResult<()> {
+        let mut header = self.header;
+        header.total_particles = header_count(self.particles)?;
+        header.total_photons = header_count(self.photons)?;
+        header.min_energy = self.min_energy_written().unwrap_or(0.0);
+        header.max_energy = self.max_energy_written().unwrap_or(0.0);
+        self.writer.flush()?;
+        let sink = self.writer.get_mut();
+        sink.seek(SeekFrom::Start(0))
+            .map_err(unless_unsupported("rewriting the header of a gzip file"))?;
+        sink.write_all(&header.encode(self.byte_order)[..header.record_size as usize])?;
+        sink.flush()?;
+        sink.seek(SeekFrom::End(0))?;
+        self.header = header;
+        Ok(())
+    }
+}
+
+impl PHSPWriter<Cursor<Vec<u8>>> {
+    /// A writer that builds the phase space in memory, `into_bytes` hands it back.
+    pub fn in_memory(header: &Header) -> EGSResult<PHSPWriter<Cursor<Vec<u8>>>> {
+        // the Vec is buffer enough
+        PHSPWriter::from_sink(Cursor::new(Vec::new()), header, 0, ByteOrderMode::Little)
+    }
+
+    /// `finalize` the header and return the bytes of the whole phase space, ready
+    /// for `PHSPReader::from_bytes` or writing out in one go.
+    pub fn into_bytes(mut self) -> EGSResult<Vec<u8>> {
+        self.sync_header()?;
+        Ok(self.into_inner()?.into_inner())
+    }
+}
+
+/// A `PHSPWriter` that writes to a hidden temporary file next to `path` and only
+/// renames it into place on `commit` (or `finalize`). Dropping it without
+/// committing, including on error or panic, deletes the temporary file so a
+/// failed run never leaves a partial output behind.
+pub struct AtomicWriter {
+    writer: Option<PHSPWriter>,
+    temp_path: PathBuf,
+    path: PathBuf,
+    committed: bool,
+    dry_run: bool,
+}
+
+impl AtomicWriter {
+    pub fn create(path: &Path, header: &Header) -> EGSResult<AtomicWriter> {
+        let name = path.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+        // keep the extension at the end so .gz outputs are still compressed
+        let temp_path = path.with_file_name(format!(".partial-{}", name.to_string_lossy()));
+        let writer = PHSPWriter::create(&temp_path, header)?;
+        Ok(AtomicWriter {
+            writer: Some(writer),
+            temp_path,
+            path: path.to_path_buf(),
+            committed: false,
+            dry_run: false,
+        })
+    }
+
+    /// Like `create`, or when `dry_run` is set a `PHSPWriter::discard` that
+    /// never touches `path`, committing it does nothing.
+    pub fn create_or_discard(path: &Path,
+                             header: &Header,
+                             dry_run: bool)
+                             -> EGSResult<AtomicWriter> {
+        if !dry_run {
+            return AtomicWriter::create(path, header);
+        }
+        Ok(AtomicWriter {
+            writer: Some(PHSPWriter::discard(header)?),
+            temp_path: path.to_path_buf(),
+            path: path.to_path_buf(),
+            committed: false,
+            dry_run: true,
+        })
+    }
+
+    /// Flush and move the output into place, the header is left as written.
+    pub fn commit(mut self) -> EGSResult<()> {
+        self.writer.take().unwrap().finish()?;
+        if !self.dry_run {
+            rename(&self.temp_path, &self.path)?;
+        }
+        self.committed = true;
+        Ok(())
+    }
+
+    /// `PHSPWriter::finalize` the header, then move the output into place.
+    pub fn finalize(mut self) -> EGSResult<()> {
+        self.writer.take().unwrap().finalize()?;
+        if !self.dry_run {
+            rename(&self.temp_path, &self.path)?;
+        }
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Deref for AtomicWriter {
+    type Target = PHSPWriter;
+    fn deref(&self) -> &PHSPWriter {
+        self.writer.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for AtomicWriter {
+    fn deref_mut(&mut self) -> &mut PHSPWriter {
+        self.writer.as_mut().unwrap()
+    }
+}
+
+impl Drop for AtomicWriter {
+    fn drop(&mut self) {
+        if !self.committed && !self.dry_run {
+            drop(self.writer.take());
+            let _ = remove_file(&self.temp_path);
+        }
+    }
+}
+
+// `{"key": value, ...}` from values that are already JSON.
+fn json_object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<String> = fields.iter()
+        .map(|&(key, ref value)| format!("\"{}\": {}", key, value))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+// JSON has no NaN or infinity so those become null.
+fn json_float(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+#[cfg(feature = "serde")]
+fn serialize_mode<S: serde::Serializer>(mode: &[u8; 5], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(mode))
+}
+
+// Read until `buffer` is full, the input ends or a read fails, returning how
+// much was read either way.
+fn fill<R: Read>(reader: &mut R, buffer: &mut [u8]) -> (usize, io::Result<()>) {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return (read, Err(err)),
+        }
+    }
+    (read, Ok(()))
+}
+
+// Sum of incident histories over `headers`, added up in f64 since an f32 sum
+// over many large files drifts.
+fn total_histories<'a, I>(headers: I) -> f32
+    where I: Iterator<Item = &'a Header>
+{
+    headers.map(|header| header.total_particles_in_source as f64).sum::<f64>() as f32
+}
+
+// Particle and photon counts over `headers`, added up in u64 so that only the
+// final count has to fit a header.
+fn total_particles<'a, I>(headers: I) -> (u64, u64)
+    where I: Iterator<Item = &'a Header>
+{
+    headers.fold((0, 0), |(particles, photons), header| {
+        (particles + header.total_particles.max(0) as u64,
+         photons + header.total_photons.max(0) as u64)
+    })
+}
+
+// Lowest and highest energy over `headers`, leaving out files with no records
+// whose extents mean nothing. Zero for both when every file is empty, as
+// `PHSPWriter::finalize` writes.
+fn energy_range<'a, I>(headers: I) -> (f32, f32)
+    where I: Iterator<Item = &'a Header>
+{
+    headers.filter(|header| header.total_particles > 0)
+        .fold(None, |range: Option<(f32, f32)>, header| {
+            Some(match range {
+                Some((min, max)) => (min.min(header.min_energy), max.max(header.max_energy)),
+                None => (header.min_energy, header.max_energy),
+            })
+        })
+        .unwrap_or((0.0, 0.0))
+}
+
+fn header_count(count: u64) -> EGSResult<i32> {
+    i32::try_from(count).map_err(|_| EGSError::TooManyParticles(count))
+}
+
+fn check_region(region: u32) -> EGSResult<()> {
+    if region > REGION_MASK >> REGION_SHIFT {
+        Err(EGSError::BadRegion(region))
+    } else {
+        Ok(())
+    }
+}
+
+fn record_size_for(mode: &[u8; MODE_LENGTH]) -> EGSResult<u64> {
+    if mode == b"MODE0" {
+        Ok(28)
+    } else if mode == b"MODE2" {
+        Ok(32)
+    } else {
+        Err(EGSError::BadMode)
+    }
+}
+
+impl Header {
+    /// Header for a MODE0 file (28 byte records, no zlast).
+    pub fn new_mode0(total_particles: i32,
+                     total_photons: i32,
+                     total_particles_in_source: f32)
+                     -> EGSResult<Header> {
+        Header::new(*b"MODE0", total_particles, total_photons, total_particles_in_source)
+    }
+
+    /// Header for a MODE2 file (32 byte records, with zlast).
+    pub fn new_mode2(total_particles: i32,
+                     total_photons: i32,
+                     total_particles_in_source: f32)
+                     -> EGSResult<Header> {
+        Header::new(*b"MODE2", total_particles, total_photons, total_particles_in_source)
+    }
+
+    // Energy extents start empty (min above max) so the first record
+    // folded in with min/max sets both.
+    fn new(mode: [u8; MODE_LENGTH],
+           total_particles: i32,
+           total_photons: i32,
+           total_particles_in_source: f32)
+           -> EGSResult<Header> {
+        if total_particles < 0 || total_photons < 0 || total_photons > total_particles ||
+           total_particles_in_source < 0.0 {
+            return Err(EGSError::BadHeader);
+        }
+        Ok(Header {
+            mode,
+            total_particles,
+            total_photons,
+            min_energy: f32::MAX,
+            max_energy: 0.0,
+            total_particles_in_source,
+            record_size: record_size_for(&mode)?,
+            using_zlast: &mode == b"MODE2",
+        })
+    }
+
+    /// Number of primary histories (incident particles from the source) that were
+    /// simulated to produce this file, which is what `total_particles_in_source`
+    /// actually holds. Fluence normalisation divides by this, not by `total_particles`.
+    ///
+    /// Histories that scored nothing leave no trace in the file, so this is
+    /// at least the number of first-history markers a `scan` finds.
+    pub fn incident_histories(&self) -> f32 {
+        self.total_particles_in_source
+    }
+
+    /// `incident_histories()` when the header has it. Some third party tools
+    /// leave it at zero, then this falls back to `total_particles`, as if every
+    /// history scored exactly one particle. That overestimates fluence when
+    /// histories score several particles or none, but keeps the normalisation
+    /// finite. `has_source_particles` tells the two apart.
+    pub fn effective_source_particles(&self) -> f32 {
+        if self.has_source_particles() {
+            self.total_particles_in_source
+        } else {
+            self.total_particles as f32
+        }
+    }
+
+    /// Whether the header records any incident particles from the source.
+    pub fn has_source_particles(&self) -> bool {
+        self.total_particles_in_source > 0.0
+    }
+
+    /// The header as a one line JSON object with the same keys and values as
+    /// the `serde` feature's `Serialize`, the mode as a string.
+    pub fn to_json(&self) -> String {
+        json_object(&[("mode", format!("\"{}\"", String::from_utf8_lossy(&self.mode))),
+                      ("total_particles", self.total_particles.to_string()),
+                      ("total_photons", self.total_photons.to_string()),
+                      ("min_energy", json_float(self.min_energy)),
+                      ("max_energy", json_float(self.max_energy)),
+                      ("total_particles_in_source", json_float(self.total_particles_in_source)),
+                      ("record_size", self.record_size.to_string()),
+                      ("using_zlast", self.using_zlast.to_string())])
+    }
+
+    fn decode(buffer: &[u8], order: ByteOrderMode) -> EGSResult<Header> {
+        let mut mode = [0; MODE_LENGTH];
+        mode.clone_from_slice(&buffer[0..5]);
+        Ok(Header {
+            mode,
+            total_particles: order.read_i32(&buffer[5..9]),
+            total_photons: order.read_i32(&buffer[9..13]),
+            max_energy: order.read_f32(&buffer[13..17]),
+            min_energy: order.read_f32(&buffer[17..21]),
+            total_particles_in_source: order.read_f32(&buffer[21..25]),
+            using_zlast: &mode == b"MODE2",
+            record_size: record_size_for(&mode)?,
+        })
+    }
+
+    fn encode(&self, order: ByteOrderMode) -> [u8; MAX_RECORD_LENGTH] {
+        let mut buffer = [0; MAX_RECORD_LENGTH];
+        buffer[0..5].clone_from_slice(&self.mode);
+        order.write_i32(&mut buffer[5..9], self.total_particles);
+        order.write_i32(&mut buffer[9..13], self.total_photons);
+        order.write_f32(&mut buffer[13..17], self.max_energy);
+        order.write_f32(&mut buffer[17..21], self.min_energy);
+        order.write_f32(&mut buffer[21..25], self.total_particles_in_source);
+        buffer
+    }
+
+    // Whether a file of `size` bytes holds exactly the records counted.
+    fn fits(&self, size: u64) -> bool {
+        self.total_particles >= 0 &&
+        (self.total_particles as u64 + 1).checked_mul(self.record_size) == Some(size)
+    }
+
+    /// Length in bytes of a file holding the records this header counts, the
+    /// header itself taking the first record's worth.
+    pub fn expected_file_size(&self) -> u64 {
+        (self.total_particles.max(0) as u64 + 1) * self.record_size
+    }
+    pub fn similar_to(&self, other: &Header) -> bool {
+        self.similar_to_within(other, &Tolerance::uniform(0.0))
+    }
+    /// Like `similar_to` but energies may also differ by `tolerance.energy` MeV and
+    /// incident histories by `tolerance.histories` relative to `self`'s. Floats
+    /// within a few ULPs always match.
+    pub fn similar_to_within(&self, other: &Header, tolerance: &Tolerance) -> bool {
+        let close = |a: f32, b: f32, ulps: i32, absolute: f32| {
+            a.approx_eq_ulps(&b, ulps) || (a - b).abs() <= absolute
+        };
+        self.mode == other.mode && self.total_particles == other.total_particles &&
+        self.total_photons == other.total_photons &&
+        close(self.max_energy, other.max_energy, 10, tolerance.energy) &&
+        close(self.min_energy, other.min_energy, 10, tolerance.energy) &&
+        close(self.total_particles_in_source,
+              other.total_particles_in_source,
+              2,
+              tolerance.histories * self.total_particles_in_source.abs())
+    }
+    /// Every field that differs as `(field, self's value, other's value)`, using the
+    /// same float tolerances as `similar_to`.
+    pub fn diff(&self, other: &Header) -> Vec<(String, String, String)> {
+        let mut fields = Vec::new();
+        {
+            let mut differs = |name: &str, a: String, b: String| {
+                if a != b {
+                    fields.push((name.to_string(), a, b));
+                }
+            };
+            differs("mode",
+                    String::from_utf8_lossy(&self.mode).into_owned(),
+                    String::from_utf8_lossy(&other.mode).into_owned());
+            differs("total_particles",
+                    self.total_particles.to_string(),
+                    other.total_particles.to_string());
+            differs("total_photons",
+                    self.total_photons.to_string(),
+                    other.total_photons.to_string());
+            differs("record_size", self.record_size.to_string(), other.record_size.to_string());
+            differs("using_zlast", self.using_zlast.to_string(), other.using_zlast.to_string());
+        }
+        let floats = [("max_energy", self.max_energy, other.max_energy, 10),
+                      ("min_energy", self.min_energy, other.min_energy, 10),
+                      ("total_particles_in_source",
+                       self.total_particles_in_source,
+                       other.total_particles_in_source,
+                       2)];
+        for &(name, a, b, ulps) in floats.iter() {
+            if !a.approx_eq_ulps(&b, ulps) {
+                fields.push((name.to_string(), a.to_string(), b.to_string()));
+            }
+        }
+        fields
+    }
+
+    /// Check that records from `other` can be appended to a file with this header.
+    pub fn compatible_with(&self, other: &Header) -> EGSResult<()> {
+        if self.mode != other.mode {
+            Err(EGSError::ModeMismatch)
+        } else if self.using_zlast != other.using_zlast || self.record_size != other.record_size {
+            Err(EGSError::HeaderMismatch)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The summary `info` prints.
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Total particles: {}", self.total_particles)?;
+        writeln!(f, "Total photons: {}", self.total_photons)?;
+        writeln!(f, "Total electrons/positrons: {}", self.total_particles - self.total_photons)?;
+        writeln!(f, "Maximum energy: {:.*} MeV", 4, self.max_energy)?;
+        writeln!(f, "Minimum energy: {:.*} MeV", 4, self.min_energy)?;
+        write!(f, "Incident particles from source: {:.*}", 1, self.incident_histories())
+    }
+}
+
+
+/// Records are equal when every field has the same bits, as if comparing their
+/// bytes on disk, so that they can go in a `HashSet`. A record with a NaN is
+/// equal to a copy of itself but not to one holding a NaN with other bits, and
+/// `0.0` and `-0.0` differ, which for the weight is a different z direction. Use
+/// `similar_to_within` to compare values.
+impl PartialEq for Record {
+    fn eq(&self, other: &Record) -> bool {
+        self.bits() == other.bits()
+    }
+}
+
+impl Eq for Record {}
+
+impl Hash for Record {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bits().hash(state);
+    }
+}
+
+impl Record {
+    /// A MODE0 record from values as they are stored: a negative `total_energy`
+    /// marks the first particle scored by a primary history and a negative
+    /// `weight` a particle heading towards negative z.
+    pub fn new(latch: u32,
+               total_energy: f32,
+               x_cm: f32,
+               y_cm: f32,
+               x_cos: f32,
+               y_cos: f32,
+               weight: f32)
+               -> Record {
+        Record {
+            latch,
+            total_energy,
+            x_cm,
+            y_cm,
+            x_cos,
+            y_cos,
+            weight,
+            zlast: None,
+        }
+    }
+    pub fn similar_to(&self, other: &Record) -> bool {
+        self.latch == other.latch && self.total_energy() - other.total_energy() < 0.01 &&
+        self.x_cm - other.x_cm < 0.01 && self.y_cm - other.y_cm < 0.01 &&
+        self.x_cos - other.x_cos < 0.01 && self.y_cos - other.y_cos < 0.01 &&
+        self.weight - other.weight < 0.01 && self.zlast == other.zlast
+    }
+    // Every field as the bits it is stored with, for `==` and hashing.
+    fn bits(&self) -> (u32, [u32; 6], Option<u32>) {
+        (self.latch,
+         [self.total_energy.to_bits(),
+          self.x_cm.to_bits(),
+          self.y_cm.to_bits(),
+          self.x_cos.to_bits(),
+          self.y_cos.to_bits(),
+          self.weight.to_bits()],
+         self.zlast.map(f32::to_bits))
+    }
+    /// Whether the latch and zlast match and every other field is within its
+    /// tolerance, inclusive like `Header::similar_to_within`.
+    pub fn similar_to_within(&self, other: &Record, tolerance: &Tolerance) -> bool {
+        self.latch == other.latch &&
+        (self.total_energy() - other.total_energy()).abs() <= tolerance.energy &&
+        (self.x_cm - other.x_cm).abs() <= tolerance.position &&
+        (self.y_cm - other.y_cm).abs() <= tolerance.position &&
+        (self.x_cos - other.x_cos).abs() <= tolerance.direction &&
+        (self.y_cos - other.y_cos).abs() <= tolerance.direction &&
+        (self.weight - other.weight).abs() <= tolerance.weight && self.zlast == other.zlast
+    }
+    pub fn bremsstrahlung_or_annihilation(&self) -> bool {
+        bremsstrahlung_or_annihilation_of(self.latch)
+    }
+    pub fn bit_region(&self) -> u32 {
+        self.latch & 0xfffffe
+    }
+    pub fn region_number(&self) -> u32 {
+        self.latch & 0xf000000
+    }
+    pub fn b29(&self) -> bool {
+        self.latch & (1 << 29) != 0
+    }
+    pub fn charged(&self) -> bool {
+        self.latch & (1 << 30) != 0
+    }
+    pub fn particle_type(&self) -> ParticleType {
+        particle_type_of(self.latch)
+    }
+    pub fn crossed_multiple(&self) -> bool {
+        self.latch & (1 << 31) != 0
+    }
+    /// Region the particle was created in, bits 24 to 28 of the latch.
+    pub fn region_index(&self) -> u32 {
+        region_index_of(self.latch)
+    }
+    /// Replace the region the particle was created in, leaving every other latch bit alone.
+    pub fn set_region(&mut self, region: u32) -> EGSResult<()> {
+        check_region(region)?;
+        self.latch = (self.latch & !REGION_MASK) | (region << REGION_SHIFT);
+        Ok(())
+    }
+    pub fn set_charged(&mut self, charged: bool) {
+        self.set_latch_bit(30, charged);
+    }
+    pub fn set_bremsstrahlung_or_annihilation(&mut self, produced: bool) {
+        self.set_latch_bit(0, produced);
+    }
+    pub fn set_crossed_multiple(&mut self, crossed: bool) {
+        self.set_latch_bit(31, crossed);
+    }
+    fn set_latch_bit(&mut self, bit: u32, value: bool) {
+        if value {
+            self.latch |= 1 << bit;
+        } else {
+            self.latch &= !(1 << bit);
+        }
+    }
+    pub fn get_weight(&self) -> f32 {
+        self.weight.abs()
+    }
+    pub fn set_weight(&mut self, new_weight: f32) {
+        self.weight = new_weight * self.weight.signum();
+    }
+    pub fn total_energy(&self) -> f32 {
+        self.total_energy.abs()
+    }
+    /// The energy field as stored. Its magnitude is `total_energy()` in MeV and
+    /// it is negative for the first particle scored from each history, the mark
+    /// `first_scored_by_primary_history` reads. Passing it back to `Record::new`
+    /// keeps the mark.
+    pub fn raw_energy(&self) -> f32 {
+        self.total_energy
+    }
+    /// Keeps the sign marking the first particle of a history.
+    pub fn set_total_energy(&mut self, new_energy: f32) {
+        self.total_energy = new_energy * self.total_energy.signum();
+    }
+    pub fn z_positive(&self) -> bool {
+        self.weight.is_sign_positive()
+    }
+    /// 1.0 heading towards positive z, -1.0 towards negative z. The file keeps
+    /// this as the sign of the weight.
+    pub fn z_direction(&self) -> f32 {
+        if self.z_positive() { 1.0 } else { -1.0 }
+    }
+    /// Point the particle towards positive or negative z, `get_weight()` is kept.
+    pub fn set_z_direction(&mut self, positive: bool) {
+        self.weight = if positive { self.weight.abs() } else { -self.weight.abs() };
+    }
+    /// False for a zero, NaN or infinite weight. A zero weight still has a sign
+    /// for `z_positive` to read, but it is as likely to be a producer's bug as
+    /// a real direction.
+    pub fn has_valid_weight(&self) -> bool {
+        self.weight.is_finite() && self.weight != 0.0
+    }
+    pub fn z_cos(&self) -> f32 {
+        (1.0 - (self.x_cos * self.x_cos + self.y_cos * self.y_cos)).sqrt()
+    }
+    /// Kinetic energy in MeV, the stored total energy less the rest mass for
+    /// electrons and positrons. A charged particle stored with less than its rest
+    /// mass (see `below_rest_mass`) gets 0.
+    pub fn kinetic_energy(&self) -> f32 {
+        match self.particle_type() {
+            ParticleType::Photon => self.total_energy(),
+            _ => (self.total_energy() - ELECTRON_REST_MASS).max(0.0),
+        }
+    }
+    /// Whether this is an electron or positron whose stored total energy is less
+    /// than its rest mass, which no valid record has.
+    pub fn below_rest_mass(&self) -> bool {
+        self.particle_type() != ParticleType::Photon && self.total_energy() < ELECTRON_REST_MASS
+    }
+    pub fn first_scored_by_primary_history(&self) -> bool {
+        self.total_energy.is_sign_negative()
+    }
+
+    // Move the polar angle by `delta` radians keeping the azimuth. Overshooting
+    // the axis continues on the opposite azimuth, tipping past the scoring
+    // plane reverses the z direction.
+    fn tilt(&mut self, delta: f32) {
+        let mut phi = self.y_cos.atan2(self.x_cos);
+        let mut theta = (self.z_cos().acos() + delta).rem_euclid(2.0 * PI);
+        if theta > PI {
+            theta = 2.0 * PI - theta;
+            phi += PI;
+        }
+        if theta > PI / 2.0 {
+            theta = PI - theta;
+            let positive = self.z_positive();
+            self.set_z_direction(!positive);
+        }
+        self.x_cos = theta.sin() * phi.cos();
+        self.y_cos = theta.sin() * phi.sin();
+    }
+
+    // The latch of an undecoded record, for filters that need nothing else.
+    fn latch_only(buffer: &[u8], order: ByteOrderMode) -> u32 {
+        order.read_u32(&buffer[0..4])
+    }
+
+    fn decode(buffer: &[u8], using_zlast: bool, order: ByteOrderMode) -> Record {
+        Record {
+            latch: order.read_u32(&buffer[0..4]),
+            total_energy: order.read_f32(&buffer[4..8]),
+            x_cm: order.read_f32(&buffer[8..12]),
+            y_cm: order.read_f32(&buffer[12..16]),
+            x_cos: order.read_f32(&buffer[16..20]),
+            y_cos: order.read_f32(&buffer[20..24]),
+            weight: order.read_f32(&buffer[24..28]),
+            zlast: if using_zlast {
+                Some(order.read_f32(&buffer[28..32]))
+            } else {
+                None
+            },
+        }
+    }
+
+    // On-disk bytes, zero padded past the record size.
+    fn encode(&self, using_zlast: bool, order: ByteOrderMode) -> [u8; MAX_RECORD_LENGTH] {
+        let mut buffer = [0; MAX_RECORD_LENGTH];
+        order.write_u32(&mut buffer[0..4], self.latch);
+        order.write_f32(&mut buffer[4..8], self.total_energy);
+        order.write_f32(&mut buffer[8..12], self.x_cm);
+        order.write_f32(&mut buffer[12..16], self.y_cm);
+        order.write_f32(&mut buffer[16..20], self.x_cos);
+        order.write_f32(&mut buffer[20..24], self.y_cos);
+        order.write_f32(&mut buffer[24..28], self.weight);
+        if using_zlast {
+            order.write_f32(&mut buffer[28..32], self.zlast.unwrap_or(0.0));
+        }
+        buffer
+    }
+
+    /// Every decoded field and latch flag on its own labelled line.
+    pub fn describe(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("latch:            {:#010x}\n", self.latch));
+        out.push_str(&format!("  particle:       {:?}\n", self.particle_type()));
+        out.push_str(&format!("  region:         {}\n", self.region_index()));
+        out.push_str(&format!("  brems/annih:    {}\n", self.bremsstrahlung_or_annihilation()));
+        out.push_str(&format!("  multiple cross: {}\n", self.crossed_multiple()));
+        out.push_str(&format!("primary history:  {}\n", self.first_scored_by_primary_history()));
+        out.push_str(&format!("energy:           {} MeV\n", self.total_energy()));
+        out.push_str(&format!("x:                {} cm\n", self.x_cm));
+        out.push_str(&format!("y:                {} cm\n", self.y_cm));
+        out.push_str(&format!("x_cos:            {}\n", self.x_cos));
+        out.push_str(&format!("y_cos:            {}\n", self.y_cos));
+        out.push_str(&format!("z_cos:            {}{}\n",
+                              if self.z_positive() { "" } else { "-" },
+                              self.z_cos()));
+        out.push_str(&format!("weight:           {}\n", self.get_weight()));
+        match self.zlast {
+            Some(zlast) => out.push_str(&format!("zlast:            {} cm\n", zlast)),
+            None => out.push_str("zlast:            -\n"),
+        }
+        out
+    }
+
+    /// The latch bit by bit, each field on its own line with the bits it comes
+    /// from, and the z direction carried by the sign of the weight.
+    pub fn describe_latch(&self) -> String {
+        let bits: Vec<String> = (0..8)
+            .rev()
+            .map(|nibble| format!("{:04b}", (self.latch >> (nibble * 4)) & 0xf))
+            .collect();
+        let mut out = String::new();
+        out.push_str(&format!("latch:          {:#010x} ({})\n", self.latch, bits.join(" ")));
+        out.push_str(&format!("  0      brems/annih:    {}\n", self.bremsstrahlung_or_annihilation()));
+        let visited: Vec<String> = (1..24)
+            .filter(|bit| self.bit_region() & (1 << bit) != 0)
+            .map(|bit: u32| bit.to_string())
+            .collect();
+        let visited = if visited.is_empty() { "none".to_string() } else { visited.join(", ") };
+        out.push_str(&format!("  1-23   bits set:       {}\n", visited));
+        out.push_str(&format!("  24-28  region:         {}\n", self.region_index()));
+        out.push_str(&format!("  29     bit 29:         {}\n", self.b29()));
+        out.push_str(&format!("  30     charged:        {}\n", self.charged()));
+        out.push_str(&format!("  31     multiple cross: {}\n", self.crossed_multiple()));
+        out.push_str(&format!("particle:       {:?}\n", self.particle_type()));
+        out.push_str(&format!("z direction:    {}\n", if self.z_positive() { "+z" } else { "-z" }));
+        out
+    }
+
+    /// The stored fields as a one line JSON object, like the `serde` feature's
+    /// `Serialize`: `total_energy` and `weight` keep their signs and `zlast` is
+    /// null for MODE0.
+    pub fn to_json(&self) -> String {
+        json_object(&[("latch", self.latch.to_string()),
+                      ("total_energy", json_float(self.total_energy)),
+                      ("x_cm", json_float(self.x_cm)),
+                      ("y_cm", json_float(self.y_cm)),
+                      ("x_cos", json_float(self.x_cos)),
+                      ("y_cos", json_float(self.y_cos)),
+                      ("weight", json_float(self.weight)),
+                      ("zlast", self.zlast.map_or("null".to_string(), json_float))])
+    }
+
+    pub fn radius(&self) -> f32 {
+        (self.x_cm * self.x_cm + self.y_cm * self.y_cm).sqrt()
+    }
+
+    fn transform(&mut self, matrix: &[[f32; 3]; 3]) {
+        let x_cm = self.x_cm;
+        let y_cm = self.y_cm;
+        self.x_cm = matrix[0][0] * x_cm + matrix[0][1] * y_cm + matrix[0][2] * 1.0;
+        self.y_cm = matrix[1][0] * x_cm + matrix[1][1] * y_cm + matrix[1][2] * 1.0;
+        // directions only see the linear part, translations don't apply to them
+        let z_cos = self.z_cos();
+        let mut x_cos = matrix[0][0] * self.x_cos + matrix[0][1] * self.y_cos;
+        let mut y_cos = matrix[1][0] * self.x_cos + matrix[1][1] * self.y_cos;
+        // a scale stretches the direction vector, bring it back to unit length,
+        // rotations and reflections are left exactly as they come out
+        let (a, b, c, d) = (matrix[0][0], matrix[0][1], matrix[1][0], matrix[1][1]);
+        let orthogonal = (a * a + c * c - 1.0).abs() <= 1e-6 &&
+                         (b * b + d * d - 1.0).abs() <= 1e-6 &&
+                         (a * b + c * d).abs() <= 1e-6;
+        if !orthogonal {
+            let norm = (x_cos * x_cos + y_cos * y_cos + z_cos * z_cos).sqrt();
+            x_cos /= norm;
+            y_cos /= norm;
+        }
+        self.x_cos = x_cos;
+        self.y_cos = y_cos;
+    }
+}
+
+impl Transform {
+    pub fn rotation(matrix: &mut [[f32; 3]; 3], theta: f32) {
+        *matrix =
+            [[theta.cos(), -theta.sin(), 0.0], [theta.sin(), theta.cos(), 0.0], [0.0, 0.0, 1.0]];
+    }
+    /// Rotate by `theta` about the point `(cx, cy)` instead of the origin.
+    pub fn rotation_about(matrix: &mut [[f32; 3]; 3], theta: f32, cx: f32, cy: f32) {
+        let mut step = [[0.0; 3]; 3];
+        Transform::translation(matrix, -cx, -cy);
+        Transform::rotation(&mut step, theta);
+        Transform::compose(matrix, &step);
+        Transform::translation(&mut step, cx, cy);
+        Transform::compose(matrix, &step);
+    }
+    pub fn translation(matrix: &mut [[f32; 3]; 3], x: f32, y: f32) {
+        *matrix = [[1.0, 0.0, x], [0.0, 1.0, y], [0.0, 0.0, 1.0]];
+    }
+    pub fn scale(matrix: &mut [[f32; 3]; 3], x: f32, y: f32) {
+        *matrix = [[x, 0.0, 0.0], [0.0, y, 0.0], [0.0, 0.0, 1.0]];
+    }
+    pub fn identity(matrix: &mut [[f32; 3]; 3]) {
+        *matrix = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    }
+
+    /// Compose `next` onto `matrix` so that the result applies `matrix` first, then `next`.
+    pub fn compose(matrix: &mut [[f32; 3]; 3], next: &[[f32; 3]; 3]) {
+        let current = *matrix;
+        for i in 0..3 {
+            for j in 0..3 {
+                matrix[i][j] = (0..3).map(|k| next[i][k] * current[k][j]).sum();
+            }
+        }
+    }
+
+    /// Parse a comma separated chain of operations such as
+    /// `rotate:1.57,translate:2,0,scale:1,-1` into a single matrix, applied left to right.
+    ///
+    /// Supported operations are `rotate:theta` (radians), `translate:x,y` (cm) and
+    /// `scale:x,y`.
+    pub fn parse(matrix: &mut [[f32; 3]; 3], ops: &str) -> EGSResult<()> {
+        let mut chain: Vec<(&str, Vec<f32>)> = Vec::new();
+        for token in ops.split(',').map(|t| t.trim()) {
+            let value = match token.find(':') {
+                Some(i) => {
+                    chain.push((&token[..i], Vec::new()));
+                    &token[i + 1..]
+                }
+                None => token,
+            };
+            let args = match chain.last_mut() {
+                Some(&mut (_, ref mut args)) => args,
+                None => return Err(EGSError::BadTransform(token.to_string())),
+            };
+            match value.trim().parse::<f32>() {
+                Ok(v) => args.push(v),
+                Err(_) => return Err(EGSError::BadTransform(token.to_string())),
+            }
+        }
+        Transform::identity(matrix);
+        let mut step = [[0.0; 3]; 3];
+        for &(name, ref args) in chain.iter() {
+            match (name, args.len()) {
+                ("rotate", 1) => Transform::rotation(&mut step, args[0]),
+                ("translate", 2) => Transform::translation(&mut step, args[0], args[1]),
+                ("scale", 2) => Transform::scale(&mut step, args[0], args[1]),
+                _ => return Err(EGSError::BadTransform(format!("{}:{:?}", name, args))),
+            }
+            Transform::compose(matrix, &step);
+        }
+        Ok(())
+    }
+}
+
+
+
+// Resolve symlinks and relative components, for an output that may not
+// exist yet resolve its directory instead.
+fn canonical_path(path: &Path) -> io::Result<PathBuf> {
+    if let Ok(resolved) = canonicalize(path) {
+        return Ok(resolved);
+    }
+    let name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    Ok(canonicalize(parent)?.join(name))
+}
+
+fn check_output_not_input(input_paths: &[&Path], output_path: &Path) -> EGSResult<()> {
+    let output = canonical_path(output_path)?;
+    for path in input_paths.iter() {
+        if canonical_path(path)? == output {
+            return Err(EGSError::OutputIsInput(output_path.to_path_buf()));
+        }
+    }
+    Ok(())
+}
+
+/// Totals gathered by reading every record of a file.
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Scan {
+    pub records: u64,
+    /// Records flagged `first_scored_by_primary_history`, one per history that scored.
+    pub first_histories: u64,
+    /// Records with `bremsstrahlung_or_annihilation()` set.
+    pub produced: u64,
+    /// Records with `b29()` set.
+    pub b29: u64,
+}
+
+impl Scan {
+    /// The totals as a one line JSON object, like the `serde` feature's `Serialize`.
+    pub fn to_json(&self) -> String {
+        json_object(&[("records", self.records.to_string()),
+                      ("first_histories", self.first_histories.to_string()),
+                      ("produced", self.produced.to_string()),
+                      ("b29", self.b29.to_string())])
+    }
+}
+
+pub fn scan(input_path: &Path) -> EGSResult<Scan> {
+    let mut scan = Scan::default();
+    for record in PHSPReader::open(input_path)? {
+        let record = record?;
+        scan.records += 1;
+        if record.first_scored_by_primary_history() {
+            scan.first_histories += 1;
+        }
+        if record.bremsstrahlung_or_annihilation() {
+            scan.produced += 1;
+        }
+        if record.b29() {
+            scan.b29 += 1;
+        }
+    }
+    Ok(scan)
+}
+
+/// Particle and photon counts found by reading a file to its end, next to the
+/// ones its header claims.
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RecordCount {
+    pub header_particles: u64,
+    pub header_photons: u64,
+    pub particles: u64,
+    pub photons: u64,
+}
+
+impl RecordCount {
+    pub fn particles_match(&self) -> bool {
+        self.header_particles == self.particles
+    }
+    pub fn photons_match(&self) -> bool {
+        self.header_photons == self.photons
+    }
+    /// The counts as a one line JSON object, like the `serde` feature's `Serialize`.
+    pub fn to_json(&self) -> String {
+        json_object(&[("header_particles", self.header_particles.to_string()),
+                      ("header_photons", self.header_photons.to_string()),
+                      ("particles", self.particles.to_string()),
+                      ("photons", self.photons.to_string())])
+    }
+}
+
+/// Count the records of `input` by reading until the data runs out rather than
+/// stopping where the header says, so a header that overcounts or undercounts
+/// shows up as a difference from what it claims instead of a read error.
+pub fn count_records(input_path: &Path) -> EGSResult<RecordCount> {
+    let mut reader = PHSPReader::open(input_path)?;
+    reader.until_eof = true;
+    let mut count = RecordCount {
+        header_particles: reader.header.total_particles.max(0) as u64,
+        header_photons: reader.header.total_photons.max(0) as u64,
+        ..RecordCount::default()
+    };
+    for record in reader {
+        count.particles += 1;
+        if record?.particle_type() == ParticleType::Photon {
+            count.photons += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// C declarations of the header and record layout used by files with this
+/// header's mode, with the byte offset of every field.
+pub fn c_layout(header: &Header) -> String {
+    let mode = String::from_utf8_lossy(&header.mode).to_lowercase();
+    let record_size = header.record_size as usize;
+    let mut out = String::new();
+    out.push_str(&format!("/* EGSnrc {} phase space, little endian, {} byte records */\n",
+                          String::from_utf8_lossy(&header.mode),
+                          record_size));
+    out.push_str("#include <stdint.h>\n\n#pragma pack(push, 1)\n");
+    out.push_str("typedef struct {\n");
+    for &(name, kind, offset, length) in HEADER_FIELDS.iter() {
+        let field = if length > 1 { format!("{}[{}]", name, length) } else { name.to_string() };
+        out.push_str(&format!("    {} {}; /* offset {} */\n", kind, field, offset));
+    }
+    out.push_str(&format!("    char padding[{}]; /* offset {} */\n",
+                          record_size - HEADER_LENGTH,
+                          HEADER_LENGTH));
+    out.push_str(&format!("}} egsphsp_{}_header;\n\n", mode));
+    out.push_str("typedef struct {\n");
+    for (i, &(name, kind)) in RECORD_FIELDS.iter().take(record_size / FIELD_LENGTH).enumerate() {
+        out.push_str(&format!("    {} {}; /* offset {} */\n", kind, name, i * FIELD_LENGTH));
+    }
+    out.push_str(&format!("}} egsphsp_{}_record;\n#pragma pack(pop)\n", mode));
+    out
+}
+
+/// How two files compare, from `diff`.
+#[derive(Debug, Clone, Default)]
+pub struct DiffReport {
+    /// Header fields that differ as `(field, a's value, b's value)`, see `Header::diff`.
+    pub header_differences: Vec<(String, String, String)>,
+    pub records_a: u64,
+    pub records_b: u64,
+    /// Index of the first record pair that isn't `similar_to_within` the default
+    /// `Tolerance`.
+    pub first_difference: Option<u64>,
+    /// Record pairs that differ that way, records past the end of the shorter
+    /// file aren't counted.
+    pub differing: u64,
+}
+
+impl DiffReport {
+    /// `HeaderMismatch` if the headers differ, otherwise `RecordMismatch` if any
+    /// record or the record counts do, otherwise nothing.
+    pub fn mismatch(&self) -> Option<EGSError> {
+        if !self.header_differences.is_empty() {
+            Some(EGSError::HeaderMismatch)
+        } else if self.differing > 0 || self.records_a != self.records_b {
+            Some(EGSError::RecordMismatch)
+        } else {
+            None
+        }
+    }
+}
+
+/// Compare the headers of `a` and `b` and walk their records in lockstep.
+pub fn diff(a: &Path, b: &Path) -> EGSResult<DiffReport> {
+    let mut reader_a = PHSPReader::open(a)?;
+    let mut reader_b = PHSPReader::open(b)?;
+    let mut report = DiffReport {
+        header_differences: reader_a.header.diff(&reader_b.header),
+        ..DiffReport::default()
+    };
+    loop {
+        match (reader_a.next(), reader_b.next()) {
+            (Some(record_a), Some(record_b)) => {
+                let (record_a, record_b) = (record_a?, record_b?);
+                if !record_a.similar_to_within(&record_b, &Tolerance::default()) {
+                    report.first_difference = report.first_difference.or(Some(report.records_a));
+                    report.differing += 1;
+                }
+                report.records_a += 1;
+                report.records_b += 1;
+            }
+            (Some(record_a), None) => {
+                record_a?;
+                report.records_a += 1;
+            }
+            (None, Some(record_b)) => {
+                record_b?;
+                report.records_b += 1;
+            }
+            (None, None) => return Ok(report),
+        }
+    }
+}
+
+/// Problems found by `validate`, each count is zero for a well formed file.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ValidationReport {
+    pub expected_size: u64,
+    pub actual_size: u64,
+    /// Records with a NaN or infinite energy, position, direction or weight.
+    pub non_finite: u64,
+    /// Records whose direction cosines satisfy `x_cos^2 + y_cos^2 > 1`, past the
+    /// same rounding slack `PHSPReader::validated` allows.
+    pub bad_cosines: u64,
+    pub header_photons: u64,
+    pub photons: u64,
+}
+
+impl ValidationReport {
+    pub fn size_matches(&self) -> bool {
+        self.expected_size == self.actual_size
+    }
+    pub fn photons_match(&self) -> bool {
+        self.header_photons == self.photons
+    }
+    pub fn is_valid(&self) -> bool {
+        self.size_matches() && self.photons_match() && self.non_finite == 0 &&
+        self.bad_cosines == 0
+    }
+}
+
+/// Read every record of `input` and check it against its header.
+pub fn validate(input: &Path) -> EGSResult<ValidationReport> {
+    let reader = PHSPReader::open(input)?;
+    let header = reader.header;
+    let mut report = ValidationReport {
+        expected_size: header.expected_file_size(),
+        header_photons: header.total_photons.max(0) as u64,
+        ..ValidationReport::default()
+    };
+    let mut records = 0;
+    for record in reader {
+        let record = record?;
+        records += 1;
+        let floats = [record.total_energy, record.x_cm, record.y_cm, record.x_cos,
+                      record.y_cos, record.weight, record.zlast.unwrap_or(0.0)];
+        if floats.iter().any(|f| !f.is_finite()) {
+            report.non_finite += 1;
+        }
+        if record.x_cos * record.x_cos + record.y_cos * record.y_cos > 1.0 + COSINE_TOLERANCE {
+            report.bad_cosines += 1;
+        }
+        if record.particle_type() == ParticleType::Photon {
+            report.photons += 1;
+        }
+    }
+    // compressed files only tell us their size once decompressed
+    report.actual_size = if is_gzip(input) {
+        (records + 1) * header.record_size
+    } else {
+        input.metadata()?.len()
+    };
+    Ok(report)
+}
+
+/// What `audit_weights` found, `zero` and `non_finite` are zero for a well formed file.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct WeightAudit {
+    pub records: u64,
+    /// Records with a weight of 0.0 or -0.0.
+    pub zero: u64,
+    pub non_finite: u64,
+    /// Records with a negative weight, heading towards negative z. A file with
+    /// far more of these than expected may come from a producer that wrote
+    /// negative weights by mistake.
+    pub negative_z: u64,
+}
+
+/// Count the records of `input` whose weight `has_valid_weight` rejects.
+pub fn audit_weights(input: &Path) -> EGSResult<WeightAudit> {
+    let mut audit = WeightAudit::default();
+    for record in PHSPReader::open(input)? {
+        let record = record?;
+        audit.records += 1;
+        if record.weight == 0.0 {
+            audit.zero += 1;
+        } else if !record.weight.is_finite() {
+            audit.non_finite += 1;
+        }
+        if !record.z_positive() {
+            audit.negative_z += 1;
+        }
+    }
+    Ok(audit)
+}
+
+/// Histogram the total energy of every record into `bins` equal bins over
+/// `[min, max]`, returning the summed weight and the number of records in each
+/// bin. Records outside the range are left out. Zero bins, or a range that is
+/// empty or not finite, fail with `InvalidArgument`.
+pub fn energy_spectrum_weighted(input: &Path,
+                                bins: usize,
+                                min: f32,
+                                max: f32)
+                                -> EGSResult<(Vec<f64>, Vec<u64>)> {
+    if bins == 0 {
+        return Err(EGSError::InvalidArgument("cannot histogram into zero bins"));
+    } else if max <= min || !min.is_finite() || !max.is_finite() {
+        return Err(EGSError::InvalidArgument("the histogram range is empty"));
+    }
+    let mut weights = vec![0.0f64; bins];
+    let mut counts = vec![0u64; bins];
+    let width = (max - min) as f64 / bins as f64;
+    for record in PHSPReader::open(input)? {
+        let record = record?;
+        let energy = record.total_energy();
+        if energy < min || energy > max {
+            continue;
+        }
+        // the top edge belongs to the last bin
+        let bin = (((energy - min) as f64 / width) as usize).min(bins - 1);
+        weights[bin] += record.get_weight() as f64;
+        counts[bin] += 1;
+    }
+    Ok((weights, counts))
+}
+
+/// Sum the weight of every record into an `nx` by `ny` grid of equal cells
+/// over `[-extent, extent]` cm in both x and y, `grid[i][j]` holding x bin `i`
+/// and y bin `j`. Records outside the square are dropped with
+/// `ClampMode::Discard` or added to the nearest edge cell with
+/// `ClampMode::Clamp`, records with a NaN position are always dropped. An `nx`
+/// or `ny` of zero, or an `extent` that isn't positive and finite, fails with
+/// `InvalidArgument`.
+pub fn fluence_map(input: &Path,
+                   nx: usize,
+                   ny: usize,
+                   extent: f32,
+                   mode: ClampMode)
+                   -> EGSResult<Vec<Vec<f64>>> {
+    if nx == 0 || ny == 0 {
+        return Err(EGSError::InvalidArgument("cannot histogram into zero bins"));
+    } else if !extent.is_finite() || extent <= 0.0 {
+        return Err(EGSError::InvalidArgument("the fluence map extent must be positive"));
+    }
+    let mut grid = vec![vec![0.0f64; ny]; nx];
+    // the far edge belongs to the last bin, like energy_spectrum_weighted
+    let bin = |position: f32, bins: usize| -> Option<usize> {
+        let bin = ((position + extent) as f64 / (2.0 * extent as f64) * bins as f64).floor();
+        if bin.is_nan() {
+            None
+        } else if position == extent {
+            Some(bins - 1)
+        } else if bin >= 0.0 && bin < bins as f64 {
+            Some(bin as usize)
+        } else if mode == ClampMode::Clamp {
+            Some(if bin < 0.0 { 0 } else { bins - 1 })
+        } else {
+            None
+        }
+    };
+    for record in PHSPReader::open(input)? {
+        let record = record?;
+        if let (Some(i), Some(j)) = (bin(record.x_cm, nx), bin(record.y_cm, ny)) {
+            grid[i][j] += record.get_weight() as f64;
+        }
+    }
+    Ok(grid)
+}
+
+/// Lowest and highest total energy of each particle type in `input`, as
+/// `(min, max)` in MeV. Like the header extents only records with a positive
+/// stored energy count, so a type missing from the map had none of those.
+pub fn energy_extents_by_type(input: &Path) -> EGSResult<HashMap<ParticleType, (f32, f32)>> {
+    let mut extents: HashMap<ParticleType, (f32, f32)> = HashMap::new();
+    for record in PHSPReader::open(input)? {
+        let record = record?;
+        let energy = record.raw_energy();
+        if energy > 0.0 {
+            let extent = extents.entry(record.particle_type()).or_insert((energy, energy));
+            extent.0 = extent.0.min(energy);
+            extent.1 = extent.1.max(energy);
+        }
+    }
+    Ok(extents)
+}
+
+/// Mean total energy and particle count in `bins` equal rings out to
+/// `max_radius` cm, as `(outer radius, mean energy, count)`. A last overflow
+/// bin with an infinite outer radius holds everything further out. Empty bins
+/// have a mean energy of zero. Zero `bins`, or a `max_radius` that isn't
+/// positive and finite, fails with `InvalidArgument`.
+pub fn radial_energy_profile(input: &Path,
+                             bins: usize,
+                             max_radius: f32)
+                             -> EGSResult<Vec<(f32, f32, u64)>> {
+    if bins == 0 {
+        return Err(EGSError::InvalidArgument("cannot histogram into zero bins"));
+    } else if !max_radius.is_finite() || max_radius <= 0.0 {
+        return Err(EGSError::InvalidArgument("the profile radius must be positive"));
+    }
+    let mut energies = vec![0.0f64; bins + 1];
+    let mut counts = vec![0u64; bins + 1];
+    for record in PHSPReader::open(input)? {
+        let record = record?;
+        let radius = record.radius();
+        let bin = if radius >= max_radius {
+            bins
+        } else {
+            ((radius / max_radius * bins as f32) as usize).min(bins - 1)
+        };
+        energies[bin] += record.total_energy() as f64;
+        counts[bin] += 1;
+    }
+    Ok((0..bins + 1)
+        .map(|i| {
+            let outer = if i == bins {
+                f32::INFINITY
+            } else {
+                max_radius * (i + 1) as f32 / bins as f32
+            };
+            let mean = if counts[i] > 0 { energies[i] / counts[i] as f64 } else { 0.0 };
+            (outer, mean as f32, counts[i])
+        })
+        .collect())
+}
+
+/// Polar angles found by `angular_histogram`.
+#[derive(Debug, Clone)]
+pub struct AngularHistogram {
+    /// Summed `get_weight()` in each bin.
+    pub weights: Vec<f64>,
+    /// Number of records in each bin.
+    pub counts: Vec<u64>,
+    /// Records left out because their direction cosines give a NaN `z_cos()`.
+    pub invalid: u64,
+}
+
+/// Histogram the polar angle `acos(z_cos())` of every record into `bins` equal
+/// bins over `[0, PI / 2]` radians. The angle is measured from the z axis on
+/// whichever side the particle heads, so backward particles fold onto forward
+/// ones. Records whose `x_cos` and `y_cos` leave no room for a z component
+/// are counted in `invalid` rather than binned. Zero `bins` fails with
+/// `InvalidArgument`.
+pub fn angular_histogram(input: &Path, bins: usize) -> EGSResult<AngularHistogram> {
+    if bins == 0 {
+        return Err(EGSError::InvalidArgument("cannot histogram into zero bins"));
+    }
+    let mut histogram = AngularHistogram {
+        weights: vec![0.0; bins],
+        counts: vec![0; bins],
+        invalid: 0,
+    };
+    for record in PHSPReader::open(input)? {
+        let record = record?;
+        let z_cos = record.z_cos();
+        if z_cos.is_nan() {
+            histogram.invalid += 1;
+            continue;
+        }
+        // straight along the plane belongs to the last bin
+        let bin = ((z_cos.acos() / (PI / 2.0) * bins as f32) as usize).min(bins - 1);
+        histogram.weights[bin] += record.get_weight() as f64;
+        histogram.counts[bin] += 1;
+    }
+    Ok(histogram)
+}
+
+/// A local maximum of the weighted energy spectrum found by `peaks`.
+#[derive(Debug, Copy, Clone)]
+pub struct Peak {
+    /// Centre of the peak's bin in MeV.
+    pub energy: f32,
+    /// Total weight in the bin.
+    pub height: f64,
+    /// Height above the higher of the lowest points separating the peak from
+    /// taller bins on either side, as a fraction of the tallest bin.
+    pub prominence: f64,
+}
+
+// Most bins `peaks` will allocate, 128 MiB of them.
+const MAX_PEAK_BINS: f64 = (1 << 24) as f64;
+
+/// Histogram the total energy of every record in `bin_width` MeV bins,
+/// weighting by `get_weight()`, and return the local maxima whose prominence
+/// is at least `min_prominence` (a fraction of the tallest bin), lowest
+/// energy first.
+///
+/// The bins run from 0 to the header's maximum energy, records above it or
+/// with an energy that isn't finite are left out. A bin width that isn't
+/// positive, or too small to cover that range in 2^24 bins, fails with
+/// `InvalidArgument`.
+pub fn peaks(input: &Path, bin_width: f32, min_prominence: f64) -> EGSResult<Vec<Peak>> {
+    if !bin_width.is_finite() || bin_width <= 0.0 {
+        return Err(EGSError::InvalidArgument("the bin width must be positive"));
+    }
+    let reader = PHSPReader::open(input)?;
+    // `max` drops a NaN maximum, an infinite one fails below
+    let count = (reader.header.max_energy.max(0.0) as f64 / bin_width as f64).floor() + 1.0;
+    if count > MAX_PEAK_BINS {
+        return Err(EGSError::InvalidArgument("the bin width is too small for the energy range"));
+    }
+    let mut bins = vec![0.0f64; count as usize];
+    for record in reader {
+        let record = record?;
+        let energy = record.total_energy();
+        if !energy.is_finite() {
+            continue;
+        }
+        if let Some(bin) = bins.get_mut((energy / bin_width) as usize) {
+            *bin += record.get_weight() as f64;
+        }
+    }
+    let tallest = bins.iter().cloned().fold(0.0, f64::max);
+    let mut found = Vec::new();
+    if tallest == 0.0 {
+        return Ok(found);
+    }
+    // out of range bins count as empty, a plateau is reported at its first bin
+    let at = |i: isize| if i < 0 { 0.0 } else { bins.get(i as usize).cloned().unwrap_or(0.0) };
+    for i in 0..bins.len() as isize {
+        let height = at(i);
+        if height <= at(i - 1) || height < at(i + 1) {
+            continue;
+        }
+        // walk out each side until a taller bin or the edge, keeping the minimum
+        let mut left = height;
+        let mut j = i - 1;
+        while j >= 0 && at(j) <= height {
+            left = left.min(at(j));
+            j -= 1;
+        }
+        if j < 0 {
+            left = 0.0;
+        }
+        let mut right = height;
+        let mut j = i + 1;
+        while (j as usize) < bins.len() && at(j) <= height {
+            right = right.min(at(j));
+            j += 1;
+        }
+        if j as usize >= bins.len() {
+            right = 0.0;
+        }
+        let prominence = (height - left.max(right)) / tallest;
+        if prominence >= min_prominence {
+            found.push(Peak {
+                energy: (i as f32 + 0.5) * bin_width,
+                height,
+                prominence,
+            });
+        }
+    }
+    Ok(found)
+}
+
+// FNV-1a over the whole file, from the start.
+fn checksum(file: &mut File) -> io::Result<u64> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::with_capacity(BUFFER_CAPACITY, file);
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let length = {
+            let buffer = reader.fill_buf()?;
+            for &byte in buffer {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+            buffer.len()
+        };
+        if length == 0 {
+            return Ok(hash);
+        }
+        reader.consume(length);
+    }
+}
+
+/// Write a sidecar index for `input` to `index_out`.
+///
+/// Records are fixed size so the offset of record `i` is always
+/// `(i + 1) * record_size`; the index stores the file length, record size,
+/// particle count and a checksum of the whole file so `PHSPReader::with_index`
+/// can tell when the file has changed under it, followed by the first record
+/// offset and stride.
+pub fn build_index(input: &Path, index_out: &Path) -> EGSResult<()> {
+    let mut file = File::open(input)?;
+    let length = file.metadata()?.len();
+    let header = PHSPReader::from(file.try_clone()?)?.header;
+    let mut buffer = [0; INDEX_LENGTH];
+    buffer[0..8].clone_from_slice(INDEX_MAGIC);
+    LittleEndian::write_u64(&mut buffer[8..16], length);
+    LittleEndian::write_u64(&mut buffer[16..24], header.record_size);
+    LittleEndian::write_u64(&mut buffer[24..32], header.total_particles as u64);
+    LittleEndian::write_u64(&mut buffer[32..40], checksum(&mut file)?);
+    LittleEndian::write_u64(&mut buffer[40..48], header.record_size);
+    LittleEndian::write_u64(&mut buffer[48..56], header.record_size);
+    File::create(index_out)?.write_all(&buffer)?;
+    Ok(())
+}
+
+/// Apply `f` to every record of `reader`, stopping at the first read error
+/// or the first error returned by `f`.
+pub fn for_each_record<F>(reader: PHSPReader, mut f: F) -> EGSResult<()>
+    where F: FnMut(Record) -> EGSResult<()>
+{
+    for record in reader {
+        f(record?)?;
+    }
+    Ok(())
+}
+
+/// Copy records from `reader` to `writer` as they are, up to `n` of them or
+/// all that remain, returning how many were copied. Neither header is touched.
+pub fn copy_records(reader: &mut PHSPReader,
+                    writer: &mut PHSPWriter,
+                    n: Option<usize>)
+                    -> EGSResult<usize> {
+    let limit = n.unwrap_or(usize::MAX);
+    let mut copied = 0;
+    for record in reader.by_ref().take(limit) {
+        writer.write(&record?)?;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+/// Write every record of `reader` to `writer` with its weight replaced by
+/// `f(record)`, the z direction carried in the weight's sign is kept.
+///
+/// Returns the ratio of output to input total weight. Fluence is conserved when
+/// it is within `FLUENCE_TOLERANCE` of 1, it is up to the caller to warn.
+pub fn reweight<F>(reader: PHSPReader, writer: &mut PHSPWriter, mut f: F) -> EGSResult<f64>
+    where F: FnMut(&Record) -> f32
+{
+    let mut weight_in = 0.0f64;
+    let mut weight_out = 0.0f64;
+    for record in reader {
+        let mut record = record?;
+        weight_in += record.get_weight() as f64;
+        let weight = f(&record);
+        record.set_weight(weight);
+        weight_out += record.get_weight() as f64;
+        writer.write(&record)?;
+    }
+    Ok(if weight_in > 0.0 { weight_out / weight_in } else { 1.0 })
+}
+
+/// Size in bytes of the file `combine` would write for `input_paths`, exact as
+/// every record is copied. Only the headers are read.
+pub fn combined_size(input_paths: &[&Path]) -> EGSResult<u64> {
+    assert!(!input_paths.is_empty(), "Cannot combine zero files");
+    let mut headers = Vec::with_capacity(input_paths.len());
+    for path in input_paths.iter() {
+        headers.push(PHSPReader::open(path)?.header);
+    }
+    for header in headers[1..].iter() {
+        headers[0].compatible_with(header)?;
+    }
+    let (particles, _) = total_particles(headers.iter());
+    Ok((particles + 1) * headers[0].record_size)
+}
+
+pub fn combine(input_paths: &[&Path], output_path: &Path, delete: bool) -> EGSResult<()> {
+    combine_with_progress(input_paths, output_path, delete, |_, _| ())
+}
+
+/// `combine` calling `progress(records done, records in total)` after every record.
+pub fn combine_with_progress<P>(input_paths: &[&Path],
+                                output_path: &Path,
+                                delete: bool,
+                                progress: P)
+                                -> EGSResult<()>
+    where P: FnMut(u64, u64)
+{
+    let regions = vec![None; input_paths.len()];
+    combine_tagged(input_paths, &regions, output_path, delete, false, progress)
+}
+
+// Inputs `combine_tagged` keeps open between reading the headers and copying
+// the records, the rest are opened again for the copy. Each holds a file and
+// a read buffer, so this many at most are held at once.
+const MAX_OPEN_INPUTS: usize = 64;
+
+/// `combine_with_progress` that also sets the region of every record from
+/// `input_paths[i]` to `regions[i]` when it is given, so merged beams stay
+/// distinguishable. Only the region bits of the latch change.
+///
+/// With `dry_run` every input is still read and checked, but nothing is written
+/// or deleted, what would have been is printed instead.
+pub fn combine_tagged<P>(input_paths: &[&Path],
+                         regions: &[Option<u32>],
+                         output_path: &Path,
+                         delete: bool,
+                         dry_run: bool,
+                         mut progress: P)
+                         -> EGSResult<()>
+    where P: FnMut(u64, u64)
+{
+    assert!(!input_paths.is_empty(), "Cannot combine zero files");
+    assert!(regions.len() == input_paths.len(), "Need one region per input");
+    check_output_not_input(input_paths, output_path)?;
+    for region in regions.iter().flatten() {
+        check_region(*region)?;
+    }
+    let start = ProcessTime::now();
+    // the first readers stay open so the copy below carries on from their
+    // headers rather than opening and parsing those inputs a second time
+    let mut readers = Vec::with_capacity(input_paths.len().min(MAX_OPEN_INPUTS));
+    let mut headers = Vec::with_capacity(input_paths.len());
+    for path in input_paths.iter() {
+        let reader = PHSPReader::open(path)?;
+        headers.push(reader.header);
+        if readers.len() < MAX_OPEN_INPUTS {
+            readers.push(reader);
+        }
+    }
+    for header in headers[1..].iter() {
+        headers[0].compatible_with(header)?;
+    }
+    let mut final_header = headers[0];
+    final_header.total_particles_in_source = total_histories(headers.iter());
+    let (min_energy, max_energy) = energy_range(headers.iter());
+    final_header.min_energy = min_energy;
+    final_header.max_energy = max_energy;
+    let (total, photons) = total_particles(headers.iter());
+    final_header.total_particles = header_count(total)?;
+    final_header.total_photons = header_count(photons)?;
+    println!();
+    println!("Final header: {:?}", final_header);
+    println!();
+    let mut done = 0;
+    let mut writer = AtomicWriter::create_or_discard(output_path, &final_header, dry_run)?;
+    let record_size = final_header.record_size as usize;
+    let mut readers = readers.into_iter();
+    for (path, region) in input_paths.iter().zip(regions.iter()) {
+        let mut reader = match readers.next() {
+            Some(reader) => reader,
+            None => PHSPReader::open(path)?,
+        };
+        // untagged inputs in the output's byte order are copied byte for byte
+        if region.is_none() && reader.byte_order() == writer.byte_order() {
+            for bytes in reader.raw_records() {
+                writer.write_raw(&bytes?[..record_size])?;
+                done += 1;
+                progress(done, total);
+            }
+            continue;
+        }
+        for record in reader {
+            let mut record = record?;
+            if let Some(region) = *region {
+                record.set_region(region)?;
+            }
+            writer.write(&record)?;
+            done += 1;
+            progress(done, total);
+        }
+    }
+    if dry_run {
+        println!("Dry run, would write {} particles to {}",
+                 writer.particles_written(),
+                 output_path.display());
+    }
+    writer.commit()?;
+    // only once the output is safely in place
+    if delete {
+        for path in input_paths.iter() {
+            if dry_run {
+                println!("Dry run, would delete {}", path.display());
+            } else {
+                remove_file(path)?;
+            }
+        }
+    }
+    let cpu_time: Duration = start.elapsed();
+    println!("CPU time: {:?}", cpu_time);
+    Ok(())
+}
+
+// How `combine_resumable` recognises an input it has seen before: its size,
+// modification time in nanoseconds (0 where the platform has none) and path.
+fn input_fingerprint(path: &Path) -> EGSResult<String> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata.modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    Ok(format!("{} {} {}", metadata.len(), modified, path.display()))
+}
+
+// Records `combine_resumable` writes between checkpoints.
+const CHECKPOINT_RECORDS: u64 = 1 << 22;
+
+/// `combine_with_progress` that can pick up where an interrupted run stopped.
+/// Records go to `<output>.partial`, whose header is brought up to date and
+/// synced to disk at every checkpoint (every few million records and the end
+/// of every input). The inputs finished, records into the next and the
+/// records and photons written are then noted in `<output>.progress`, along
+/// with the path, size and modification time of every input.
+///
+/// Finding both from a run over the same inputs, the partial output is cut
+/// back to the last checkpoint and the copy carries on from there, giving the
+/// same output as a run that was never interrupted. Both files go once the
+/// output is in place. A progress file that doesn't add up against the
+/// inputs, or was written for different or since modified inputs, fails with
+/// `StaleCheckpoint`. Only plain files can be resumed.
+pub fn combine_resumable<P>(input_paths: &[&Path],
+                            output_path: &Path,
+                            delete: bool,
+                            mut progress: P)
+                            -> EGSResult<()>
+    where P: FnMut(u64, u64)
+{
+    assert!(!input_paths.is_empty(), "Cannot combine zero files");
+    check_output_not_input(input_paths, output_path)?;
+    let mut headers = Vec::with_capacity(input_paths.len());
+    for path in input_paths.iter() {
+        headers.push(PHSPReader::open(path)?.header);
+    }
+    let mut final_header = headers[0];
+    for header in headers[1..].iter() {
+        final_header.compatible_with(header)?;
+    }
+    final_header.total_particles_in_source = total_histories(headers.iter());
+    let (total, _) = total_particles(headers.iter());
+    let partial_path = PathBuf::from(format!("{}.partial", output_path.display()));
+    let progress_path = PathBuf::from(format!("{}.progress", output_path.display()));
+    let stale = || EGSError::StaleCheckpoint(progress_path.clone());
+    let inputs = input_paths.iter()
+        .map(|path| input_fingerprint(path))
+        .collect::<EGSResult<Vec<String>>>()?;
+
+    // inputs finished, records into the next one, records and photons written
+    let mut checkpoint = (0usize, 0u64, 0u64, 0u64);
+    let mut writer = if progress_path.exists() && partial_path.exists() {
+        let text = std::fs::read_to_string(&progress_path)?;
+        let mut lines = text.lines();
+        let values = lines.next()
+            .unwrap_or("")
+            .split_whitespace()
+            .map(|value| value.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|_| stale())?;
+        if values.len() != 5 || values[0] != input_paths.len() as u64 ||
+           !lines.eq(inputs.iter().map(String::as_str)) {
+            return Err(stale());
+        }
+        checkpoint = (values[1] as usize, values[2], values[3], values[4]);
+        let (done, into_next, written, photons) = checkpoint;
+        let before: u64 = headers.iter().take(done).map(|h| h.total_particles.max(0) as u64).sum();
+        let next = headers.get(done).map_or(0, |h| h.total_particles.max(0) as u64);
+        if done > input_paths.len() || into_next > next || before + into_next != written ||
+           photons > written {
+            return Err(stale());
+        }
+        // anything after the checkpoint may be half written, and the header may
+        // be from either side of it, the extents are good either way as the
+        // records past it will be written again
+        let mut file = OpenOptions::new().read(true).write(true).open(&partial_path)?;
+        file.set_len((written + 1) * final_header.record_size)?;
+        let mut header = PHSPReader::from_source(Source(Input::File(file.try_clone()?)),
+                                                 None,
+                                                 MAX_RECORD_LENGTH)?
+            .header;
+        header.compatible_with(&final_header)?;
+        header.total_particles = header_count(written)?;
+        header.total_photons = header_count(photons)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header.encode(ByteOrderMode::Little)[..header.record_size as usize])?;
+        PHSPWriter::append(file)?
+    } else {
+        PHSPWriter::create(&partial_path, &final_header)?
+    };
+    let save = |writer: &mut PHSPWriter, done: usize, into_next: u64| -> EGSResult<()> {
+        // the records must be on disk before a checkpoint says they are
+        writer.sync_header()?;
+        writer.sync_all()?;
+        let temp_path = PathBuf::from(format!("{}.tmp", progress_path.display()));
+        let mut text = format!("{} {} {} {} {}\n",
+                               input_paths.len(),
+                               done,
+                               into_next,
+                               writer.particles_written(),
+                               writer.photons_written());
+        for input in inputs.iter() {
+            text.push_str(input);
+            text.push('\n');
+        }
+        let mut file = File::create(&temp_path)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        rename(&temp_path, &progress_path)?;
+        Ok(())
+    };
+    let mut done = checkpoint.2;
+    for (i, path) in input_paths.iter().enumerate().skip(checkpoint.0) {
+        let mut reader = PHSPReader::open(path)?;
+        let mut into_input = if i == checkpoint.0 { checkpoint.1 } else { 0 };
+        if into_input > 0 {
+            reader.seek_to_record(into_input)?;
+        }
+        for record in reader {
+            writer.write(&record?)?;
+            into_input += 1;
+            done += 1;
+            progress(done, total);
+            if done.is_multiple_of(CHECKPOINT_RECORDS) {
+                save(&mut writer, i, into_input)?;
+            }
+        }
+        save(&mut writer, i + 1, 0)?;
+    }
+    writer.finalize()?;
+    rename(&partial_path, output_path)?;
+    remove_file(&progress_path)?;
+    if delete {
+        for path in input_paths.iter() {
+            remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// `combine` taking one record from each input in turn rather than each input
+/// whole, so the first records of the output are drawn from every source. An
+/// input that runs out drops out of the rotation and the rest carry on. Records
+/// of one history are no longer next to each other, only the first record of each
+/// still marks a new history. Every input is open until it runs out, so their
+/// number is bounded by the open file limit, but they share about 1 MiB of
+/// buffers between them, at least 64 KiB each.
+pub fn combine_interleaved(input_paths: &[&Path],
+                           output_path: &Path,
+                           delete: bool)
+                           -> EGSResult<()> {
+    assert!(!input_paths.is_empty(), "Cannot combine zero files");
+    check_output_not_input(input_paths, output_path)?;
+    // every input is read at once, so they share the usual buffer
+    let capacity = (BUFFER_CAPACITY / input_paths.len()).max(MIN_BUFFER_CAPACITY);
+    let mut readers = Vec::with_capacity(input_paths.len());
+    for path in input_paths.iter() {
+        readers.push(PHSPReader::open_with_capacity(path, capacity)?);
+    }
+    let mut final_header = readers[0].header;
+    for reader in readers[1..].iter() {
+        final_header.compatible_with(&reader.header)?;
+    }
+    final_header.total_particles_in_source = total_histories(readers.iter().map(|r| &r.header));
+    let mut writer = AtomicWriter::create(output_path, &final_header)?;
+    while !readers.is_empty() {
+        let mut i = 0;
+        while i < readers.len() {
+            match readers[i].next() {
+                Some(record) => {
+                    writer.write(&record?)?;
+                    i += 1;
+                }
+                None => {
+                    readers.remove(i);
+                }
+            }
+        }
+    }
+    writer.finalize()?;
+    if delete {
+        for path in input_paths.iter() {
+            remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// `combine` mixing the inputs in the ratio of `weights`, one per input, rather
+/// than in proportion to the histories behind each.
+///
+/// With `N_i` incident histories behind input `i`, `N` their sum and
+/// `f_i = weights[i] / sum(weights)`, every record weight of input `i` is
+/// multiplied by `f_i * N / N_i`. The output keeps `N` histories, so a tally per
+/// history over it is `sum(f_i * T_i / N_i)` where `T_i` is the tally summed over
+/// input `i`: each input's own normalised result, mixed by `f_i`. Weights in the
+/// ratio of the `N_i` leave every record alone and give the same file as `combine`.
+/// Inputs with a weight of zero are left out entirely: they aren't read, their
+/// histories don't count towards `N` and `delete` leaves them in place. An input
+/// with records but no histories in its header fails with `BadHeader`. Anything
+/// but one weight per input, a weight that is negative or not finite, or weights
+/// that are all zero, fails with `InvalidArgument`.
+pub fn combine_weighted(input_paths: &[&Path],
+                        weights: &[f64],
+                        output_path: &Path,
+                        delete: bool)
+                        -> EGSResult<()> {
+    assert!(!input_paths.is_empty(), "Cannot combine zero files");
+    if weights.len() != input_paths.len() {
+        return Err(EGSError::InvalidArgument("need one weight per input"));
+    } else if !weights.iter().all(|w| w.is_finite() && *w >= 0.0) {
+        return Err(EGSError::InvalidArgument("weights must be finite and non-negative"));
+    }
+    let weight_sum: f64 = weights.iter().sum();
+    if weight_sum <= 0.0 {
+        return Err(EGSError::InvalidArgument("weights must not all be zero"));
+    }
+    check_output_not_input(input_paths, output_path)?;
+    let used: Vec<(&Path, f64)> = input_paths.iter()
+        .zip(weights.iter())
+        .filter(|&(_, &weight)| weight > 0.0)
+        .map(|(path, &weight)| (*path, weight))
+        .collect();
+    let mut headers = Vec::with_capacity(used.len());
+    for &(path, _) in used.iter() {
+        headers.push(PHSPReader::open(path)?.header);
+    }
+    let mut final_header = headers[0];
+    for header in headers[1..].iter() {
+        final_header.compatible_with(header)?;
+    }
+    final_header.total_particles_in_source = total_histories(headers.iter());
+    let histories = final_header.total_particles_in_source as f64;
+    let mut writer = AtomicWriter::create(output_path, &final_header)?;
+    for (header, &(path, weight)) in headers.iter().zip(used.iter()) {
+        let input_histories = header.total_particles_in_source as f64;
+        if header.total_particles <= 0 {
+            continue;
+        }
+        if input_histories <= 0.0 {
+            return Err(EGSError::BadHeader);
+        }
+        let scale = weight / weight_sum * histories / input_histories;
+        for record in PHSPReader::open(path)? {
+            let mut record = record?;
+            let weight = record.get_weight() as f64 * scale;
+            record.set_weight(weight as f32);
+            writer.write(&record)?;
+        }
+    }
+    writer.finalize()?;
+    if delete {
+        for &(path, _) in used.iter() {
+            remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// `combine` keeping only the records `keep` returns true for, in one pass. The
+/// header counts and energy extents are those of the records kept, the number
+/// of incident histories is still the sum over all inputs.
+pub fn combine_filtered<F>(input_paths: &[&Path],
+                           output_path: &Path,
+                           delete: bool,
+                           mut keep: F)
+                           -> EGSResult<()>
+    where F: FnMut(&Record) -> bool
+{
+    assert!(!input_paths.is_empty(), "Cannot combine zero files");
+    check_output_not_input(input_paths, output_path)?;
+    let mut headers = Vec::with_capacity(input_paths.len());
+    for path in input_paths.iter() {
+        headers.push(PHSPReader::open(path)?.header);
+    }
+    let mut final_header = headers[0];
+    for header in headers[1..].iter() {
+        final_header.compatible_with(header)?;
+    }
+    final_header.total_particles_in_source = total_histories(headers.iter());
+    let mut writer = AtomicWriter::create(output_path, &final_header)?;
+    for path in input_paths.iter() {
+        for record in PHSPReader::open(path)? {
+            let record = record?;
+            if keep(&record) {
+                writer.write(&record)?;
+            }
+        }
+    }
+    writer.finalize()?;
+    if delete {
+        for path in input_paths.iter() {
+            remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// Approximate set membership in a fixed number of bits, may claim to have seen
+// something it hasn't but never the other way around.
+struct BloomFilter {
+    bits: Vec<u64>,
+    len: u64,
+}
+
+impl BloomFilter {
+    const HASHES: u64 = 4;
+
+    fn new(bits: usize) -> BloomFilter {
+        let words = bits.div_ceil(64).max(1);
+        BloomFilter {
+            bits: vec![0; words],
+            len: words as u64 * 64,
+        }
+    }
+
+    // Marks `bytes` as seen, returning whether it (probably) already was.
+    fn insert(&mut self, bytes: &[u8]) -> bool {
+        let mut first = DefaultHasher::new();
+        bytes.hash(&mut first);
+        let h1 = first.finish();
+        let mut second = DefaultHasher::new();
+        h1.hash(&mut second);
+        let h2 = second.finish() | 1;
+        let mut seen = true;
+        for i in 0..BloomFilter::HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.len;
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            if self.bits[word] & mask == 0 {
+                seen = false;
+                self.bits[word] |= mask;
+            }
+        }
+        seen
+    }
+}
+
+/// Like `combine`, but records whose bytes exactly match an earlier record are
+/// dropped and the header counts adjusted. Floating point near duplicates are kept.
+///
+/// Exact mode remembers every distinct record. For inputs too large for that pass
+/// `bloom_bits` to use a Bloom filter of that many bits instead: memory stays fixed
+/// but a small fraction of unique records is wrongly dropped, fewer the more bits.
+pub fn combine_dedup(input_paths: &[&Path],
+                     output_path: &Path,
+                     delete: bool,
+                     bloom_bits: Option<usize>)
+                     -> EGSResult<()> {
+    assert!(!input_paths.is_empty(), "Cannot combine zero files");
+    check_output_not_input(input_paths, output_path)?;
+    let mut headers = Vec::with_capacity(input_paths.len());
+    for path in input_paths.iter() {
+        headers.push(PHSPReader::open(path)?.header);
+    }
+    let mut final_header = headers[0];
+    for header in headers[1..].iter() {
+        final_header.compatible_with(header)?;
+    }
+    final_header.total_particles_in_source = total_histories(headers.iter());
+    let record_size = final_header.record_size as usize;
+    let mut exact: HashSet<Record> = HashSet::new();
+    let mut bloom = bloom_bits.map(BloomFilter::new);
+    let mut writer = AtomicWriter::create(output_path, &final_header)?;
+    let mut duplicates: u64 = 0;
+    for path in input_paths.iter() {
+        for record in PHSPReader::open(path)? {
+            let record = record?;
+            let seen = match bloom {
+                Some(ref mut bloom) => {
+                    let bytes = record.encode(final_header.using_zlast, ByteOrderMode::Little);
+                    bloom.insert(&bytes[..record_size])
+                }
+                None => !exact.insert(record),
+            };
+            if seen {
+                duplicates += 1;
+            } else {
+                writer.write(&record)?;
+            }
+        }
+    }
+    println!("Dropped {} duplicate records", duplicates);
+    writer.finalize()?;
+    if delete {
+        for path in input_paths.iter() {
+            remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// What `combine_sorted` orders records by.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SortKey {
+    /// `total_energy()`, ignoring the sign that marks a new history.
+    Energy,
+    /// `radius()` in the scoring plane.
+    Radius,
+    /// `region_index()`.
+    Region,
+}
+
+impl SortKey {
+    fn of(&self, record: &Record) -> f32 {
+        match *self {
+            SortKey::Energy => record.total_energy(),
+            SortKey::Radius => record.radius(),
+            SortKey::Region => record.region_index() as f32,
+        }
+    }
+}
+
+// Ascending with NaN after everything else.
+fn compare_keys(a: f32, b: f32) -> Ordering {
+    a.partial_cmp(&b).unwrap_or_else(|| a.is_nan().cmp(&b.is_nan()))
+}
+
+// Records per sorted run in `combine_sorted`, 32 MB of MODE2 records.
+const SORT_RUN_RECORDS: usize = 1 << 20;
+
+// Runs `combine_sorted` merges at once, each holds a file and a read buffer.
+const MERGE_FAN_IN: usize = 64;
+
+// Temporary files deleted when dropped, so a failed sort cleans up after itself.
+struct TempFiles(Vec<PathBuf>);
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in self.0.iter() {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+// The next record of run `run` during the merge, `BinaryHeap` is a max heap so
+// the order is reversed, and ties go to the earlier run to keep the sort stable.
+struct RunHead {
+    key: f32,
+    run: usize,
+    record: Record,
+}
+
+impl Ord for RunHead {
+    fn cmp(&self, other: &RunHead) -> Ordering {
+        compare_keys(other.key, self.key).then(other.run.cmp(&self.run))
+    }
+}
+
+impl PartialOrd for RunHead {
+    fn partial_cmp(&self, other: &RunHead) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RunHead {
+    fn eq(&self, other: &RunHead) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RunHead {}
+
+// Merge the sorted runs at `paths` by `key`, handing `write` every record in order.
+fn merge_runs<F>(paths: &[PathBuf], key: SortKey, mut write: F) -> EGSResult<()>
+    where F: FnMut(&Record) -> EGSResult<()>
+{
+    let mut readers = Vec::with_capacity(paths.len());
+    let mut heads = BinaryHeap::with_capacity(paths.len());
+    for (i, path) in paths.iter().enumerate() {
+        let mut reader = PHSPReader::open(path)?;
+        if let Some(record) = reader.next() {
+            let record = record?;
+            heads.push(RunHead { key: key.of(&record), run: i, record });
+        }
+        readers.push(reader);
+    }
+    while let Some(head) = heads.pop() {
+        write(&head.record)?;
+        if let Some(record) = readers[head.run].next() {
+            let record = record?;
+            heads.push(RunHead { key: key.of(&record), run: head.run, record });
+        }
+    }
+    Ok(())
+}
+
+/// Like `combine`, but the output is ordered by `key`, smallest first, with
+/// records holding a NaN key at the end. Records with equal keys keep their
+/// order from the inputs.
+///
+/// Inputs don't have to fit in memory: records are sorted in runs of about a
+/// million, each run is written to a temporary file next to `output_path` and
+/// the runs are then merged, at most 64 at a time so the number of open files
+/// stays bounded. More runs than that are merged in passes, which need as much
+/// temporary space again as the runs themselves.
+pub fn combine_sorted(input_paths: &[&Path], output_path: &Path, key: SortKey) -> EGSResult<()> {
+    assert!(!input_paths.is_empty(), "Cannot combine zero files");
+    check_output_not_input(input_paths, output_path)?;
+    let mut headers = Vec::with_capacity(input_paths.len());
+    for path in input_paths.iter() {
+        headers.push(PHSPReader::open(path)?.header);
+    }
+    let mut final_header = headers[0];
+    for header in headers[1..].iter() {
+        final_header.compatible_with(header)?;
+    }
+    final_header.total_particles_in_source = total_histories(headers.iter());
+    let (min_energy, max_energy) = energy_range(headers.iter());
+    final_header.min_energy = min_energy;
+    final_header.max_energy = max_energy;
+    let (particles, photons) = total_particles(headers.iter());
+    final_header.total_particles = header_count(particles)?;
+    final_header.total_photons = header_count(photons)?;
+
+    let mut runs = TempFiles(Vec::new());
+    let mut run: Vec<(f32, Record)> = Vec::new();
+    let write_run = |run: &mut Vec<(f32, Record)>, runs: &mut TempFiles| -> EGSResult<()> {
+        // stable, so equal keys stay in input order within the run
+        run.sort_by(|a, b| compare_keys(a.0, b.0));
+        let path = PathBuf::from(format!("{}.run-{}", output_path.display(), runs.0.len()));
+        runs.0.push(path.clone());
+        let mut writer = PHSPWriter::from(File::create(&path)?, &final_header)?;
+        for (_, record) in run.iter() {
+            writer.write(record)?;
+        }
+        writer.finalize()?;
+        run.clear();
+        Ok(())
+    };
+    for path in input_paths.iter() {
+        for record in PHSPReader::open(path)? {
+            let record = record?;
+            run.push((key.of(&record), record));
+            if run.len() == SORT_RUN_RECORDS {
+                write_run(&mut run, &mut runs)?;
+            }
+        }
+    }
+    if !run.is_empty() {
+        write_run(&mut run, &mut runs)?;
+    }
+
+    // consecutive runs are merged together, so equal keys keep their order
+    let mut pass = 0;
+    while runs.0.len() > MERGE_FAN_IN {
+        pass += 1;
+        let mut merged = TempFiles(Vec::new());
+        for batch in runs.0.chunks(MERGE_FAN_IN) {
+            let path = PathBuf::from(format!("{}.run-{}-{}",
+                                             output_path.display(),
+                                             pass,
+                                             merged.0.len()));
+            merged.0.push(path.clone());
+            let mut writer = PHSPWriter::from(File::create(&path)?, &final_header)?;
+            merge_runs(batch, key, |record| writer.write(record))?;
+            writer.finalize()?;
+        }
+        runs = merged;
+    }
+    let mut writer = AtomicWriter::create(output_path, &final_header)?;
+    merge_runs(&runs.0, key, |record| writer.write(record))?;
+    writer.commit()
+}
+
+/// Split `input` into `parts` files named `<output_prefix>_<n>.egsphsp1` holding
+/// consecutive runs of records, the inverse of `combine`. When the particle count
+/// doesn't divide evenly the first chunks take one extra record each. Incident
+/// particles from source are shared out in proportion to each chunk's size.
+/// Each part is written atomically, so an error leaves only complete parts.
+/// Zero `parts` fails with `InvalidArgument`.
+pub fn split(input: &Path, output_prefix: &Path, parts: usize) -> EGSResult<Vec<PathBuf>> {
+    if parts == 0 {
+        return Err(EGSError::InvalidArgument("cannot split into zero files"));
+    }
+    let mut reader = PHSPReader::open(input)?;
+    let header = reader.header;
+    let total = header.total_particles.max(0) as u64;
+    let base = total / parts as u64;
+    let remainder = total % parts as u64;
+    let mut paths = Vec::with_capacity(parts);
+    for i in 0..parts as u64 {
+        let path = PathBuf::from(format!("{}_{}.egsphsp1", output_prefix.display(), i + 1));
+        let count = base + if i < remainder { 1 } else { 0 };
+        let mut writer = AtomicWriter::create(&path, &header)?;
+        copy_records(&mut reader, &mut writer, Some(count as usize))?;
+        if total > 0 {
+            writer.header.total_particles_in_source =
+                (header.total_particles_in_source as f64 * count as f64 / total as f64) as f32;
+        }
+        writer.finalize()?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Copy records `start` up to but not including `end` of `input` into `output`,
+/// seeking straight to `start`. Incident particles from source are scaled by
+/// the share of records kept, like `split`. A range running past the last
+/// record returns `RecordOutOfRange`, an empty one `EmptyRange`.
+pub fn extract_range(input: &Path, output: &Path, start: u64, end: u64) -> EGSResult<()> {
+    let mut reader = PHSPReader::open(input)?;
+    let header = reader.header;
+    let total = header.total_particles.max(0) as u64;
+    if end > total {
+        return Err(EGSError::RecordOutOfRange(end));
+    } else if start >= end {
+        return Err(EGSError::EmptyRange { start, end });
+    }
+    reader.seek_to_record(start)?;
+    let mut writer = AtomicWriter::create(output, &header)?;
+    copy_records(&mut reader, &mut writer, Some((end - start) as usize))?;
+    writer.header.total_particles_in_source =
+        (header.total_particles_in_source as f64 * (end - start) as f64 / total as f64) as f32;
+    writer.finalize()
+}
+
+/// Pick exactly `count` records uniformly at random from all inputs in one pass
+/// (reservoir sampling) and write them in their original order. Inputs holding
+/// fewer than `count` records in total are copied whole. Incident particles from
+/// source are scaled by the fraction of records kept.
+pub fn sample_exact(inputs: &[&Path], output: &Path, count: usize, seed: u64) -> EGSResult<()> {
+    assert!(!inputs.is_empty(), "Cannot sample zero files");
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut header = PHSPReader::open(inputs[0])?.header;
+    // `count` can be far more than there are records
+    let mut total = 0u64;
+    for path in inputs.iter() {
+        total += PHSPReader::open(path)?.header.total_particles.max(0) as u64;
+    }
+    let mut reservoir: Vec<(u64, Record)> =
+        Vec::with_capacity(count.min(usize::try_from(total).unwrap_or(usize::MAX)));
+    let mut source_particles = 0.0;
+    let mut seen: u64 = 0;
+    for path in inputs.iter() {
+        let reader = PHSPReader::open(path)?;
+        header.compatible_with(&reader.header)?;
+        source_particles += reader.header.effective_source_particles() as f64;
+        for record in reader {
+            let record = record?;
+            if reservoir.len() < count {
+                reservoir.push((seen, record));
+            } else {
+                let j = rng.gen_range(0..=seen);
+                if j < count as u64 {
+                    reservoir[j as usize] = (seen, record);
+                }
+            }
+            seen += 1;
+        }
+    }
+    reservoir.sort_by_key(|&(index, _)| index);
+    if seen > 0 {
+        header.total_particles_in_source =
+            (source_particles * reservoir.len() as f64 / seen as f64) as f32;
+    }
+    let mut writer = AtomicWriter::create(output, &header)?;
+    for (_, record) in reservoir.iter() {
+        writer.write(record)?;
+    }
+    writer.finalize()
+}
+
+pub fn sample(ipaths: &[&Path], opath: &Path, rate: u32, seed: u64) -> EGSResult<()> {
+    sample_rates(ipaths, opath, rate, rate, seed, false, |_, _| ())
+}
+
+/// `sample` calling `progress(records read, records in total)` after every input record.
+pub fn sample_with_progress<P>(ipaths: &[&Path],
+                               opath: &Path,
+                               rate: u32,
+                               seed: u64,
+                               dry_run: bool,
+                               progress: P)
+                               -> EGSResult<()>
+    where P: FnMut(u64, u64)
+{
+    sample_rates(ipaths, opath, rate, rate, seed, dry_run, progress)
+}
+
+/// Like `sample` but photons are kept at 1 in `photon_rate` and electrons and
+/// positrons at 1 in `charged_rate`. Weights are left alone and the source
+/// histories are scaled by the photon rate, so the charged component needs
+/// reweighting by `charged_rate / photon_rate` to keep its fluence. Either rate
+/// being 0 fails with `InvalidArgument` before the output is created.
+pub fn sample_by_type(ipaths: &[&Path],
+                      opath: &Path,
+                      photon_rate: u32,
+                      charged_rate: u32,
+                      seed: u64)
+                      -> EGSResult<()> {
+    sample_rates(ipaths, opath, photon_rate, charged_rate, seed, false, |_, _| ())
+}
+
+/// Roughly the size in bytes of the file `sample_by_type` would write, from the
+/// photon and charged particle counts in the headers and the chance each is kept.
+/// The real size varies with the seed. A rate of 0 fails with `InvalidArgument`
+/// as `sample_by_type` would.
+pub fn sampled_size(ipaths: &[&Path], photon_rate: u32, charged_rate: u32) -> EGSResult<u64> {
+    assert!(!ipaths.is_empty(), "Cannot combine zero files");
+    if photon_rate == 0 || charged_rate == 0 {
+        return Err(EGSError::InvalidArgument("sample rates must be at least 1"));
+    }
+    let header = empty_header_like(ipaths)?;
+    let mut expected = 0.0;
+    for path in ipaths.iter() {
+        let input = PHSPReader::open(path)?.header;
+        let photons = input.total_photons.max(0) as f64;
+        let charged = (input.total_particles - input.total_photons).max(0) as f64;
+        expected += photons / photon_rate as f64 + charged / charged_rate as f64;
+    }
+    // the float to integer cast saturates, the header record is added the same way
+    Ok((expected.round() as u64).saturating_add(1).saturating_mul(header.record_size))
+}
+
+fn sample_rates<P>(ipaths: &[&Path],
+                   opath: &Path,
+                   photon_rate: u32,
+                   charged_rate: u32,
+                   seed: u64,
+                   dry_run: bool,
+                   mut progress: P)
+                   -> EGSResult<()>
+    where P: FnMut(u64, u64)
+{
+    assert!(!ipaths.is_empty(), "Cannot combine zero files");
+    // before anything is written, `gen_ratio` panics on a zero rate
+    if photon_rate == 0 || charged_rate == 0 {
+        return Err(EGSError::InvalidArgument("sample rates must be at least 1"));
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let header = empty_header_like(ipaths)?;
+    let mut total = 0;
+    for path in ipaths.iter() {
+        total += PHSPReader::open(path)?.header.total_particles.max(0) as u64;
+    }
+    let mut done = 0;
+    // the header is patched once the counts are known, which a compressed
+    // stream can't do, so sample into a plain file and compress it afterwards
+    let plain_path = if is_gzip(opath) {
+        PathBuf::from(format!("{}.partial", opath.display()))
+    } else {
+        opath.to_path_buf()
+    };
+    let mut writer = AtomicWriter::create_or_discard(&plain_path, &header, dry_run)?;
+    let mut histories = 0.0f64;
+    for path in ipaths.iter() {
+        let reader = PHSPReader::open(path)?;
+        println!("Found {} particles", reader.header.total_particles);
+        histories += reader.header.effective_source_particles() as f64;
+        for record in reader {
+            let record = record?;
+            done += 1;
+            progress(done, total);
+            let rate = if record.particle_type() != ParticleType::Photon {
+                charged_rate
+            } else {
+                photon_rate
+            };
+            if rng.gen_ratio(1, rate) {
+                writer.write(&record)?;
+            }
+        }
+        println!("Now have {} particles", writer.particles_written());
+    }
+    writer.header.total_particles_in_source = (histories / photon_rate as f64) as f32;
+    if dry_run {
+        println!("Dry run, would write {} particles to {}",
+                 writer.particles_written(),
+                 opath.display());
+        return writer.finalize();
+    }
+    writer.finalize()?;
+    if plain_path != opath {
+        let copied = copy_file(&plain_path, opath);
+        remove_file(&plain_path)?;
+        copied?;
+    }
+    Ok(())
+}
+
+// A header with no particles in the mode shared by all `inputs`, failing with
+// `ModeMismatch` if they don't share one.
+fn empty_header_like(inputs: &[&Path]) -> EGSResult<Header> {
+    let mode = PHSPReader::open(inputs[0])?.header.mode;
+    for path in inputs[1..].iter() {
+        if PHSPReader::open(path)?.header.mode != mode {
+            return Err(EGSError::ModeMismatch);
+        }
+    }
+    if &mode == b"MODE2" {
+        Header::new_mode2(0, 0, 0.0)
+    } else {
+        Header::new_mode0(0, 0, 0.0)
+    }
+}
+
+/// Like `sample` but inputs are sampled concurrently on up to `threads` threads,
+/// each into its own temporary file, and then concatenated in input order.
+///
+/// Every input gets its own seed drawn from `seed`, so the output is
+/// reproducible for a given seed (whatever the thread count) but differs from
+/// what `sample` picks with the same seed. A `rate` or `threads` of 0 fails with
+/// `InvalidArgument`.
+pub fn sample_parallel(ipaths: &[&Path],
+                       opath: &Path,
+                       rate: u32,
+                       seed: u64,
+                       threads: usize)
+                       -> EGSResult<()> {
+    assert!(!ipaths.is_empty(), "Cannot combine zero files");
+    if rate == 0 {
+        return Err(EGSError::InvalidArgument("the sample rate must be at least 1"));
+    } else if threads == 0 {
+        return Err(EGSError::InvalidArgument("cannot sample on zero threads"));
+    }
+    let empty = empty_header_like(ipaths)?;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let seeds: Vec<u64> = ipaths.iter().map(|_| rng.gen()).collect();
+    let temp_paths: Vec<PathBuf> = (0..ipaths.len())
+        .map(|i| PathBuf::from(format!("{}.partial-{}", opath.display(), i)))
+        .collect();
+    let results: Vec<EGSResult<Header>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(ipaths.len()))
+            .map(|worker| {
+                let seeds = &seeds;
+                let temp_paths = &temp_paths;
+                scope.spawn(move || {
+                    (worker..ipaths.len())
+                        .step_by(threads)
+                        .map(|i| (i, sample_into(ipaths[i], &temp_paths[i], rate, seeds[i])))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut results: Vec<_> = workers.into_iter()
+            .flat_map(|worker| worker.join().expect("Sampling thread panicked"))
+            .collect();
+        results.sort_by_key(|&(i, _)| i);
+        results.into_iter().map(|(_, result)| result).collect()
+    });
+    let concatenated = results.into_iter()
+        .collect::<EGSResult<Vec<Header>>>()
+        .and_then(|headers| {
+            let mut header = empty;
+            for part in headers.iter() {
+                header.compatible_with(part)?;
+            }
+            header.total_particles_in_source = total_histories(headers.iter());
+            let (min_energy, max_energy) = energy_range(headers.iter());
+            header.min_energy = min_energy;
+            header.max_energy = max_energy;
+            let (particles, photons) = total_particles(headers.iter());
+            header.total_particles = header_count(particles)?;
+            header.total_photons = header_count(photons)?;
+            let mut writer = AtomicWriter::create(opath, &header)?;
+            for path in temp_paths.iter() {
+                copy_records(&mut PHSPReader::open(path)?, &mut writer, None)?;
+            }
+            writer.commit()
+        });
+    for path in temp_paths.iter() {
+        if path.exists() {
+            remove_file(path)?;
+        }
+    }
+    concatenated
+}
+
+// Sample one input into a plain file for `sample_parallel`, returning its header.
+fn sample_into(ipath: &Path, opath: &Path, rate: u32, seed: u64) -> EGSResult<Header> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let reader = PHSPReader::open(ipath)?;
+    let mut writer = PHSPWriter::create(opath, &empty_header_like(&[ipath])?)?;
+    writer.header.total_particles_in_source =
+        reader.header.effective_source_particles() / rate as f32;
+    for record in reader.filter(|_| rng.gen_ratio(1, rate)) {
+        writer.write(&record?)?;
+    }
+    writer.finalize()?;
+    Ok(PHSPReader::open(opath)?.header)
+}
+
+// Re-encode every record of `input` into `output`, compressing or
+// decompressing according to the file extensions.
+fn copy_file(input: &Path, output: &Path) -> EGSResult<()> {
+    let mut reader = PHSPReader::open(input)?;
+    let mut writer = AtomicWriter::create(output, &reader.header)?;
+    copy_records(&mut reader, &mut writer, None)?;
+    writer.commit()
+}
+
+/// Mean `(x_cm, y_cm)` over all records weighted by `get_weight()`, failing with
+/// `ZeroWeight` when there is no weight to average over.
+pub fn centroid(input: &Path) -> EGSResult<(f32, f32)> {
+    let mut weight = 0.0f64;
+    let mut x = 0.0f64;
+    let mut y = 0.0f64;
+    for record in PHSPReader::open(input)? {
+        let record = record?;
+        let w = record.get_weight() as f64;
+        weight += w;
+        x += w * record.x_cm as f64;
+        y += w * record.y_cm as f64;
+    }
+    if weight == 0.0 {
+        return Err(EGSError::ZeroWeight);
+    }
+    Ok(((x / weight) as f32, (y / weight) as f32))
+}
+
+/// Weighted mean and variance of one quantity, accumulated a value at a time
+/// (West's update) and combined across partial results (Chan et al.), both
+/// stable where summing squares would cancel.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Moments {
+    /// Sum of the weights seen.
+    pub weight: f64,
+    mean: f64,
+    // weighted sum of squared differences from the mean
+    m2: f64,
+}
+
+impl Moments {
+    pub fn add(&mut self, value: f64, weight: f64) {
+        if weight == 0.0 {
+            return;
+        }
+        self.weight += weight;
+        let delta = value - self.mean;
+        self.mean += delta * weight / self.weight;
+        self.m2 += weight * delta * (value - self.mean);
+    }
+
+    /// Fold in moments gathered over other values.
+    pub fn merge(&mut self, other: &Moments) {
+        let weight = self.weight + other.weight;
+        if weight == 0.0 {
+            return;
+        }
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.weight / weight;
+        self.m2 += other.m2 + delta * delta * self.weight * other.weight / weight;
+        self.weight = weight;
+    }
+
+    /// NaN when no weight has been added.
+    pub fn mean(&self) -> f64 {
+        if self.weight == 0.0 { f64::NAN } else { self.mean }
+    }
+
+    /// Population variance, NaN when no weight has been added.
+    pub fn variance(&self) -> f64 {
+        self.m2 / self.weight
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        self.variance().sqrt()
+    }
+}
+
+/// Weighted moments of the main record fields, weights from `get_weight()`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Statistics {
+    pub records: u64,
+    /// Total energy in MeV.
+    pub energy: Moments,
+    pub x_cm: Moments,
+    pub y_cm: Moments,
+    pub x_cos: Moments,
+    pub y_cos: Moments,
+}
+
+impl Statistics {
+    pub fn add(&mut self, record: &Record) {
+        let weight = record.get_weight() as f64;
+        self.records += 1;
+        self.energy.add(record.total_energy() as f64, weight);
+        self.x_cm.add(record.x_cm as f64, weight);
+        self.y_cm.add(record.y_cm as f64, weight);
+        self.x_cos.add(record.x_cos as f64, weight);
+        self.y_cos.add(record.y_cos as f64, weight);
+    }
+
+    pub fn merge(&mut self, other: &Statistics) {
+        self.records += other.records;
+        self.energy.merge(&other.energy);
+        self.x_cm.merge(&other.x_cm);
+        self.y_cm.merge(&other.y_cm);
+        self.x_cos.merge(&other.x_cos);
+        self.y_cos.merge(&other.y_cos);
+    }
+}
+
+/// `Statistics` over every record of `input` in one streaming pass.
+pub fn statistics(input: &Path) -> EGSResult<Statistics> {
+    let mut statistics = Statistics::default();
+    for record in PHSPReader::open(input)? {
+        statistics.add(&record?);
+    }
+    Ok(statistics)
+}
+
+// Records per chunk handed to a thread by `statistics_parallel`.
+#[cfg(feature = "parallel")]
+const STATISTICS_CHUNK_RECORDS: usize = 1 << 16;
+
+/// A memory mapped phase space that any number of threads can decode records
+/// from at once, for servers answering queries over one file. Clones are cheap
+/// and share the mapping, there are no file handles or locks per thread. Only
+/// plain files can be mapped, compressed ones return `Unsupported`.
+///
+/// The file must not be truncated or written to while any clone is alive. The
+/// records are read straight from the mapping: a rewritten file changes what
+/// they decode to, and reading past a truncation kills the process with
+/// SIGBUS instead of returning an error.
+#[cfg(feature = "parallel")]
+#[derive(Clone)]
+pub struct SharedPHSP {
+    map: Arc<Mmap>,
+    pub header: Header,
+    byte_order: ByteOrderMode,
+}
+
+#[cfg(feature = "parallel")]
+impl SharedPHSP {
+    /// Map `path` after checking its length against the header. See the type's
+    /// docs for what must not happen to the file while it stays mapped.
+    pub fn open(path: &Path) -> EGSResult<SharedPHSP> {
+        if is_gzip(path) {
+            return Err(EGSError::Unsupported("memory mapping a gzip file"));
+        }
+        // checks the length against the header
+        let reader = PHSPReader::open(path)?;
+        let file = File::open(path)?;
+        // Safety: the map is only sound while nothing truncates or writes to the
+        // file, a shrunk file faults with SIGBUS on the next read of the lost pages
+        // and a rewritten one changes bytes under a shared slice. Callers are told
+        // so in the docs, there is no way to enforce it from here.
+        let map = unsafe { Mmap::map(&file)? };
+        if (map.len() as u64) < reader.header.expected_file_size() {
+            return Err(EGSError::BadLength);
+        }
+        Ok(SharedPHSP {
+            map: Arc::new(map),
+            header: reader.header,
+            byte_order: reader.byte_order(),
+        })
+    }
+
+    pub fn byte_order(&self) -> ByteOrderMode {
+        self.byte_order
+    }
+
+    /// Records the header counts.
+    pub fn len(&self) -> u64 {
+        self.header.total_particles.max(0) as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Decode record `index`, counting from zero, straight from the mapping.
+    pub fn record(&self, index: u64) -> EGSResult<Record> {
+        if index >= self.len() {
+            return Err(EGSError::RecordOutOfRange(index));
+        }
+        let record_size = self.header.record_size as usize;
+        let start = (index as usize + 1) * record_size;
+        Ok(Record::decode(&self.map[start..start + record_size],
+                          self.header.using_zlast,
+                          self.byte_order))
+    }
+
+    // The bytes of every record, without the header.
+    fn records(&self) -> &[u8] {
+        let record_size = self.header.record_size as usize;
+        &self.map[record_size..(self.len() as usize + 1) * record_size]
+    }
+}
+
+/// Like `statistics` but the file is memory mapped and cut into chunks of
+/// whole records that are gathered on rayon's thread pool, then merged. The
+/// result matches `statistics` up to floating point rounding. Only plain
+/// files can be mapped, compressed ones return `Unsupported`.
+///
+/// Nothing may truncate or write to `input` while this runs: a truncated file
+/// kills the process with SIGBUS rather than returning an error.
+#[cfg(feature = "parallel")]
+pub fn statistics_parallel(input: &Path) -> EGSResult<Statistics> {
+    let shared = SharedPHSP::open(input)?;
+    let (header, order) = (shared.header, shared.byte_order());
+    let record_size = header.record_size as usize;
+    Ok(shared.records()
+        .par_chunks(record_size * STATISTICS_CHUNK_RECORDS)
+        .map(|chunk| {
+            let mut statistics = Statistics::default();
+            for buffer in chunk.chunks(record_size) {
+                statistics.add(&Record::decode(buffer, header.using_zlast, order));
+            }
+            statistics
+        })
+        .reduce(Statistics::default, |mut a, b| {
+            a.merge(&b);
+            a
+        }))
+}
+
+/// Write `n` unit weight photons of `energy` MeV leaving the origin in directions
+/// spread evenly over the sphere, each the first particle of its own history.
+pub fn generate_point_source(output: &Path, n: usize, energy: f32, seed: u64) -> EGSResult<()> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let count = i32::try_from(n).map_err(|_| EGSError::BadHeader)?;
+    // known up front, and `finalize` would leave the energy extents empty since
+    // records that start a history don't count towards them
+    let mut header = Header::new_mode0(count, count, n as f32)?;
+    if n > 0 {
+        header.min_energy = energy;
+        header.max_energy = energy;
+    }
+    let mut writer = AtomicWriter::create(output, &header)?;
+    for _ in 0..n {
+        let z_cos: f32 = rng.gen_range(-1.0..=1.0);
+        let phi: f32 = rng.gen_range(0.0..2.0 * PI);
+        let sin_theta = (1.0 - z_cos * z_cos).max(0.0).sqrt();
+        let mut x_cos = sin_theta * phi.cos();
+        let mut y_cos = sin_theta * phi.sin();
+        // rounding can push the transverse part just past 1
+        while x_cos * x_cos + y_cos * y_cos > 1.0 {
+            x_cos *= 1.0 - f32::EPSILON;
+            y_cos *= 1.0 - f32::EPSILON;
+        }
+        let weight = if z_cos < 0.0 { -1.0 } else { 1.0 };
+        writer.write(&Record::new(0, -energy, 0.0, 0.0, x_cos, y_cos, weight))?;
+    }
+    writer.commit()
+}
+
+/// Add gaussian noise with standard deviation `sigma` radians to the polar angle of
+/// every record, positions and energies are left alone.
+pub fn angular_smear(input_path: &Path,
+                     output_path: &Path,
+                     sigma: f32,
+                     seed: u64)
+                     -> EGSResult<()> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let normal = Normal::new(0.0, sigma)
+        .map_err(|_| EGSError::BadTransform(format!("smear sigma {}", sigma)))?;
+    rewrite(input_path, output_path, |record| {
+        let delta = normal.sample(&mut rng);
+        if delta != 0.0 {
+            record.tilt(delta);
+        }
+        true
+    })
+}
+
+// Stream `input` into `output` through `f`, dropping the records it returns
+// false for. Counts and energy extents in the header are recomputed.
+fn rewrite<F>(input_path: &Path, output_path: &Path, mut f: F) -> EGSResult<()>
+    where F: FnMut(&mut Record) -> bool
+{
+    let reader = PHSPReader::open(input_path)?;
+    let mut writer = AtomicWriter::create(output_path, &reader.header)?;
+    for record in reader {
+        let mut record = record?;
+        if f(&mut record) {
+            writer.write(&record)?;
+        }
+    }
+    writer.finalize()
+}
+
+/// Fit a phase space to a scoring circle of radius `max_radius` cm, either dropping
+/// particles outside it or moving them radially onto its edge.
+pub fn clamp_radius(input_path: &Path,
+                    output_path: &Path,
+                    max_radius: f32,
+                    mode: ClampMode)
+                    -> EGSResult<()> {
+    rewrite(input_path, output_path, |record| {
+        let radius = record.radius();
+        if radius <= max_radius {
+            return true;
+        }
+        match mode {
+            ClampMode::Discard => false,
+            ClampMode::Clamp => {
+                record.x_cm *= max_radius / radius;
+                record.y_cm *= max_radius / radius;
+                true
+            }
+        }
+    })
+}
+
+/// Cap total energies at `max_energy` MeV, either dropping particles above it
+/// or lowering their energy to it. Intended for scoring artifacts above the
+/// nominal beam energy.
+pub fn clamp_energy(input_path: &Path,
+                    output_path: &Path,
+                    max_energy: f32,
+                    mode: ClampMode)
+                    -> EGSResult<()> {
+    rewrite(input_path, output_path, |record| {
+        if record.total_energy() <= max_energy {
+            return true;
+        }
+        match mode {
+            ClampMode::Discard => false,
+            ClampMode::Clamp => {
+                record.set_total_energy(max_energy);
+                true
+            }
+        }
+    })
+}
+
+/// Add `delta` MeV to every total energy, stopping at zero rather than going
+/// negative so the first-of-history marker carried in the sign is kept.
+pub fn shift_energy(input_path: &Path, output_path: &Path, delta: f32) -> EGSResult<()> {
+    rewrite(input_path, output_path, |record| {
+        let energy = (record.total_energy() + delta).max(0.0);
+        record.set_total_energy(energy);
+        true
+    })
+}
+
+/// Reverse the z direction of every particle, as if the beam ran the other
+/// way. With `negate_zlast` the zlast of MODE2 files is negated too, MODE0
+/// files have none so only the weight sign flips. Flipping twice gives back
+/// the original file.
+pub fn flip_z(input_path: &Path, output_path: &Path, negate_zlast: bool) -> EGSResult<()> {
+    rewrite(input_path, output_path, |record| {
+        let positive = record.z_positive();
+        record.set_z_direction(!positive);
+        if negate_zlast {
+            record.zlast = record.zlast.map(|zlast| -zlast);
+        }
+        true
+    })
+}
+
+/// Give every zero weight the smallest positive `f32` instead, so it has a sign
+/// that isn't lost on the way through tools that take `abs()` or `signum()`.
+/// The direction is `positive_z` when given, otherwise the sign the zero was
+/// stored with. Returns the number of records repaired.
+pub fn repair_weights(input_path: &Path,
+                      output_path: &Path,
+                      positive_z: Option<bool>)
+                      -> EGSResult<u64> {
+    let mut repaired = 0;
+    rewrite(input_path, output_path, |record| {
+        if record.weight == 0.0 {
+            let positive = positive_z.unwrap_or(record.z_positive());
+            record.weight = f32::MIN_POSITIVE;
+            record.set_z_direction(positive);
+            repaired += 1;
+        }
+        true
+    })?;
+    Ok(repaired)
+}
+
+/// Resample `input` to exactly `target` equally weighted particles, each record
+/// picked with probability in proportion to its weight and repeated when
+/// picked more than once, which keeps the total weight. Records of zero weight
+/// are never picked. Picks are systematic (evenly spaced through the cumulative
+/// weight from one random offset), so the output keeps the input's order and
+/// takes two streaming passes. With no weight at all this fails with `ZeroWeight`,
+/// a `target` of zero with `InvalidArgument`.
+pub fn resample_to(input_path: &Path,
+                   output_path: &Path,
+                   target: usize,
+                   seed: u64)
+                   -> EGSResult<()> {
+    if target == 0 {
+        return Err(EGSError::InvalidArgument("cannot resample to zero particles"));
+    }
+    let mut total_weight = 0.0f64;
+    for record in PHSPReader::open(input_path)? {
+        total_weight += record?.get_weight() as f64;
+    }
+    if total_weight == 0.0 {
+        return Err(EGSError::ZeroWeight);
+    }
+    let step = total_weight / target as f64;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let offset = rng.gen::<f64>() * step;
+    let reader = PHSPReader::open(input_path)?;
+    let mut writer = AtomicWriter::create(output_path, &reader.header)?;
+    let mut picked = 0;
+    let mut cumulative = 0.0f64;
+    let mut last = None;
+    for record in reader {
+        let mut record = record?;
+        if record.get_weight() == 0.0 {
+            continue;
+        }
+        cumulative += record.get_weight() as f64;
+        record.set_weight(step as f32);
+        while picked < target && offset + picked as f64 * step < cumulative {
+            writer.write(&record)?;
+            picked += 1;
+        }
+        last = Some(record);
+    }
+    // rounding in the cumulative sum can leave the final pick just past the end
+    if let Some(record) = last {
+        while picked < target {
+            writer.write(&record)?;
+            picked += 1;
+        }
+    }
+    writer.finalize()
+}
+
+// Per cell tallies for `grid_thin`.
+#[derive(Default)]
+struct ThinCell {
+    records: u64,
+    weight: f64,
+    seen: u64,
+    kept: u64,
+    kept_weight: f64,
+}
+
+/// Thin out crowded parts of the x/y plane. The plane is cut into square cells
+/// `cell_size` cm wide and a cell holding more than `max_per_cell` particles
+/// keeps that many of them, picked at random, with their weights scaled so the
+/// cell's total weight is unchanged. Cells at or under the cap and records with
+/// a NaN position are copied as they are. Takes three streaming passes, the
+/// second and third make the same picks from `seed`. A `cell_size` that isn't
+/// positive and finite, or a `max_per_cell` of zero, fails with `InvalidArgument`.
+pub fn grid_thin(input_path: &Path,
+                 output_path: &Path,
+                 cell_size: f32,
+                 max_per_cell: u64,
+                 seed: u64)
+                 -> EGSResult<()> {
+    if !cell_size.is_finite() || cell_size <= 0.0 {
+        return Err(EGSError::InvalidArgument("the cell size must be positive"));
+    } else if max_per_cell == 0 {
+        return Err(EGSError::InvalidArgument("cannot keep zero particles per cell"));
+    }
+    let cell_of = |record: &Record| -> Option<(i64, i64)> {
+        if record.x_cm.is_nan() || record.y_cm.is_nan() {
+            None
+        } else {
+            let cell = |position: f32| (position / cell_size).floor() as i64;
+            Some((cell(record.x_cm), cell(record.y_cm)))
+        }
+    };
+    let mut cells: HashMap<(i64, i64), ThinCell> = HashMap::new();
+    for record in PHSPReader::open(input_path)? {
+        let record = record?;
+        if let Some(key) = cell_of(&record) {
+            let cell = cells.entry(key).or_default();
+            cell.records += 1;
+            cell.weight += record.get_weight() as f64;
+        }
+    }
+    // selection sampling, each record of a full cell is kept with the chance
+    // of filling the places left from the records left, which keeps exactly
+    // `max_per_cell` of them
+    let pick = |cell: &mut ThinCell, rng: &mut StdRng| -> bool {
+        let keep = rng.gen_range(0..cell.records - cell.seen) < max_per_cell - cell.kept;
+        cell.seen += 1;
+        if keep {
+            cell.kept += 1;
+        }
+        keep
+    };
+    let mut rng = StdRng::seed_from_u64(seed);
+    for record in PHSPReader::open(input_path)? {
+        let record = record?;
+        if let Some(cell) = cell_of(&record).and_then(|key| cells.get_mut(&key)) {
+            if cell.records > max_per_cell && pick(cell, &mut rng) {
+                cell.kept_weight += record.get_weight() as f64;
+            }
+        }
+    }
+    for cell in cells.values_mut() {
+        cell.seen = 0;
+        cell.kept = 0;
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    rewrite(input_path, output_path, |record| {
+        let cell = match cell_of(record).and_then(|key| cells.get_mut(&key)) {
+            Some(cell) if cell.records > max_per_cell => cell,
+            _ => return true,
+        };
+        if !pick(cell, &mut rng) {
+            return false;
+        }
+        if cell.kept_weight > 0.0 {
+            let scale = cell.weight / cell.kept_weight;
+            record.set_weight((record.get_weight() as f64 * scale) as f32);
+        }
+        true
+    })
+}
+
+/// Rewrite `input` with `mode`, `MODE0` or `MODE2`, otherwise `BadMode`.
+/// Records gaining a zlast get `zlast` cm, ones losing it just drop it; the
+/// rest of every record and the header counts are kept as they are.
+pub fn convert_mode(input_path: &Path,
+                    output_path: &Path,
+                    mode: [u8; MODE_LENGTH],
+                    zlast: f32)
+                    -> EGSResult<()> {
+    let reader = PHSPReader::open(input_path)?;
+    let mut header = reader.header;
+    header.record_size = record_size_for(&mode)?;
+    header.using_zlast = &mode == b"MODE2";
+    header.mode = mode;
+    let mut writer = AtomicWriter::create(output_path, &header)?;
+    for record in reader {
+        let mut record = record?;
+        record.zlast = if header.using_zlast { record.zlast.or(Some(zlast)) } else { None };
+        writer.write(&record)?;
+    }
+    writer.commit()
+}
+
+/// Thin out low weight particles by Russian roulette: a record whose weight is
+/// below `threshold` survives with probability `weight / threshold` and then
+/// carries `threshold`, heavier records pass through as they are. The expected
+/// total weight, and so the fluence, is unchanged. Header counts and energy
+/// extents are recomputed for the survivors. A `threshold` that isn't positive
+/// and finite fails with `InvalidArgument`.
+pub fn russian_roulette(input_path: &Path,
+                        output_path: &Path,
+                        threshold: f32,
+                        seed: u64)
+                        -> EGSResult<()> {
+    if !threshold.is_finite() || threshold <= 0.0 {
+        return Err(EGSError::InvalidArgument("the roulette threshold must be positive"));
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    rewrite(input_path, output_path, |record| {
+        let weight = record.get_weight();
+        if weight >= threshold {
+            true
+        } else if rng.gen::<f32>() * threshold < weight {
+            record.set_weight(threshold);
+            true
+        } else {
+            false
+        }
+    })
+}
+
+/// Sample by energy band, each of `bands` being `(min, max, rate)`: a record
+/// with `min <= total_energy() < max` is kept with probability `1 / rate`. A
+/// record in overlapping bands goes by the first that holds it, one in none is
+/// dropped. Unlike `sample` the number of histories is left alone and every
+/// kept record's weight is multiplied by its band's rate instead, so each band
+/// keeps its fluence whatever the mix of rates. Header counts and energy
+/// extents are recomputed for what is kept. No bands, or a band with a rate of
+/// 0, fails with `InvalidArgument`.
+pub fn sample_stratified(input_path: &Path,
+                         output_path: &Path,
+                         bands: &[(f32, f32, u32)],
+                         seed: u64)
+                         -> EGSResult<()> {
+    if bands.is_empty() {
+        return Err(EGSError::InvalidArgument("cannot sample into zero energy bands"));
+    } else if bands.iter().any(|&(_, _, rate)| rate == 0) {
+        return Err(EGSError::InvalidArgument("sample rates must be at least 1"));
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    rewrite(input_path, output_path, |record| {
+        let energy = record.total_energy();
+        match bands.iter().find(|&&(min, max, _)| min <= energy && energy < max) {
+            Some(&(_, _, rate)) if rng.gen_ratio(1, rate) => {
+                let weight = record.get_weight() * rate as f32;
+                record.set_weight(weight);
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+pub fn transform(input_path: &Path, output_path: &Path, matrix: &[[f32; 3]; 3]) -> EGSResult<()> {
+    transform_with_progress(input_path, output_path, matrix, false, |_, _| ())
+}
+
+/// `transform` calling `progress(records done, records in total)` after every record.
+/// With `dry_run` the records are read and transformed but nothing is written.
+pub fn transform_with_progress<P>(input_path: &Path,
+                                  output_path: &Path,
+                                  matrix: &[[f32; 3]; 3],
+                                  dry_run: bool,
+                                  mut progress: P)
+                                  -> EGSResult<()>
+    where P: FnMut(u64, u64)
+{
+    if input_path == output_path && !is_gzip(input_path) {
+        println!("Transforming {} in place", input_path.display());
+        let (records_transformed, n_particles) =
+            transform_in_place(input_path, matrix, dry_run, &mut progress)?;
+        println!("Transformed {} records, expected {}",
+                 records_transformed,
+                 n_particles);
+        if dry_run {
+            println!("Dry run, {} was left as it is", input_path.display());
+        }
+        return Ok(());
+    }
+    let reader = PHSPReader::open(input_path)?;
+    if input_path == output_path {
+        // compressed files can't be rewritten where they lie, the original is
+        // only replaced once the transformed copy is complete
+        println!("Transforming {} in place", input_path.display());
+    } else {
+        println!("Transforming {} and saving to {}",
+                 input_path.display(),
+                 output_path.display());
+    }
+    let mut writer = AtomicWriter::create_or_discard(output_path, &reader.header, dry_run)?;
+    let n_particles = reader.header.total_particles;
+    let total = n_particles.max(0) as u64;
+    let mut records_transformed = 0;
+    for record in reader {
+        let mut record = record?;
+        record.transform(matrix);
+        writer.write(&record)?;
+        records_transformed += 1;
+        progress(records_transformed, total);
+    }
+    writer.commit()?;
     println!("Transformed {} records, expected {}",
              records_transformed,
              n_particles);
+    if dry_run {
+        println!("Dry run, nothing was written to {}", output_path.display());
+    }
     Ok(())
 }
+
+// Transforms keep the record size, so each block of records is read, transformed
+// and written back over itself. The header is left alone. Interrupting this
+// leaves the file partly transformed.
+fn transform_in_place<P>(path: &Path,
+                         matrix: &[[f32; 3]; 3],
+                         dry_run: bool,
+                         progress: &mut P)
+                         -> EGSResult<(u64, i32)>
+    where P: FnMut(u64, u64)
+{
+    let mut file = OpenOptions::new().read(true).write(!dry_run).open(path)?;
+    let reader = PHSPReader::from(file.try_clone()?)?;
+    let (header, order) = (reader.header, reader.byte_order());
+    let record_size = header.record_size as usize;
+    let mut buffer = vec![0; BUFFER_CAPACITY / record_size * record_size];
+    let mut offset = header.record_size;
+    let end = header.record_size * (header.total_particles.max(0) as u64 + 1);
+    let total = header.total_particles.max(0) as u64;
+    let mut records_transformed = 0;
+    while offset < end {
+        let length = buffer.len().min((end - offset) as usize);
+        let block = &mut buffer[..length];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(block)?;
+        for bytes in block.chunks_mut(record_size) {
+            let mut record = Record::decode(bytes, header.using_zlast, order);
+            record.transform(matrix);
+            bytes.copy_from_slice(&record.encode(header.using_zlast, order)[..record_size]);
+            records_transformed += 1;
+            progress(records_transformed, total);
+        }
+        if !dry_run {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(block)?;
+        }
+        offset += length as u64;
+    }
+    file.flush()?;
+    Ok((records_transformed, header.total_particles))
+}
@@ -0,0 +1,2998 @@
+use super::*;
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+// A fresh directory under the system temp dir, removed with what is in it on drop.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> TempDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!("egsphsp-test-{}-{}",
+                                                process::id(),
+                                                NEXT.fetch_add(1, AtomicOrdering::SeqCst)));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// A photon heading towards +z with unit weight, energy as stored on disk.
+fn photon(energy: f32, x_cm: f32, y_cm: f32) -> Record {
+    Record::new(0, energy, x_cm, y_cm, 0.0, 0.0, 1.0)
+}
+
+// An electron or a positron heading towards +z with unit weight.
+fn charged(energy: f32, positron: bool) -> Record {
+    let latch = if positron { 1 << 29 } else { 1 << 30 };
+    Record::new(latch, energy, 0.0, 0.0, 0.6, 0.0, 1.0)
+}
+
+// `count` records with distinct energies and positions, every third charged.
+fn records(count: usize) -> Vec<Record> {
+    (0..count)
+        .map(|i| {
+            let mut record = photon(1.0 + i as f32 * 0.01, i as f32 * 0.1, -(i as f32) * 0.05);
+            record.set_charged(i % 3 == 2);
+            record
+        })
+        .collect()
+}
+
+// Write `records` to a MODE0 file at `path` from `histories` histories.
+fn write_file(path: &Path, records: &[Record], histories: f32) -> Header {
+    let header = Header::new_mode0(0, 0, histories).unwrap();
+    let mut writer = PHSPWriter::create(path, &header).unwrap();
+    for record in records.iter() {
+        writer.write(record).unwrap();
+    }
+    writer.finalize().unwrap();
+    PHSPReader::open(path).unwrap().header
+}
+
+// The phase space checked in next to Cargo.toml.
+fn fixture() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("sample.egsphsp1")
+}
+
+fn read_file(path: &Path) -> Vec<Record> {
+    PHSPReader::open(path).unwrap().map(|record| record.unwrap()).collect()
+}
+
+// `write_file` for MODE2, every record last crossing z = 1.
+fn write_mode2_file(path: &Path, records: &[Record], histories: f32) -> Header {
+    let header = Header::new_mode2(0, 0, histories).unwrap();
+    let mut writer = PHSPWriter::create(path, &header).unwrap();
+    for record in records.iter() {
+        writer.write(&Record { zlast: Some(1.0), ..*record }).unwrap();
+    }
+    writer.finalize().unwrap();
+    PHSPReader::open(path).unwrap().header
+}
+
+#[test]
+fn mode0_header() {
+    let header = Header::new_mode0(3, 2, 10.0).unwrap();
+    assert_eq!(&header.mode, b"MODE0");
+    assert_eq!(header.record_size, 28);
+    assert!(!header.using_zlast);
+    assert_eq!(header.expected_file_size(), 4 * 28);
+}
+
+#[test]
+fn mode2_header_uses_zlast() {
+    let header = Header::new_mode2(3, 2, 10.0).unwrap();
+    assert_eq!(&header.mode, b"MODE2");
+    assert_eq!(header.record_size, 32);
+    assert!(header.using_zlast);
+    assert_eq!(header.expected_file_size(), 4 * 32);
+}
+
+#[test]
+fn header_rejects_contradictory_counts() {
+    assert!(matches!(Header::new_mode0(1, 2, 1.0), Err(EGSError::BadHeader)));
+    assert!(matches!(Header::new_mode0(-1, 0, 1.0), Err(EGSError::BadHeader)));
+    assert!(matches!(Header::new_mode2(1, 0, -1.0), Err(EGSError::BadHeader)));
+}
+
+#[test]
+fn for_each_record_visits_every_record() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(5), 5.0);
+    let mut count = 0;
+    let result = for_each_record(PHSPReader::open(&path).unwrap(), |_| {
+        count += 1;
+        Ok(())
+    });
+    assert!(result.is_ok());
+    assert_eq!(count, 5);
+}
+
+#[test]
+fn for_each_record_stops_at_an_error() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(5), 5.0);
+    let mut count = 0;
+    let result = for_each_record(PHSPReader::open(&path).unwrap(), |_| {
+        count += 1;
+        if count == 2 { Err(EGSError::ZeroWeight) } else { Ok(()) }
+    });
+    assert!(matches!(result, Err(EGSError::ZeroWeight)));
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn truncated_file_reports_where_it_ends() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(5), 5.0);
+    // the header still counts five records, two are left
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..3 * 28]).unwrap();
+    let mut reader = PHSPReader::open(&path).unwrap();
+    assert!(reader.next().unwrap().is_ok());
+    assert!(reader.next().unwrap().is_ok());
+    match reader.next() {
+        Some(Err(EGSError::ReadFailed { record, offset, .. })) => {
+            assert_eq!(record, 2);
+            assert_eq!(offset, 3 * 28);
+        }
+        other => panic!("expected ReadFailed, got {:?}", other),
+    }
+}
+
+// Hands out `limit` bytes of `bytes`, then fails.
+struct FailingReader {
+    bytes: Cursor<Vec<u8>>,
+    limit: u64,
+}
+
+impl Read for FailingReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let left = self.limit.saturating_sub(self.bytes.position()) as usize;
+        if left == 0 {
+            return Err(io::Error::other("disk on fire"));
+        }
+        let length = buffer.len().min(left);
+        self.bytes.read(&mut buffer[..length])
+    }
+}
+
+#[test]
+fn read_error_reports_the_offset_reached() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(5), 5.0);
+    let bytes = Cursor::new(fs::read(&path).unwrap());
+    // ten bytes into the third record
+    let mut reader = PHSPReader::from_reader(FailingReader { bytes, limit: 3 * 28 + 10 }).unwrap();
+    match reader.nth(2) {
+        Some(Err(EGSError::ReadFailed { record, offset, .. })) => {
+            assert_eq!(record, 2);
+            assert_eq!(offset, 3 * 28 + 10);
+        }
+        other => panic!("expected ReadFailed, got {:?}", other),
+    }
+}
+
+#[test]
+fn combine_rejects_mixed_modes() {
+    let dir = TempDir::new();
+    let mode0 = dir.path("mode0.egsphsp1");
+    let mode2 = dir.path("mode2.egsphsp2");
+    let output = dir.path("out.egsphsp1");
+    write_file(&mode0, &records(3), 3.0);
+    write_mode2_file(&mode2, &records(3), 3.0);
+    let result = combine(&[&mode0, &mode2], &output, false);
+    assert!(matches!(result, Err(EGSError::ModeMismatch)));
+    assert!(!output.exists());
+}
+
+#[test]
+fn headers_of_other_modes_are_incompatible() {
+    let mode0 = Header::new_mode0(1, 1, 1.0).unwrap();
+    let mode2 = Header::new_mode2(1, 1, 1.0).unwrap();
+    assert!(mode0.compatible_with(&mode0).is_ok());
+    assert!(matches!(mode0.compatible_with(&mode2), Err(EGSError::ModeMismatch)));
+    let mut odd = mode0;
+    odd.using_zlast = true;
+    assert!(matches!(mode0.compatible_with(&odd), Err(EGSError::HeaderMismatch)));
+}
+
+#[test]
+fn parsed_ops_match_manual_composition() {
+    let mut parsed = [[0.0; 3]; 3];
+    Transform::parse(&mut parsed, "rotate:1.57,translate:2,0,scale:1,-1").unwrap();
+    let mut manual = [[0.0; 3]; 3];
+    let mut step = [[0.0; 3]; 3];
+    Transform::rotation(&mut manual, 1.57);
+    Transform::translation(&mut step, 2.0, 0.0);
+    Transform::compose(&mut manual, &step);
+    Transform::scale(&mut step, 1.0, -1.0);
+    Transform::compose(&mut manual, &step);
+    assert_eq!(parsed, manual);
+}
+
+#[test]
+fn parse_rejects_unknown_ops_and_arities() {
+    let mut matrix = [[0.0; 3]; 3];
+    assert!(matches!(Transform::parse(&mut matrix, "shear:1"), Err(EGSError::BadTransform(_))));
+    assert!(matches!(Transform::parse(&mut matrix, "rotate:1,2"), Err(EGSError::BadTransform(_))));
+    assert!(matches!(Transform::parse(&mut matrix, "translate:x,0"),
+                     Err(EGSError::BadTransform(_))));
+}
+
+#[test]
+fn combine_refuses_to_overwrite_an_input() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let other = dir.path("other.egsphsp1");
+    write_file(&input, &records(3), 3.0);
+    write_file(&other, &records(2), 2.0);
+    let before = fs::read(&input).unwrap();
+    let roundabout = dir.0.join(".").join("in.egsphsp1");
+    for output in [&input, &roundabout].iter() {
+        let result = combine(&[&input, &other], output, true);
+        assert!(matches!(result, Err(EGSError::OutputIsInput(_))));
+    }
+    assert_eq!(fs::read(&input).unwrap(), before);
+    assert!(other.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn combine_sees_through_symlinks_to_an_input() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let link = dir.path("link.egsphsp1");
+    write_file(&input, &records(3), 3.0);
+    std::os::unix::fs::symlink(&input, &link).unwrap();
+    let result = combine(&[&input], &link, false);
+    assert!(matches!(result, Err(EGSError::OutputIsInput(_))));
+    assert_eq!(read_file(&input).len(), 3);
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_round_trip() {
+    let dir = TempDir::new();
+    let path = dir.path("out.egsphsp1.gz");
+    let written = records(50);
+    // the header can't be patched afterwards, so it has to be right up front
+    let header = Header::new_mode0(50, 34, 50.0).unwrap();
+    let mut writer = PHSPWriter::create(&path, &header).unwrap();
+    for record in written.iter() {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    assert_eq!(&fs::read(&path).unwrap()[..2], &[0x1f, 0x8b]);
+    let mut reader = PHSPReader::open(&path).unwrap();
+    assert_eq!(reader.header.total_particles, 50);
+    assert!(matches!(reader.seek_to_record(0), Err(EGSError::Unsupported(_))));
+    let read: Vec<Record> = reader.map(|record| record.unwrap()).collect();
+    assert_eq!(read, written);
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_header_cannot_be_finalized() {
+    let dir = TempDir::new();
+    let path = dir.path("out.egsphsp1.gz");
+    let mut writer = PHSPWriter::create(&path, &Header::new_mode0(0, 0, 1.0).unwrap()).unwrap();
+    writer.write(&photon(1.0, 0.0, 0.0)).unwrap();
+    assert!(matches!(writer.finalize(), Err(EGSError::Unsupported(_))));
+}
+
+#[test]
+fn trailing_partial_record_is_an_error() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(3), 3.0);
+    let mut bytes = fs::read(&path).unwrap();
+    bytes.extend_from_slice(&[0; 12]);
+    fs::write(&path, &bytes).unwrap();
+    match PHSPReader::open(&path) {
+        Err(EGSError::PartialRecord { record, bytes }) => {
+            assert_eq!(record, 3);
+            assert_eq!(bytes, 12);
+        }
+        other => panic!("expected PartialRecord, got {:?}", other.map(|r| r.header)),
+    }
+}
+
+#[test]
+fn file_shorter_than_a_header_is_an_error() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &[], 0.0);
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..26]).unwrap();
+    assert!(matches!(PHSPReader::open(&path).map(|r| r.header), Err(EGSError::BadLength)));
+}
+
+#[test]
+fn angular_smear_with_zero_sigma_changes_nothing() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&input, &records(20), 20.0);
+    angular_smear(&input, &output, 0.0, 1).unwrap();
+    assert_eq!(read_file(&output), read_file(&input));
+}
+
+#[test]
+fn angular_smear_keeps_cosines_normalised() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let tilted: Vec<Record> = records(200)
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut record)| {
+            let angle = i as f32 * 0.1;
+            record.x_cos = 0.7 * angle.cos();
+            record.y_cos = 0.7 * angle.sin();
+            record
+        })
+        .collect();
+    write_file(&input, &tilted, 200.0);
+    angular_smear(&input, &output, 0.5, 7).unwrap();
+    let smeared = read_file(&output);
+    assert_eq!(smeared.len(), tilted.len());
+    for (before, after) in tilted.iter().zip(smeared.iter()) {
+        assert!(after.x_cos * after.x_cos + after.y_cos * after.y_cos <= 1.0);
+        assert!(!after.z_cos().is_nan());
+        assert_eq!(after.total_energy(), before.total_energy());
+        assert_eq!((after.x_cm, after.y_cm), (before.x_cm, before.y_cm));
+        assert_eq!(after.get_weight(), before.get_weight());
+    }
+    assert!(smeared.iter().zip(tilted.iter()).any(|(a, b)| a.x_cos != b.x_cos));
+}
+
+#[test]
+fn finalize_writes_the_counts_it_tallied() {
+    let dir = TempDir::new();
+    let path = dir.path("out.egsphsp1");
+    let mut writer = PHSPWriter::create(&path, &Header::new_mode0(0, 0, 42.0).unwrap()).unwrap();
+    for record in records(10).iter() {
+        writer.write(record).unwrap();
+    }
+    writer.finalize().unwrap();
+    let header = PHSPReader::open(&path).unwrap().header;
+    assert_eq!(header.total_particles, 10);
+    assert_eq!(header.total_photons, 7);
+    assert_eq!(header.min_energy, 1.0);
+    assert_eq!(header.max_energy, 1.0 + 9.0 * 0.01);
+    assert_eq!(header.total_particles_in_source, 42.0);
+    assert_eq!(fs::metadata(&path).unwrap().len(), header.expected_file_size());
+}
+
+#[test]
+fn fixture_incident_histories() {
+    let header = PHSPReader::open(&fixture()).unwrap().header;
+    assert_eq!(header.incident_histories(), header.total_particles_in_source);
+    assert!((header.incident_histories() - 166666.6).abs() < 0.1);
+    let scanned = scan(&fixture()).unwrap();
+    assert_eq!(scanned.records, 10687);
+    assert_eq!(scanned.first_histories, 10298);
+    assert!(scanned.first_histories as f32 <= header.incident_histories());
+}
+
+#[test]
+fn scan_counts_first_history_markers() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let marked: Vec<Record> = [-1.0, 2.0, -3.0, -4.0, 5.0].iter()
+        .map(|&energy| photon(energy, 0.0, 0.0))
+        .collect();
+    let header = write_file(&path, &marked, 10.0);
+    assert_eq!(header.incident_histories(), 10.0);
+    assert_eq!(scan(&path).unwrap().first_histories, 3);
+}
+
+#[test]
+fn writer_tallies_match_a_manual_count() {
+    let written = [photon(2.0, 0.0, 0.0),
+                   charged(-5.0, false),
+                   photon(0.5, 1.0, 1.0),
+                   charged(3.0, true),
+                   photon(-7.0, 0.0, 0.0),
+                   charged(1.5, false)];
+    let mut writer = PHSPWriter::in_memory(&Header::new_mode0(0, 0, 6.0).unwrap()).unwrap();
+    assert_eq!(writer.min_energy_written(), None);
+    for record in written.iter() {
+        writer.write(record).unwrap();
+    }
+    let photons = written.iter().filter(|r| r.particle_type() == ParticleType::Photon).count();
+    assert_eq!(writer.particles_written(), written.len() as u64);
+    assert_eq!(writer.photons_written(), photons as u64);
+    // records marking a new history are stored negative and left out of the extents
+    assert_eq!(writer.min_energy_written(), Some(0.5));
+    assert_eq!(writer.max_energy_written(), Some(3.0));
+}
+
+#[test]
+fn split_then_combine_gives_back_the_records() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let combined = dir.path("combined.egsphsp1");
+    write_file(&input, &records(10), 100.0);
+    let parts = split(&input, &dir.path("part"), 3).unwrap();
+    assert_eq!(parts, vec![dir.path("part_1.egsphsp1"),
+                           dir.path("part_2.egsphsp1"),
+                           dir.path("part_3.egsphsp1")]);
+    // the remainder goes to the first chunk
+    let counts: Vec<i32> = parts.iter()
+        .map(|path| PHSPReader::open(path).unwrap().header.total_particles)
+        .collect();
+    assert_eq!(counts, vec![4, 3, 3]);
+    let paths: Vec<&Path> = parts.iter().map(|path| path.as_path()).collect();
+    combine(&paths, &combined, false).unwrap();
+    assert_eq!(read_file(&combined), read_file(&input));
+    let header = PHSPReader::open(&combined).unwrap().header;
+    assert!((header.total_particles_in_source - 100.0).abs() < 1e-3);
+}
+
+#[test]
+fn split_recomputes_each_header() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let written = records(6);
+    write_file(&input, &written, 6.0);
+    let parts = split(&input, &dir.path("part"), 2).unwrap();
+    for (path, chunk) in parts.iter().zip(written.chunks(3)) {
+        let header = PHSPReader::open(path).unwrap().header;
+        assert_eq!(header.total_particles, 3);
+        assert_eq!(header.total_photons, chunk.iter().filter(|r| !r.charged()).count() as i32);
+        assert_eq!(header.min_energy, chunk[0].total_energy());
+        assert_eq!(header.max_energy, chunk[2].total_energy());
+        assert_eq!(read_file(path), chunk);
+    }
+}
+
+#[test]
+fn split_into_zero_parts_is_an_error() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    write_file(&input, &records(3), 3.0);
+    let result = split(&input, &dir.path("part"), 0);
+    assert!(matches!(result, Err(EGSError::InvalidArgument(_))));
+}
+
+#[test]
+fn failed_combine_leaves_no_output() {
+    let dir = TempDir::new();
+    let good = dir.path("good.egsphsp1");
+    let truncated = dir.path("truncated.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&good, &records(5), 5.0);
+    write_file(&truncated, &records(5), 5.0);
+    let bytes = fs::read(&truncated).unwrap();
+    fs::write(&truncated, &bytes[..3 * 28]).unwrap();
+    // fails after the whole of `good` has gone out
+    let result = combine(&[&good, &truncated], &output, false);
+    assert!(matches!(result, Err(EGSError::ReadFailed { .. })));
+    assert!(!output.exists());
+    assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 2);
+}
+
+#[test]
+fn dropped_atomic_writer_removes_its_temporary_file() {
+    let dir = TempDir::new();
+    let output = dir.path("out.egsphsp1");
+    {
+        let mut writer = AtomicWriter::create(&output, &Header::new_mode0(0, 0, 1.0).unwrap())
+            .unwrap();
+        writer.write(&photon(1.0, 0.0, 0.0)).unwrap();
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 1);
+    }
+    assert!(!output.exists());
+    assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 0);
+}
+
+#[test]
+fn committed_atomic_writer_moves_into_place() {
+    let dir = TempDir::new();
+    let output = dir.path("out.egsphsp1");
+    let mut writer = AtomicWriter::create(&output, &Header::new_mode0(0, 0, 1.0).unwrap()).unwrap();
+    writer.write(&photon(1.0, 0.0, 0.0)).unwrap();
+    assert!(!output.exists());
+    writer.finalize().unwrap();
+    assert_eq!(read_file(&output), vec![photon(1.0, 0.0, 0.0)]);
+    assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 1);
+}
+
+#[test]
+fn sample_exact_writes_exactly_count_records() {
+    let dir = TempDir::new();
+    let first = dir.path("first.egsphsp1");
+    let second = dir.path("second.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let written = records(60);
+    write_file(&first, &written[..40], 40.0);
+    write_file(&second, &written[40..], 20.0);
+    sample_exact(&[&first, &second], &output, 25, 3).unwrap();
+    let sampled = read_file(&output);
+    assert_eq!(sampled.len(), 25);
+    assert_eq!(PHSPReader::open(&output).unwrap().header.total_particles, 25);
+    // every record is a distinct input record, kept in input order
+    let indices: Vec<usize> = sampled.iter()
+        .map(|record| written.iter().position(|r| r == record).unwrap())
+        .collect();
+    assert!(indices.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn sample_exact_is_reproducible_for_a_seed() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    write_file(&input, &records(100), 100.0);
+    let mut outputs = Vec::new();
+    for (name, seed) in [("a", 11), ("b", 11), ("c", 12)].iter() {
+        let output = dir.path(name);
+        sample_exact(&[&input], &output, 10, *seed).unwrap();
+        outputs.push(read_file(&output));
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert_ne!(outputs[0], outputs[2]);
+}
+
+#[test]
+fn sample_exact_copies_short_inputs_whole() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&input, &records(5), 5.0);
+    sample_exact(&[&input], &output, 50, 1).unwrap();
+    assert_eq!(read_file(&output), read_file(&input));
+}
+
+#[test]
+fn clamp_radius_drops_records_outside() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let written = [photon(1.0, 1.0, 0.0), photon(2.0, 3.0, 4.0), photon(3.0, 0.0, -2.0)];
+    write_file(&input, &written, 3.0);
+    clamp_radius(&input, &output, 2.5, ClampMode::Discard).unwrap();
+    assert_eq!(read_file(&output), vec![written[0], written[2]]);
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(header.total_particles, 2);
+    assert_eq!(header.total_photons, 2);
+    assert_eq!(header.max_energy, 3.0);
+}
+
+#[test]
+fn clamp_radius_moves_records_onto_the_edge() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let written = [photon(1.0, 1.0, 0.0), photon(2.0, 3.0, 4.0), photon(3.0, -6.0, 8.0)];
+    write_file(&input, &written, 3.0);
+    clamp_radius(&input, &output, 2.5, ClampMode::Clamp).unwrap();
+    let clamped = read_file(&output);
+    assert_eq!(clamped.len(), 3);
+    assert_eq!(clamped[0], written[0]);
+    for (before, after) in written[1..].iter().zip(clamped[1..].iter()) {
+        assert!((after.radius() - 2.5).abs() < 1e-5);
+        // same angle, only pulled in
+        assert!((after.x_cm * before.y_cm - after.y_cm * before.x_cm).abs() < 1e-5);
+        assert!(after.x_cm * before.x_cm + after.y_cm * before.y_cm > 0.0);
+        assert_eq!(after.total_energy(), before.total_energy());
+    }
+}
+
+#[test]
+fn scan_counts_production_flags() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let flagged: Vec<Record> = [0, 1, 1 << 29, 1 | 1 << 29, 1, 0, 0]
+        .iter()
+        .map(|&latch| Record::new(latch, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0))
+        .collect();
+    write_file(&path, &flagged, 7.0);
+    let scanned = scan(&path).unwrap();
+    assert_eq!(scanned.records, 7);
+    assert_eq!(scanned.produced, 3);
+    assert_eq!(scanned.b29, 2);
+}
+
+#[test]
+fn fixture_production_flags() {
+    let scanned = scan(&fixture()).unwrap();
+    assert_eq!(scanned.produced, 10687);
+    assert_eq!(scanned.b29, 2);
+    assert_eq!(scanned.to_json(),
+               "{\"records\": 10687, \"first_histories\": 10298, \"produced\": 10687, \"b29\": 2}");
+}
+
+#[test]
+fn sample_with_a_seed_is_byte_identical() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let first = dir.path("first.egsphsp1");
+    let second = dir.path("second.egsphsp1");
+    write_file(&input, &records(500), 500.0);
+    sample(&[&input], &first, 10, 12345).unwrap();
+    sample(&[&input], &second, 10, 12345).unwrap();
+    let bytes = fs::read(&first).unwrap();
+    assert_eq!(bytes, fs::read(&second).unwrap());
+    let kept = read_file(&first).len();
+    assert!(kept > 0 && kept < 500);
+}
+
+#[test]
+fn header_diff_lists_each_differing_field() {
+    let a = Header::new_mode0(10, 4, 10.0).unwrap();
+    let mut b = a;
+    b.total_photons = 6;
+    b.max_energy = 2.5;
+    assert_eq!(a.diff(&b),
+               vec![("total_photons".to_string(), "4".to_string(), "6".to_string()),
+                    ("max_energy".to_string(), a.max_energy.to_string(), "2.5".to_string())]);
+    assert!(a.diff(&a).is_empty());
+}
+
+#[test]
+fn header_diff_ignores_float_noise() {
+    let a = Header::new_mode0(10, 4, 10.0).unwrap();
+    let mut b = a;
+    b.total_particles_in_source = f32::from_bits(a.total_particles_in_source.to_bits() + 1);
+    assert!(a.diff(&b).is_empty());
+}
+
+#[test]
+fn dedup_of_a_file_with_itself_is_the_file() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let doubled = dir.path("doubled.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&input, &records(40), 40.0);
+    combine(&[&input, &input], &doubled, false).unwrap();
+    assert_eq!(read_file(&doubled).len(), 80);
+    combine_dedup(&[&doubled], &output, false, None).unwrap();
+    assert_eq!(read_file(&output), read_file(&input));
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(header.total_particles, 40);
+    assert_eq!(header.total_photons, PHSPReader::open(&input).unwrap().header.total_photons);
+}
+
+#[test]
+fn bloom_dedup_drops_every_duplicate() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&input, &records(40), 40.0);
+    combine_dedup(&[&input, &input], &output, false, Some(1 << 16)).unwrap();
+    // false positives only ever drop more
+    let kept = read_file(&output).len();
+    assert!(kept <= 40 && kept > 30);
+}
+
+#[test]
+fn fresh_index_opens_the_file() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let index = dir.path("in.idx");
+    write_file(&input, &records(8), 8.0);
+    build_index(&input, &index).unwrap();
+    let mut reader = PHSPReader::with_index(File::open(&input).unwrap(), &index).unwrap();
+    reader.seek_to_record(5).unwrap();
+    assert_eq!(reader.next().unwrap().unwrap(), records(8)[5]);
+}
+
+#[test]
+fn stale_index_is_rejected() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let index = dir.path("in.idx");
+    write_file(&input, &records(8), 8.0);
+    build_index(&input, &index).unwrap();
+    // same length, one record changed
+    let mut bytes = fs::read(&input).unwrap();
+    bytes[3 * 28 + 5] ^= 1;
+    fs::write(&input, &bytes).unwrap();
+    let result = PHSPReader::with_index(File::open(&input).unwrap(), &index);
+    assert!(matches!(result.map(|r| r.header), Err(EGSError::StaleIndex("checksum"))));
+    write_file(&input, &records(9), 9.0);
+    let result = PHSPReader::with_index(File::open(&input).unwrap(), &index);
+    assert!(matches!(result.map(|r| r.header), Err(EGSError::StaleIndex("file length"))));
+    fs::write(&index, b"not an index, just some bytes that are long enough to read here").unwrap();
+    let result = PHSPReader::with_index(File::open(&input).unwrap(), &index);
+    assert!(matches!(result.map(|r| r.header), Err(EGSError::StaleIndex("format"))));
+}
+
+#[test]
+fn sample_by_type_keeps_each_species_at_its_rate() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let mixed: Vec<Record> = (0..4000)
+        .map(|i| if i % 2 == 0 { photon(1.0, 0.0, 0.0) } else { charged(1.0, false) })
+        .collect();
+    write_file(&input, &mixed, 4000.0);
+    sample_by_type(&[&input], &output, 2, 10, 5).unwrap();
+    let sampled = read_file(&output);
+    let photons = sampled.iter().filter(|r| r.particle_type() == ParticleType::Photon).count();
+    let electrons = sampled.len() - photons;
+    assert!((900..1100).contains(&photons), "{} photons", photons);
+    assert!((150..250).contains(&electrons), "{} electrons", electrons);
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(header.total_particles as usize, sampled.len());
+    assert_eq!(header.total_photons as usize, photons);
+}
+
+#[test]
+fn sample_by_type_rejects_a_zero_rate() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&input, &records(10), 10.0);
+    let result = sample_by_type(&[&input], &output, 2, 0, 5);
+    assert!(matches!(result, Err(EGSError::InvalidArgument(_))));
+    assert!(!output.exists());
+}
+
+#[test]
+fn validate_finds_a_corrupted_cosine() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let mut written = records(5);
+    written[3].x_cos = 0.8;
+    written[3].y_cos = 0.8;
+    write_file(&path, &written, 5.0);
+    let report = validate(&path).unwrap();
+    assert_eq!(report.bad_cosines, 1);
+    assert_eq!(report.non_finite, 0);
+    assert!(report.size_matches());
+    assert!(report.photons_match());
+    assert!(!report.is_valid());
+}
+
+#[test]
+fn validate_passes_a_clean_file() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(5), 5.0);
+    assert!(validate(&path).unwrap().is_valid());
+}
+
+#[test]
+fn validate_compares_fixture_photons_with_its_header() {
+    let report = validate(&fixture()).unwrap();
+    assert!(report.size_matches());
+    assert_eq!((report.header_photons, report.photons), (10625, 10623));
+    assert!(!report.is_valid());
+}
+
+#[test]
+fn identity_reweight_conserves_fluence() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&input, &records(20), 20.0);
+    let reader = PHSPReader::open(&input).unwrap();
+    let mut writer = PHSPWriter::create(&output, &reader.header).unwrap();
+    let ratio = reweight(reader, &mut writer, |record| record.get_weight()).unwrap();
+    writer.finalize().unwrap();
+    assert!((ratio - 1.0).abs() < FLUENCE_TOLERANCE);
+    assert_eq!(read_file(&output), read_file(&input));
+}
+
+#[test]
+fn reweight_reports_the_weight_ratio() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let mut written = records(4);
+    // heading towards -z, the sign has to survive
+    written[1].set_weight(-1.0);
+    write_file(&input, &written, 4.0);
+    let reader = PHSPReader::open(&input).unwrap();
+    let mut writer = PHSPWriter::create(&output, &reader.header).unwrap();
+    let ratio = reweight(reader, &mut writer, |record| record.get_weight().abs() * 3.0).unwrap();
+    writer.finalize().unwrap();
+    assert!((ratio - 3.0).abs() < 1e-9);
+    let reweighted = read_file(&output);
+    assert_eq!(reweighted[0].get_weight(), 3.0);
+    assert!(!reweighted[1].z_positive());
+}
+
+#[test]
+fn mode2_c_layout_lists_every_field_offset() {
+    let layout = c_layout(&Header::new_mode2(0, 0, 1.0).unwrap());
+    let record = &layout[layout.find("} egsphsp_mode2_header;").unwrap()..];
+    let fields = ["    uint32_t latch; /* offset 0 */",
+                  "    float total_energy; /* offset 4 */",
+                  "    float x_cm; /* offset 8 */",
+                  "    float y_cm; /* offset 12 */",
+                  "    float x_cos; /* offset 16 */",
+                  "    float y_cos; /* offset 20 */",
+                  "    float weight; /* offset 24 */",
+                  "    float zlast; /* offset 28 */",
+                  "} egsphsp_mode2_record;"];
+    for line in fields.iter() {
+        assert!(record.contains(line), "missing {:?} in\n{}", line, layout);
+    }
+    assert!(layout.contains("    char mode[5]; /* offset 0 */"));
+    assert!(layout.contains("    char padding[7]; /* offset 25 */"));
+}
+
+#[test]
+fn mode0_c_layout_has_no_zlast() {
+    let layout = c_layout(&Header::new_mode0(0, 0, 1.0).unwrap());
+    assert!(layout.contains("    float weight; /* offset 24 */"));
+    assert!(layout.contains("    char padding[3]; /* offset 25 */"));
+    assert!(!layout.contains("zlast"));
+}
+
+#[test]
+fn validated_rejects_cosines_past_one() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let mut written = records(4);
+    written[2].x_cos = 0.9;
+    written[2].y_cos = 0.6;
+    write_file(&path, &written, 4.0);
+    let mut validated = PHSPReader::open(&path).unwrap().validated();
+    assert!(validated.next().unwrap().is_ok());
+    assert!(validated.next().unwrap().is_ok());
+    assert!(matches!(validated.next(), Some(Err(EGSError::BadRecord(2)))));
+    assert!(validated.next().unwrap().is_ok());
+    // the plain iterator lets it through
+    let plain = read_file(&path);
+    assert!(plain[2].z_cos().is_nan());
+}
+
+#[test]
+fn iaea_header_counts_match_the_records() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let base = dir.path("out");
+    let written = [photon(2.0, 0.0, 0.0),
+                   charged(3.0, false),
+                   photon(1.0, 1.0, 1.0),
+                   charged(4.0, true),
+                   charged(5.0, false),
+                   photon(0.5, 0.0, 0.0)];
+    write_file(&input, &written, 4.0);
+    iaea::export_iaea(&input, &base).unwrap();
+    let text = fs::read_to_string(dir.path("out.IAEAheader")).unwrap();
+    let entries = ["$PARTICLES:\n6\n",
+                   "$PHOTONS:\n3\n",
+                   "$ELECTRONS:\n2\n",
+                   "$POSITRONS:\n1\n",
+                   "$ORIG_HISTORIES:\n4\n",
+                   "$RECORD_LENGTH:\n29\n",
+                   "$CHECKSUM:\n174\n"];
+    for entry in entries.iter() {
+        assert!(text.contains(entry), "missing {:?} in\n{}", entry, text);
+    }
+    let bytes = fs::read(dir.path("out.IAEAphsp")).unwrap();
+    assert_eq!(bytes.len(), 6 * 29);
+    // electron, kinetic energy
+    assert_eq!(bytes[29] as i8, 2);
+    let energy = LittleEndian::read_f32(&bytes[30..34]);
+    assert!((energy - (3.0 - ELECTRON_REST_MASS)).abs() < 1e-6);
+}
+
+#[test]
+fn iaea_mode2_records_carry_zlast() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp2");
+    let base = dir.path("out");
+    write_mode2_file(&input, &records(3), 3.0);
+    iaea::export_iaea(&input, &base).unwrap();
+    let text = fs::read_to_string(dir.path("out.IAEAheader")).unwrap();
+    assert!(text.contains("$RECORD_LENGTH:\n33\n"));
+    assert!(text.contains("ZLAST in extrafloat"));
+    let bytes = fs::read(dir.path("out.IAEAphsp")).unwrap();
+    assert_eq!(bytes.len(), 3 * 33);
+    assert_eq!(LittleEndian::read_f32(&bytes[25..29]), 1.0);
+}
+
+#[test]
+fn windows_overlap_by_size_less_step() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let written = records(10);
+    write_file(&path, &written, 10.0);
+    let windows: Vec<Vec<Record>> = PHSPReader::open(&path)
+        .unwrap()
+        .windows(4, 2)
+        .unwrap()
+        .map(|window| window.unwrap())
+        .collect();
+    assert_eq!(windows.len(), 4);
+    for (i, window) in windows.iter().enumerate() {
+        assert_eq!(window.as_slice(), &written[2 * i..2 * i + 4]);
+    }
+    assert_eq!(windows[0][2..], windows[1][..2]);
+}
+
+#[test]
+fn empty_windows_are_an_error() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(3), 3.0);
+    for &(size, step) in [(0, 1), (2, 0)].iter() {
+        let result = PHSPReader::open(&path).unwrap().windows(size, step);
+        assert!(matches!(result.map(|_| ()), Err(EGSError::InvalidArgument(_))));
+    }
+}
+
+#[test]
+fn peaks_finds_an_injected_annihilation_line() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let mut spectrum = read_file(&fixture());
+    let weight = spectrum.iter().map(|r| r.get_weight()).fold(0.0, f32::max);
+    for _ in 0..2000 {
+        let mut record = photon(0.511, 0.0, 0.0);
+        record.set_weight(weight);
+        spectrum.push(record);
+    }
+    write_file(&path, &spectrum, 166666.6);
+    let found = peaks(&path, 0.01, 0.25).unwrap();
+    assert!(found.iter().any(|peak| (peak.energy - 0.511).abs() < 0.01), "{:?}", found);
+    let tallest = found.iter().max_by(|a, b| a.height.partial_cmp(&b.height).unwrap()).unwrap();
+    assert!((tallest.energy - 0.515).abs() < 1e-4);
+    assert_eq!(tallest.prominence, 1.0);
+}
+
+#[test]
+fn peaks_rejects_a_bad_bin_width() {
+    for &width in [0.0, -1.0, f32::NAN].iter() {
+        assert!(matches!(peaks(&fixture(), width, 0.1), Err(EGSError::InvalidArgument(_))));
+    }
+}
+
+#[test]
+fn describe_labels_every_field() {
+    let mut record = Record::new(0, -1.25, 2.0, -3.0, 0.6, 0.0, -0.5);
+    record.set_region(3).unwrap();
+    record.set_charged(true);
+    record.set_bremsstrahlung_or_annihilation(true);
+    let text = record.describe();
+    let lines = ["latch:            0x43000001\n",
+                  "  particle:       Electron\n",
+                  "  region:         3\n",
+                  "  brems/annih:    true\n",
+                  "  multiple cross: false\n",
+                  "primary history:  true\n",
+                  "energy:           1.25 MeV\n",
+                  "x:                2 cm\n",
+                  "y:                -3 cm\n",
+                  "x_cos:            0.6\n",
+                  "z_cos:            -0.8\n",
+                  "weight:           0.5\n",
+                  "zlast:            -\n"];
+    for line in lines.iter() {
+        assert!(text.contains(line), "missing {:?} in\n{}", line, text);
+    }
+    let text = Record { zlast: Some(4.5), ..record }.describe();
+    assert!(text.contains("zlast:            4.5 cm\n"));
+}
+
+#[test]
+fn copy_records_copies_up_to_n() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let written = records(8);
+    write_file(&input, &written, 8.0);
+    let mut reader = PHSPReader::open(&input).unwrap();
+    let mut writer = PHSPWriter::create(&output, &reader.header).unwrap();
+    assert_eq!(copy_records(&mut reader, &mut writer, Some(5)).unwrap(), 5);
+    writer.finalize().unwrap();
+    assert_eq!(read_file(&output), &written[..5]);
+    // the rest are still there to read
+    assert_eq!(reader.records_remaining(), 3);
+    let mut sink = PHSPWriter::discard(&reader.header).unwrap();
+    assert_eq!(copy_records(&mut reader, &mut sink, None).unwrap(), 3);
+    assert_eq!(copy_records(&mut reader, &mut sink, None).unwrap(), 0);
+}
+
+#[test]
+fn only_io_errors_have_a_source() {
+    let io_error = EGSError::Io(io::Error::other("disk on fire"));
+    assert_eq!(io_error.source().unwrap().to_string(), "disk on fire");
+    assert_eq!(io_error.to_string(), "disk on fire");
+    let failed = EGSError::ReadFailed {
+        record: 1,
+        offset: 56,
+        err: io::Error::other("disk on fire"),
+    };
+    assert!(failed.source().is_some());
+    let others = [EGSError::BadMode,
+                  EGSError::ModeMismatch,
+                  EGSError::BadRecord(3),
+                  EGSError::InvalidArgument("no")];
+    for error in others.iter() {
+        assert!(error.source().is_none());
+    }
+}
+
+// Errors go through `?` into a boxed error like any other.
+fn header_of(path: &Path) -> Result<Header, Box<dyn Error>> {
+    Ok(PHSPReader::open(path)?.header)
+}
+
+#[test]
+fn errors_box_through_the_question_mark() {
+    let dir = TempDir::new();
+    assert_eq!(header_of(&fixture()).unwrap().total_particles, 10687);
+    let boxed = header_of(&dir.path("missing.egsphsp1")).unwrap_err();
+    match boxed.downcast_ref::<EGSError>() {
+        Some(EGSError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+        other => panic!("expected an Io error, got {:?}", other),
+    }
+}
+
+#[test]
+fn scan_breaks_down_provenance() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    // (latch, stored energy): bit 0 marks bremsstrahlung or annihilation and a
+    // negative energy the first particle of a history
+    let known = [(1, -1.0), (1, 2.0), (0, -3.0), (0, 4.0), (1, 5.0), (0, -6.0)];
+    let written: Vec<Record> = known.iter()
+        .map(|&(latch, energy)| Record::new(latch, energy, 0.0, 0.0, 0.0, 0.0, 1.0))
+        .collect();
+    write_file(&path, &written, 3.0);
+    let scanned = scan(&path).unwrap();
+    assert_eq!(scanned.records, 6);
+    assert_eq!(scanned.produced, 3);
+    assert_eq!(scanned.first_histories, 3);
+    assert_eq!(scanned.b29, 0);
+}
+
+#[test]
+fn inferred_count_ignores_a_wrong_header() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let written = records(6);
+    write_file(&path, &written, 6.0);
+    // a header claiming two records, then half a record more than the six there are
+    let mut bytes = fs::read(&path).unwrap();
+    LittleEndian::write_i32(&mut bytes[5..9], 2);
+    bytes.extend_from_slice(&[0; 14]);
+    fs::write(&path, &bytes).unwrap();
+    let reader = PHSPReader::from_infer_count(File::open(&path).unwrap()).unwrap();
+    assert_eq!(reader.header.total_particles, 6);
+    let read: Vec<Record> = reader.map(|record| record.unwrap()).collect();
+    assert_eq!(read, written);
+}
+
+#[test]
+fn weighted_spectrum_sums_weights_per_bin() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let weights = [0.5, 1.0, 2.25, 4.0];
+    let written: Vec<Record> = weights.iter()
+        .map(|&weight| {
+            let mut record = photon(1.5, 0.0, 0.0);
+            record.set_weight(weight);
+            record
+        })
+        .collect();
+    write_file(&path, &written, 4.0);
+    let (summed, counts) = energy_spectrum_weighted(&path, 4, 0.0, 4.0).unwrap();
+    assert_eq!(summed, vec![0.0, 7.75, 0.0, 0.0]);
+    assert_eq!(counts, vec![0, 4, 0, 0]);
+}
+
+#[test]
+fn weighted_spectrum_rejects_empty_ranges() {
+    assert!(matches!(energy_spectrum_weighted(&fixture(), 0, 0.0, 1.0),
+                     Err(EGSError::InvalidArgument(_))));
+    assert!(matches!(energy_spectrum_weighted(&fixture(), 4, 1.0, 1.0),
+                     Err(EGSError::InvalidArgument(_))));
+}
+
+#[test]
+fn radial_profile_means_each_ring() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    // rings of 1 cm out to 3 cm, then the overflow
+    let written = [photon(1.0, 0.5, 0.0),
+                   photon(3.0, 0.0, -0.5),
+                   photon(2.0, 1.2, 1.2),
+                   photon(5.0, 0.0, 2.5),
+                   photon(7.0, 2.0, -1.5),
+                   photon(9.0, 3.0, 4.0)];
+    write_file(&path, &written, 6.0);
+    let profile = radial_energy_profile(&path, 3, 3.0).unwrap();
+    assert_eq!(profile,
+               vec![(1.0, 2.0, 2), (2.0, 2.0, 1), (3.0, 6.0, 2), (f32::INFINITY, 9.0, 1)]);
+}
+
+#[test]
+fn radial_profile_rejects_bad_bins() {
+    assert!(matches!(radial_energy_profile(&fixture(), 0, 1.0),
+                     Err(EGSError::InvalidArgument(_))));
+    for &radius in [0.0, -1.0, f32::NAN, f32::INFINITY].iter() {
+        assert!(matches!(radial_energy_profile(&fixture(), 4, radius),
+                         Err(EGSError::InvalidArgument(_))));
+    }
+}
+
+#[test]
+fn parallel_sample_is_reproducible_whatever_the_threads() {
+    let dir = TempDir::new();
+    let written = records(300);
+    let mut inputs = Vec::new();
+    for (i, chunk) in written.chunks(100).enumerate() {
+        let path = dir.path(&format!("in{}.egsphsp1", i));
+        write_file(&path, chunk, 100.0);
+        inputs.push(path);
+    }
+    let inputs: Vec<&Path> = inputs.iter().map(|path| path.as_path()).collect();
+    let mut outputs = Vec::new();
+    for &threads in [1, 2, 3].iter() {
+        let output = dir.path(&format!("out{}.egsphsp1", threads));
+        sample_parallel(&inputs, &output, 4, 99, threads).unwrap();
+        outputs.push(fs::read(&output).unwrap());
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert_eq!(outputs[0], outputs[2]);
+    // no temporary files are left behind
+    assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 6);
+    let sampled = read_file(&dir.path("out1.egsphsp1"));
+    assert!(!sampled.is_empty() && sampled.len() < 300);
+    let header = PHSPReader::open(&dir.path("out1.egsphsp1")).unwrap().header;
+    assert_eq!(header.total_particles as usize, sampled.len());
+}
+
+#[test]
+fn parallel_sample_rejects_zero_threads() {
+    let dir = TempDir::new();
+    let output = dir.path("out.egsphsp1");
+    let result = sample_parallel(&[&fixture()], &output, 4, 1, 0);
+    assert!(matches!(result, Err(EGSError::InvalidArgument(_))));
+    assert!(!output.exists());
+}
+
+#[test]
+fn photon_kinetic_energy_is_its_total_energy() {
+    let record = photon(1.25, 0.0, 0.0);
+    assert_eq!(record.kinetic_energy(), 1.25);
+    assert!(!record.below_rest_mass());
+    // the first particle of a history keeps its energy
+    assert_eq!(photon(-0.2, 0.0, 0.0).kinetic_energy(), 0.2);
+}
+
+#[test]
+fn charged_kinetic_energy_leaves_out_the_rest_mass() {
+    for &positron in [false, true].iter() {
+        let record = charged(2.0, positron);
+        assert!((record.kinetic_energy() - (2.0 - 0.510_998_95)).abs() < 1e-6);
+        assert!(!record.below_rest_mass());
+        let slow = charged(0.3, positron);
+        assert_eq!(slow.kinetic_energy(), 0.0);
+        assert!(slow.below_rest_mass());
+    }
+}
+
+#[test]
+fn in_place_rotation_and_its_inverse_restore_the_file() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let written: Vec<Record> = records(50)
+        .into_iter()
+        .map(|mut record| {
+            record.x_cos = 0.3;
+            record.y_cos = -0.4;
+            record
+        })
+        .collect();
+    write_file(&path, &written, 50.0);
+    let before = fs::read(&path).unwrap();
+    let mut matrix = [[0.0; 3]; 3];
+    Transform::rotation(&mut matrix, 0.7);
+    transform(&path, &path, &matrix).unwrap();
+    let rotated = read_file(&path);
+    assert!((rotated[10].x_cm - written[10].x_cm).abs() > 1e-3);
+    Transform::rotation(&mut matrix, -0.7);
+    transform(&path, &path, &matrix).unwrap();
+    let after = fs::read(&path).unwrap();
+    assert_eq!(after.len(), before.len());
+    assert_eq!(after[..28], before[..28]);
+    for (restored, original) in read_file(&path).iter().zip(written.iter()) {
+        assert!((restored.x_cm - original.x_cm).abs() < 1e-5);
+        assert!((restored.y_cm - original.y_cm).abs() < 1e-5);
+        assert!((restored.x_cos - original.x_cos).abs() < 1e-6);
+        assert!((restored.y_cos - original.y_cos).abs() < 1e-6);
+        assert_eq!(restored.total_energy(), original.total_energy());
+        assert_eq!(restored.latch, original.latch);
+    }
+}
+
+#[test]
+fn diff_of_identical_files_is_empty() {
+    let dir = TempDir::new();
+    let a = dir.path("a.egsphsp1");
+    let b = dir.path("b.egsphsp1");
+    write_file(&a, &records(20), 20.0);
+    write_file(&b, &records(20), 20.0);
+    let report = diff(&a, &b).unwrap();
+    assert!(report.header_differences.is_empty());
+    assert_eq!((report.records_a, report.records_b), (20, 20));
+    assert_eq!(report.first_difference, None);
+    assert_eq!(report.differing, 0);
+    assert!(report.mismatch().is_none());
+}
+
+#[test]
+fn diff_finds_the_first_differing_record() {
+    let dir = TempDir::new();
+    let a = dir.path("a.egsphsp1");
+    let b = dir.path("b.egsphsp1");
+    let mut changed = records(20);
+    changed[7].x_cm += 1.0;
+    changed[12].x_cos = 0.5;
+    write_file(&a, &records(20), 20.0);
+    write_file(&b, &changed, 20.0);
+    let report = diff(&a, &b).unwrap();
+    assert!(report.header_differences.is_empty());
+    assert_eq!(report.first_difference, Some(7));
+    assert_eq!(report.differing, 2);
+    assert!(matches!(report.mismatch(), Some(EGSError::RecordMismatch)));
+}
+
+#[test]
+fn diff_reports_header_differences_first() {
+    let dir = TempDir::new();
+    let a = dir.path("a.egsphsp1");
+    let b = dir.path("b.egsphsp1");
+    write_file(&a, &records(20), 20.0);
+    write_file(&b, &records(18), 20.0);
+    let report = diff(&a, &b).unwrap();
+    assert_eq!(report.header_differences[0],
+               ("total_particles".to_string(), "20".to_string(), "18".to_string()));
+    assert_eq!((report.records_a, report.records_b, report.differing), (20, 18, 0));
+    assert!(matches!(report.mismatch(), Some(EGSError::HeaderMismatch)));
+}
+
+#[test]
+fn records_match_at_coarse_tolerances_only() {
+    let a = photon(1.0, 2.0, 3.0);
+    let b = photon(1.05, 2.05, 3.0);
+    assert!(a.similar_to_within(&b, &Tolerance::uniform(0.1)));
+    assert!(!a.similar_to_within(&b, &Tolerance::default()));
+    assert!(!a.similar_to_within(&b, &Tolerance::uniform(0.001)));
+    // either sign of difference counts
+    assert!(!b.similar_to_within(&a, &Tolerance::uniform(0.001)));
+    let loose_energy = Tolerance { energy: 0.1, ..Tolerance::uniform(0.001) };
+    assert!(!a.similar_to_within(&b, &loose_energy));
+    let loose_both = Tolerance { position: 0.1, ..loose_energy };
+    assert!(a.similar_to_within(&b, &loose_both));
+}
+
+#[test]
+fn headers_match_at_coarse_tolerances_only() {
+    let a = Header::new_mode0(10, 4, 100.0).unwrap();
+    let mut b = a;
+    b.max_energy = a.max_energy + 0.05;
+    b.total_particles_in_source = 105.0;
+    assert!(a.similar_to(&a));
+    assert!(!a.similar_to(&b));
+    assert!(a.similar_to_within(&b, &Tolerance::uniform(0.1)));
+    assert!(!a.similar_to_within(&b, &Tolerance::uniform(0.001)));
+    b.total_photons = 5;
+    assert!(!a.similar_to_within(&b, &Tolerance::uniform(0.1)));
+}
+
+#[test]
+fn sampling_mode2_keeps_zlast() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp2");
+    let output = dir.path("out.egsphsp2");
+    write_mode2_file(&input, &records(200), 200.0);
+    sample(&[&input], &output, 2, 8).unwrap();
+    let reader = PHSPReader::open(&output).unwrap();
+    assert_eq!(&reader.header.mode, b"MODE2");
+    assert!(reader.header.using_zlast);
+    let sampled: Vec<Record> = reader.map(|record| record.unwrap()).collect();
+    assert!(!sampled.is_empty());
+    assert!(sampled.iter().all(|record| record.zlast == Some(1.0)));
+    assert!(validate(&output).unwrap().is_valid());
+}
+
+#[test]
+fn sampling_mixed_modes_is_an_error() {
+    let dir = TempDir::new();
+    let mode0 = dir.path("in.egsphsp1");
+    let mode2 = dir.path("in.egsphsp2");
+    let output = dir.path("out.egsphsp1");
+    write_file(&mode0, &records(10), 10.0);
+    write_mode2_file(&mode2, &records(10), 10.0);
+    assert!(matches!(sample(&[&mode0, &mode2], &output, 2, 8), Err(EGSError::ModeMismatch)));
+    assert!(!output.exists());
+}
+
+#[test]
+fn progress_is_reported_once_per_record() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let other = dir.path("other.egsphsp1");
+    write_file(&input, &records(7), 7.0);
+    write_file(&other, &records(5), 5.0);
+    let mut calls = Vec::new();
+    combine_with_progress(&[&input, &other], &dir.path("combined.egsphsp1"), false, |done, total| {
+        calls.push((done, total))
+    }).unwrap();
+    assert_eq!(calls, (1..13).map(|done| (done, 12)).collect::<Vec<_>>());
+    let mut calls = 0;
+    sample_with_progress(&[&input], &dir.path("sampled.egsphsp1"), 2, 1, false, |_, total| {
+        assert_eq!(total, 7);
+        calls += 1;
+    }).unwrap();
+    assert_eq!(calls, 7);
+    let mut calls = 0;
+    let mut matrix = [[0.0; 3]; 3];
+    Transform::rotation(&mut matrix, 1.0);
+    transform_with_progress(&input, &dir.path("rotated.egsphsp1"), &matrix, false, |done, _| {
+        calls += 1;
+        assert_eq!(done, calls);
+    }).unwrap();
+    assert_eq!(calls, 7);
+}
+
+#[test]
+fn point_source_directions_are_valid() {
+    let dir = TempDir::new();
+    let path = dir.path("source.egsphsp1");
+    generate_point_source(&path, 2000, 1.25, 17).unwrap();
+    let header = PHSPReader::open(&path).unwrap().header;
+    assert_eq!((header.total_particles, header.total_photons), (2000, 2000));
+    assert_eq!((header.min_energy, header.max_energy), (1.25, 1.25));
+    let mut backward = 0;
+    for record in PHSPReader::open(&path).unwrap().validated() {
+        let record = record.unwrap();
+        assert!(record.x_cos * record.x_cos + record.y_cos * record.y_cos <= 1.0);
+        assert_eq!(record.total_energy(), 1.25);
+        assert_eq!((record.x_cm, record.y_cm), (0.0, 0.0));
+        if !record.z_positive() {
+            backward += 1;
+        }
+    }
+    // isotropic, so about half head back
+    assert!((800..1200).contains(&backward), "{} backward", backward);
+    assert!(validate(&path).unwrap().is_valid());
+}
+
+#[test]
+fn rotating_about_a_point_leaves_it_fixed() {
+    let mut matrix = [[0.0; 3]; 3];
+    Transform::rotation_about(&mut matrix, 1.2, 3.0, -2.0);
+    let mut record = photon(1.0, 3.0, -2.0);
+    record.transform(&matrix);
+    assert!((record.x_cm - 3.0).abs() < 1e-5);
+    assert!((record.y_cm + 2.0).abs() < 1e-5);
+}
+
+#[test]
+fn rotating_about_a_distant_centre_moves_the_point() {
+    let mut matrix = [[0.0; 3]; 3];
+    // a quarter turn about (10, 0) takes the origin to (10, -10)
+    Transform::rotation_about(&mut matrix, PI / 2.0, 10.0, 0.0);
+    let mut record = photon(1.0, 0.0, 0.0);
+    record.x_cos = 0.5;
+    record.transform(&matrix);
+    assert!((record.x_cm - 10.0).abs() < 1e-5);
+    assert!((record.y_cm + 10.0).abs() < 1e-5);
+    // directions turn with it but aren't translated
+    assert!(record.x_cos.abs() < 1e-6);
+    assert!((record.y_cos - 0.5).abs() < 1e-6);
+}
+
+#[test]
+fn centroid_of_a_symmetric_cloud_is_the_origin() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let mut cloud = Vec::new();
+    for i in 1..20 {
+        let (x, y) = (i as f32 * 0.37, i as f32 * -0.21);
+        for &(sx, sy) in [(1.0, 1.0), (-1.0, 1.0), (1.0, -1.0), (-1.0, -1.0)].iter() {
+            let mut record = photon(1.0, sx * x, sy * y);
+            record.set_weight(i as f32);
+            cloud.push(record);
+        }
+    }
+    write_file(&path, &cloud, 76.0);
+    let (x, y) = centroid(&path).unwrap();
+    assert!(x.abs() < 1e-5 && y.abs() < 1e-5, "({}, {})", x, y);
+}
+
+#[test]
+fn centroid_weights_each_position() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let mut heavy = photon(1.0, 4.0, 2.0);
+    heavy.set_weight(3.0);
+    write_file(&path, &[heavy, photon(1.0, 0.0, -2.0)], 2.0);
+    assert_eq!(centroid(&path).unwrap(), (3.0, 1.0));
+}
+
+#[test]
+fn centroid_without_weight_is_an_error() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let mut weightless = photon(1.0, 4.0, 2.0);
+    weightless.set_weight(0.0);
+    write_file(&path, &[weightless], 1.0);
+    assert!(matches!(centroid(&path), Err(EGSError::ZeroWeight)));
+    write_file(&path, &[], 0.0);
+    assert!(matches!(centroid(&path), Err(EGSError::ZeroWeight)));
+}
+
+#[test]
+fn combine_concatenates_every_input_in_order() {
+    let dir = TempDir::new();
+    let output = dir.path("out.egsphsp1");
+    // more inputs than are kept open between the two passes
+    let written = records(MAX_OPEN_INPUTS * 2 + 6);
+    let mut inputs = Vec::new();
+    for (i, chunk) in written.chunks(2).enumerate() {
+        let path = dir.path(&format!("in{}.egsphsp1", i));
+        write_file(&path, chunk, 2.0);
+        inputs.push(path);
+    }
+    let inputs: Vec<&Path> = inputs.iter().map(|path| path.as_path()).collect();
+    combine(&inputs, &output, false).unwrap();
+    assert_eq!(read_file(&output), written);
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(header.total_particles as usize, written.len());
+    assert_eq!(header.total_particles_in_source, written.len() as f32);
+    assert_eq!(header.min_energy, written[0].total_energy());
+    assert_eq!(header.max_energy, written[written.len() - 1].total_energy());
+    assert_eq!(combined_size(&inputs).unwrap(), fs::metadata(&output).unwrap().len());
+}
+
+#[test]
+fn combine_with_delete_removes_the_inputs() {
+    let dir = TempDir::new();
+    let a = dir.path("a.egsphsp1");
+    let b = dir.path("b.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&a, &records(3), 3.0);
+    write_file(&b, &records(4), 4.0);
+    combine(&[&a, &b], &output, true).unwrap();
+    assert!(!a.exists() && !b.exists());
+    assert_eq!(read_file(&output).len(), 7);
+}
+
+#[test]
+fn set_region_reads_back_and_leaves_other_bits() {
+    let mut record = charged(2.0, true);
+    record.set_bremsstrahlung_or_annihilation(true);
+    record.set_crossed_multiple(true);
+    let latch = record.latch;
+    for &region in [0, 1, 17, 31].iter() {
+        record.set_region(region).unwrap();
+        assert_eq!(record.region_index(), region);
+        assert_eq!(record.latch & !REGION_MASK, latch & !REGION_MASK);
+    }
+    assert_eq!(record.particle_type(), ParticleType::Positron);
+    assert!(record.bremsstrahlung_or_annihilation() && record.crossed_multiple());
+}
+
+#[test]
+fn set_region_rejects_regions_past_the_field() {
+    let mut record = photon(1.0, 0.0, 0.0);
+    record.set_region(5).unwrap();
+    assert!(matches!(record.set_region(32), Err(EGSError::BadRegion(32))));
+    assert_eq!(record.region_index(), 5);
+}
+
+#[test]
+fn flag_setters_touch_only_their_bit() {
+    let mut record = photon(1.0, 0.0, 0.0);
+    record.set_region(9).unwrap();
+    record.set_charged(true);
+    assert_eq!(record.latch, 9 << REGION_SHIFT | 1 << 30);
+    record.set_bremsstrahlung_or_annihilation(true);
+    record.set_crossed_multiple(true);
+    record.set_charged(false);
+    assert_eq!(record.latch, 9 << REGION_SHIFT | 1 << 31 | 1);
+}
+
+#[test]
+fn tagged_inputs_can_be_filtered_apart() {
+    let dir = TempDir::new();
+    let a = dir.path("a.egsphsp1");
+    let b = dir.path("b.egsphsp1");
+    let combined = dir.path("combined.egsphsp1");
+    let from_a = records(6);
+    let mut from_b = records(9)[6..].to_vec();
+    // already in a region, which the tag replaces
+    from_b[0].set_region(7).unwrap();
+    write_file(&a, &from_a, 6.0);
+    write_file(&b, &from_b, 3.0);
+    combine_tagged(&[&a, &b], &[Some(1), Some(2)], &combined, false, false, |_, _| ()).unwrap();
+    for &(region, source) in [(1, &from_a), (2, &from_b)].iter() {
+        let output = dir.path(&format!("region{}.egsphsp1", region));
+        combine_filtered(&[&combined], &output, false, |record| record.region_index() == region)
+            .unwrap();
+        let recovered = read_file(&output);
+        assert_eq!(recovered.len(), source.len());
+        for (tagged, original) in recovered.iter().zip(source.iter()) {
+            assert_eq!(tagged.latch & !REGION_MASK, original.latch & !REGION_MASK);
+            assert_eq!(Record { latch: original.latch, ..*tagged }, *original);
+        }
+    }
+}
+
+#[test]
+fn untagged_inputs_keep_their_regions() {
+    let dir = TempDir::new();
+    let a = dir.path("a.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let mut written = records(3);
+    written[1].set_region(4).unwrap();
+    write_file(&a, &written, 3.0);
+    combine_tagged(&[&a], &[None], &output, false, false, |_, _| ()).unwrap();
+    assert_eq!(read_file(&output), written);
+    let result = combine_tagged(&[&a], &[Some(40)], &output, false, false, |_, _| ());
+    assert!(matches!(result, Err(EGSError::BadRegion(40))));
+}
+
+#[test]
+fn histories_are_summed_in_f64() {
+    let mut headers = Vec::new();
+    for i in 0..100_000 {
+        headers.push(Header::new_mode0(0, 0, if i == 0 { 20_000_000.0 } else { 0.1 }).unwrap());
+    }
+    let reference: f64 = headers.iter().map(|header| header.total_particles_in_source as f64).sum();
+    let summed = total_histories(headers.iter());
+    assert_eq!(summed, reference as f32);
+    // a running f32 sum never gets past the first file's histories
+    let naive = headers.iter().fold(0.0f32, |sum, header| sum + header.total_particles_in_source);
+    assert_eq!(naive, 20_000_000.0);
+    assert!((summed - 20_010_000.0).abs() <= 2.0);
+}
+
+#[test]
+fn combine_sums_histories_in_f64() {
+    let dir = TempDir::new();
+    let output = dir.path("out.egsphsp1");
+    let mut inputs = vec![dir.path("big.egsphsp1")];
+    write_file(&inputs[0], &records(1), 16_777_216.0);
+    for i in 0..40 {
+        inputs.push(dir.path(&format!("small{}.egsphsp1", i)));
+        write_file(&inputs[i + 1], &records(1), 1.0);
+    }
+    let inputs: Vec<&Path> = inputs.iter().map(|path| path.as_path()).collect();
+    combine(&inputs, &output, false).unwrap();
+    // 2^24 + 1 rounds back down in f32, so adding them one at a time gains nothing
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(header.total_particles_in_source, 16_777_256.0);
+}
+
+#[test]
+fn mode0_cannot_merge_into_mode2() {
+    let mode2 = Header::new_mode2(4, 1, 4.0).unwrap();
+    let mode0 = Header::new_mode0(4, 1, 4.0).unwrap();
+    assert!(matches!(mode2.compatible_with(&mode0), Err(EGSError::ModeMismatch)));
+    let mut without_zlast = mode2;
+    without_zlast.using_zlast = false;
+    assert!(matches!(mode2.compatible_with(&without_zlast), Err(EGSError::HeaderMismatch)));
+    let dir = TempDir::new();
+    let a = dir.path("a.egsphsp2");
+    let b = dir.path("b.egsphsp1");
+    let output = dir.path("out.egsphsp2");
+    write_mode2_file(&a, &records(2), 2.0);
+    write_file(&b, &records(2), 2.0);
+    let results = [combine(&[&a, &b], &output, false),
+                   combine_dedup(&[&a, &b], &output, false, None),
+                   combine_filtered(&[&a, &b], &output, false, |_| true)];
+    for combined in results.iter() {
+        assert!(matches!(*combined, Err(EGSError::ModeMismatch)));
+    }
+    assert!(!output.exists());
+}
+
+#[test]
+fn counts_past_i32_are_an_error() {
+    let big = Header::new_mode0(2_000_000_000, 1_500_000_000, 1.0).unwrap();
+    let (particles, photons) = total_particles([big, big].iter());
+    assert_eq!((particles, photons), (4_000_000_000, 3_000_000_000));
+    assert!(matches!(header_count(particles), Err(EGSError::TooManyParticles(4_000_000_000))));
+    assert_eq!(header_count(i32::MAX as u64).unwrap(), i32::MAX);
+}
+
+#[test]
+fn combining_past_i32_particles_is_an_error() {
+    let dir = TempDir::new();
+    let a = dir.path("a.egsphsp1");
+    let b = dir.path("b.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    // only the headers, which is all that is read before the counts are checked
+    for path in [&a, &b].iter() {
+        let header = Header::new_mode0(2_000_000_000, 0, 1.0).unwrap();
+        PHSPWriter::create(path, &header).unwrap().finish().unwrap();
+    }
+    let result = combine(&[&a, &b], &output, false);
+    assert!(matches!(result, Err(EGSError::TooManyParticles(4_000_000_000))));
+    assert!(!output.exists());
+}
+
+#[test]
+fn raw_record_is_the_bytes_on_disk() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp2");
+    write_mode2_file(&path, &records(5), 5.0);
+    let bytes = fs::read(&path).unwrap();
+    let mut reader = PHSPReader::open(&path).unwrap();
+    assert_eq!(reader.raw_record(3).unwrap(), &bytes[4 * 32..5 * 32]);
+    assert_eq!(reader.raw_record(0).unwrap(), &bytes[32..64]);
+    assert!(matches!(reader.raw_record(5), Err(EGSError::RecordOutOfRange(5))));
+}
+
+// `records(count)` in a scrambled but fixed order.
+fn shuffled(count: usize) -> Vec<Record> {
+    let mut scrambled = records(count);
+    for i in 0..count {
+        scrambled.swap(i, (i * 7919 + 13) % count);
+    }
+    scrambled
+}
+
+#[test]
+fn sorted_combine_orders_every_record() {
+    let dir = TempDir::new();
+    let a = dir.path("a.egsphsp1");
+    let b = dir.path("b.egsphsp1");
+    let written = shuffled(60);
+    write_file(&a, &written[..35], 35.0);
+    write_file(&b, &written[35..], 25.0);
+    for &key in [SortKey::Energy, SortKey::Radius].iter() {
+        let output = dir.path("sorted.egsphsp1");
+        combine_sorted(&[&a, &b], &output, key).unwrap();
+        let sorted = read_file(&output);
+        assert_eq!(sorted.len(), 60);
+        assert!(sorted.windows(2).all(|pair| key.of(&pair[0]) <= key.of(&pair[1])));
+        let mut expected = records(60);
+        expected.sort_by(|x, y| compare_keys(key.of(x), key.of(y)));
+        assert_eq!(sorted, expected);
+        assert_eq!(PHSPReader::open(&output).unwrap().header.total_particles, 60);
+    }
+}
+
+#[test]
+fn sorted_combine_is_stable_and_puts_nan_last() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("sorted.egsphsp1");
+    let mut written = Vec::new();
+    for (i, &region) in [3, 1, 3, 2, 1, 3].iter().enumerate() {
+        let mut record = photon(1.0, i as f32, 0.0);
+        record.set_region(region).unwrap();
+        written.push(record);
+    }
+    write_file(&input, &written, 6.0);
+    combine_sorted(&[&input], &output, SortKey::Region).unwrap();
+    let xs: Vec<f32> = read_file(&output).iter().map(|record| record.x_cm).collect();
+    assert_eq!(xs, vec![1.0, 4.0, 3.0, 0.0, 2.0, 5.0]);
+    let with_nan = [photon(2.0, f32::NAN, 0.0), photon(1.0, 3.0, 4.0), photon(3.0, 0.0, 1.0)];
+    write_file(&input, &with_nan, 3.0);
+    combine_sorted(&[&input], &output, SortKey::Radius).unwrap();
+    let energies: Vec<f32> = read_file(&output).iter().map(|r| r.total_energy()).collect();
+    assert_eq!(energies, vec![3.0, 1.0, 2.0]);
+}
+
+#[test]
+fn runs_merge_into_one_order() {
+    let dir = TempDir::new();
+    let mut expected = records(30);
+    let mut runs = Vec::new();
+    for i in 0..3 {
+        // every third record, so the runs interleave
+        let run: Vec<Record> = expected.iter().skip(i).step_by(3).cloned().collect();
+        runs.push(dir.path(&format!("run{}.egsphsp1", i)));
+        write_file(&runs[i], &run, 10.0);
+    }
+    let mut merged = Vec::new();
+    merge_runs(&runs, SortKey::Energy, |record| {
+        merged.push(*record);
+        Ok(())
+    }).unwrap();
+    expected.sort_by(|x, y| compare_keys(x.total_energy(), y.total_energy()));
+    assert_eq!(merged, expected);
+}
+
+#[test]
+fn russian_roulette_preserves_the_expected_weight() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let written: Vec<Record> = (0..20_000)
+        .map(|i| {
+            let mut record = photon(1.0, 0.0, 0.0);
+            record.set_weight(if i % 10 == 0 { 2.0 } else { 0.1 });
+            record
+        })
+        .collect();
+    write_file(&input, &written, 20_000.0);
+    russian_roulette(&input, &output, 0.5, 21).unwrap();
+    let survivors = read_file(&output);
+    let weight_in: f64 = written.iter().map(|r| r.get_weight() as f64).sum();
+    let weight_out: f64 = survivors.iter().map(|r| r.get_weight() as f64).sum();
+    // 18000 light records survive one in five, sigma of the survivors is 54
+    assert!((weight_out / weight_in - 1.0).abs() < 0.02, "{} vs {}", weight_out, weight_in);
+    assert!(survivors.iter().all(|r| r.get_weight() == 0.5 || r.get_weight() == 2.0));
+    assert_eq!(survivors.iter().filter(|r| r.get_weight() == 2.0).count(), 2000);
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(header.total_particles as usize, survivors.len());
+}
+
+#[test]
+fn russian_roulette_rejects_a_bad_threshold() {
+    let dir = TempDir::new();
+    let output = dir.path("out.egsphsp1");
+    for &threshold in [0.0, -1.0, f32::NAN].iter() {
+        let result = russian_roulette(&fixture(), &output, threshold, 1);
+        assert!(matches!(result, Err(EGSError::InvalidArgument(_))));
+    }
+    assert!(!output.exists());
+}
+
+#[test]
+fn header_json_round_trips() {
+    let path = fixture();
+    let header = PHSPReader::open(&path).unwrap().header;
+    let parsed: Value = serde_json::from_str(&header.to_json()).unwrap();
+    assert_eq!(parsed["mode"], "MODE0");
+    assert_eq!(parsed["total_particles"], 10687);
+    assert_eq!(parsed["total_photons"], 10625);
+    assert_eq!(parsed["min_energy"].as_f64().unwrap() as f32, header.min_energy);
+    assert_eq!(parsed["max_energy"].as_f64().unwrap() as f32, header.max_energy);
+    assert_eq!(parsed["total_particles_in_source"].as_f64().unwrap() as f32,
+               header.total_particles_in_source);
+    assert_eq!(parsed["record_size"], 28);
+    assert_eq!(parsed["using_zlast"], false);
+}
+
+#[test]
+fn record_json_round_trips() {
+    for record in read_file(&fixture()).iter().take(50) {
+        let parsed: Value = serde_json::from_str(&record.to_json()).unwrap();
+        assert_eq!(parsed["latch"], record.latch);
+        let float = |name: &str| parsed[name].as_f64().unwrap() as f32;
+        assert_eq!(float("total_energy"), record.raw_energy());
+        assert_eq!(float("x_cm"), record.x_cm);
+        assert_eq!(float("y_cm"), record.y_cm);
+        assert_eq!(float("x_cos"), record.x_cos);
+        assert_eq!(float("y_cos"), record.y_cos);
+        assert_eq!(float("weight"), record.weight);
+        assert_eq!(parsed["zlast"], Value::Null);
+    }
+    let mode2 = Record { zlast: Some(-2.5), ..photon(1.0, 0.0, 0.0) };
+    let parsed: Value = serde_json::from_str(&mode2.to_json()).unwrap();
+    assert_eq!(parsed["zlast"].as_f64(), Some(-2.5));
+}
+
+#[test]
+fn header_displays_its_counts() {
+    let header = Header::new_mode0(10, 4, 100.0).unwrap();
+    let text = header.to_string();
+    assert!(text.contains("Total particles: 10\n"));
+    assert!(text.contains("Total electrons/positrons: 6\n"));
+    assert!(text.ends_with("Incident particles from source: 100.0"));
+}
+
+// Whether two JSON objects have the same keys and values, `1` and `1.0` being equal.
+#[cfg(feature = "serde")]
+fn same_json(serialized: &str, written: &str) -> bool {
+    let a: Value = serde_json::from_str(serialized).unwrap();
+    let b: Value = serde_json::from_str(written).unwrap();
+    let (a, b) = (a.as_object().unwrap(), b.as_object().unwrap());
+    a.len() == b.len() &&
+    a.iter().all(|(key, value)| match (value.as_f64(), b.get(key)) {
+        (Some(number), Some(other)) => other.as_f64() == Some(number),
+        (None, other) => other == Some(value),
+        _ => false,
+    })
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serialize_matches_to_json() {
+    let header = PHSPReader::open(&fixture()).unwrap().header;
+    assert!(same_json(&serde_json::to_string(&header).unwrap(), &header.to_json()));
+    for record in read_file(&fixture()).iter().take(50) {
+        assert!(same_json(&serde_json::to_string(record).unwrap(), &record.to_json()));
+    }
+    let scanned = scan(&fixture()).unwrap();
+    assert!(same_json(&serde_json::to_string(&scanned).unwrap(), &scanned.to_json()));
+}
+
+#[test]
+fn peek_then_next_returns_the_same_record() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let written = records(4);
+    write_file(&path, &written, 4.0);
+    let mut reader = PHSPReader::open(&path).unwrap();
+    assert_eq!(reader.peek().unwrap().unwrap(), written[0]);
+    assert_eq!(reader.peek().unwrap().unwrap(), written[0]);
+    assert_eq!(reader.next().unwrap().unwrap(), written[0]);
+    assert_eq!(reader.records_remaining(), 3);
+    assert_eq!(reader.nth(1).unwrap().unwrap(), written[2]);
+    // the last record, twice, then the end twice
+    assert_eq!(reader.peek().unwrap().unwrap(), written[3]);
+    assert_eq!(reader.peek().unwrap().unwrap(), written[3]);
+    assert_eq!(reader.next().unwrap().unwrap(), written[3]);
+    assert!(reader.peek().is_none());
+    assert!(reader.peek().is_none());
+    assert!(reader.next().is_none());
+}
+
+#[test]
+fn peeking_leaves_the_count_alone() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(6), 6.0);
+    let mut reader = PHSPReader::open(&path).unwrap();
+    reader.peek().unwrap().unwrap();
+    assert_eq!(reader.count(), 6);
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn peek_is_unsupported_in_gzip_files() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1.gz");
+    let mut writer = PHSPWriter::create(&path, &Header::new_mode0(2, 2, 2.0).unwrap()).unwrap();
+    writer.write(&photon(1.0, 0.0, 0.0)).unwrap();
+    writer.write(&photon(2.0, 0.0, 0.0)).unwrap();
+    writer.finish().unwrap();
+    let mut reader = PHSPReader::open(&path).unwrap();
+    assert!(matches!(reader.peek(), Some(Err(EGSError::Unsupported(_)))));
+    assert_eq!(reader.count(), 2);
+}
+
+#[test]
+fn size_hint_narrows_as_records_are_read() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(5), 5.0);
+    let mut reader = PHSPReader::open(&path).unwrap();
+    for left in (0..6).rev() {
+        assert_eq!(reader.size_hint(), (left, Some(left)));
+        assert_eq!(reader.len(), left);
+        reader.next();
+    }
+    let collected: Vec<EGSResult<Record>> = PHSPReader::open(&path).unwrap().collect();
+    assert_eq!(collected.capacity(), 5);
+}
+
+#[test]
+fn len_trusts_the_header() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(5), 5.0);
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..3 * 28]).unwrap();
+    let mut reader = PHSPReader::open(&path).unwrap();
+    assert_eq!(reader.len(), 5);
+    assert!(reader.next().unwrap().is_ok());
+    assert!(reader.next().unwrap().is_ok());
+    assert_eq!(reader.len(), 3);
+    assert!(matches!(reader.next(), Some(Err(EGSError::ReadFailed { record: 2, .. }))));
+}
+
+#[test]
+fn mode2_to_mode0_and_back_keeps_every_other_field() {
+    let dir = TempDir::new();
+    let mode2 = dir.path("in.egsphsp2");
+    let mode0 = dir.path("mode0.egsphsp1");
+    let back = dir.path("back.egsphsp2");
+    let written = records(12);
+    write_mode2_file(&mode2, &written, 12.0);
+    convert_mode(&mode2, &mode0, *b"MODE0", 0.0).unwrap();
+    let header = PHSPReader::open(&mode0).unwrap().header;
+    assert_eq!((&header.mode, header.record_size, header.using_zlast), (b"MODE0", 28, false));
+    assert_eq!(fs::metadata(&mode0).unwrap().len(), header.expected_file_size());
+    assert_eq!(read_file(&mode0), written);
+    convert_mode(&mode0, &back, *b"MODE2", 3.5).unwrap();
+    let header = PHSPReader::open(&back).unwrap().header;
+    assert_eq!((&header.mode, header.record_size, header.using_zlast), (b"MODE2", 32, true));
+    assert_eq!(fs::metadata(&back).unwrap().len(), header.expected_file_size());
+    let converted = read_file(&back);
+    assert!(converted.iter().all(|record| record.zlast == Some(3.5)));
+    let stripped: Vec<Record> = converted.iter().map(|r| Record { zlast: None, ..*r }).collect();
+    assert_eq!(stripped, written);
+}
+
+#[test]
+fn converting_to_another_mode_is_an_error() {
+    let dir = TempDir::new();
+    let output = dir.path("out.egsphsp1");
+    assert!(matches!(convert_mode(&fixture(), &output, *b"MODE1", 0.0), Err(EGSError::BadMode)));
+    assert!(!output.exists());
+}
+
+#[test]
+fn file_cut_inside_its_last_record_is_an_error() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(5), 5.0);
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..5 * 28 + 14]).unwrap();
+    match PHSPReader::open(&path) {
+        Err(EGSError::PartialRecord { record, bytes }) => assert_eq!((record, bytes), (4, 14)),
+        other => panic!("expected PartialRecord, got {:?}", other.map(|r| r.header)),
+    }
+    // streamed, the length is only found out on reaching it
+    let streamed = Cursor::new(bytes[..5 * 28 + 14].to_vec());
+    let mut reader = PHSPReader::from_reader(streamed).unwrap();
+    match reader.nth(4) {
+        Some(Err(EGSError::PartialRecord { record, bytes })) => {
+            assert_eq!((record, bytes), (4, 14))
+        }
+        other => panic!("expected PartialRecord, got {:?}", other),
+    }
+}
+
+#[test]
+fn extract_range_is_that_slice_of_records() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let written = records(20);
+    write_file(&input, &written, 40.0);
+    extract_range(&input, &output, 5, 12).unwrap();
+    assert_eq!(read_file(&output), &written[5..12]);
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(header.total_particles, 7);
+    assert_eq!(header.min_energy, written[5].total_energy());
+    assert_eq!(header.max_energy, written[11].total_energy());
+    assert_eq!(header.total_particles_in_source, 14.0);
+    extract_range(&input, &output, 19, 20).unwrap();
+    assert_eq!(read_file(&output), &written[19..]);
+}
+
+#[test]
+fn extract_range_checks_its_bounds() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&input, &records(20), 20.0);
+    assert!(matches!(extract_range(&input, &output, 5, 21), Err(EGSError::RecordOutOfRange(21))));
+    assert!(matches!(extract_range(&input, &output, 5, 5),
+                     Err(EGSError::EmptyRange { start: 5, end: 5 })));
+    assert!(matches!(extract_range(&input, &output, 8, 3), Err(EGSError::EmptyRange { .. })));
+    assert!(!output.exists());
+}
+
+#[test]
+fn clamp_energy_drops_over_energy_particles() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let written = [photon(1.0, 0.0, 0.0), photon(9.0, 0.0, 0.0), charged(3.0, false)];
+    write_file(&input, &written, 3.0);
+    clamp_energy(&input, &output, 6.0, ClampMode::Discard).unwrap();
+    assert_eq!(read_file(&output), vec![written[0], written[2]]);
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!((header.total_particles, header.total_photons), (2, 1));
+    assert_eq!(header.max_energy, 3.0);
+}
+
+#[test]
+fn clamp_energy_caps_keeping_the_history_marker() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let written = [photon(1.0, 0.0, 0.0), photon(9.0, 0.0, 0.0), photon(-8.0, 0.0, 0.0)];
+    write_file(&input, &written, 3.0);
+    clamp_energy(&input, &output, 6.0, ClampMode::Clamp).unwrap();
+    let clamped = read_file(&output);
+    let energies: Vec<f32> = clamped.iter().map(|r| r.total_energy()).collect();
+    assert_eq!(energies, vec![1.0, 6.0, 6.0]);
+    let markers: Vec<bool> = clamped.iter().map(|r| r.first_scored_by_primary_history()).collect();
+    assert_eq!(markers, vec![false, false, true]);
+    assert_eq!(PHSPReader::open(&output).unwrap().header.max_energy, 6.0);
+}
+
+#[test]
+fn shifting_energy_there_and_back_restores_it() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let up = dir.path("up.egsphsp1");
+    let back = dir.path("back.egsphsp1");
+    let written: Vec<Record> = [1.0, -2.0, 0.75, -3.5].iter()
+        .map(|&energy| photon(energy, 0.0, 0.0))
+        .collect();
+    write_file(&input, &written, 2.0);
+    shift_energy(&input, &up, 0.5).unwrap();
+    shift_energy(&up, &back, -0.5).unwrap();
+    let shifted = read_file(&up);
+    for ((original, up), back) in written.iter().zip(shifted.iter()).zip(read_file(&back).iter()) {
+        assert!((up.total_energy() - original.total_energy() - 0.5).abs() < 1e-6);
+        assert!((back.total_energy() - original.total_energy()).abs() < 1e-6);
+        assert_eq!(back.first_scored_by_primary_history(),
+                   original.first_scored_by_primary_history());
+        assert_eq!(up.first_scored_by_primary_history(),
+                   original.first_scored_by_primary_history());
+    }
+}
+
+#[test]
+fn shifting_energy_stops_at_zero() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&input, &[photon(0.3, 0.0, 0.0), photon(-0.2, 0.0, 0.0)], 1.0);
+    shift_energy(&input, &output, -1.0).unwrap();
+    let shifted = read_file(&output);
+    assert_eq!((shifted[0].total_energy(), shifted[1].total_energy()), (0.0, 0.0));
+    assert!(!shifted[0].first_scored_by_primary_history());
+    assert!(shifted[1].first_scored_by_primary_history());
+}
+
+#[test]
+fn moments_match_a_direct_computation() {
+    let values = [(1.0, 2.0), (4.0, 1.0), (-2.0, 0.5), (7.5, 3.0), (0.25, 1.5)];
+    let weight: f64 = values.iter().map(|&(_, w)| w).sum();
+    let mean = values.iter().map(|&(v, w)| v * w).sum::<f64>() / weight;
+    let variance = values.iter().map(|&(v, w)| w * (v - mean) * (v - mean)).sum::<f64>() / weight;
+    let mut all = Moments::default();
+    let (mut first, mut second) = (Moments::default(), Moments::default());
+    for (i, &(value, w)) in values.iter().enumerate() {
+        all.add(value, w);
+        if i < 2 { first.add(value, w) } else { second.add(value, w) }
+    }
+    first.merge(&second);
+    for moments in [all, first].iter() {
+        assert!((moments.weight - weight).abs() < 1e-12);
+        assert!((moments.mean() - mean).abs() < 1e-12);
+        assert!((moments.variance() - variance).abs() < 1e-12);
+    }
+    assert!(Moments::default().mean().is_nan());
+}
+
+#[test]
+fn statistics_of_a_known_file() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let mut heavy = photon(3.0, 2.0, -1.0);
+    heavy.set_weight(3.0);
+    write_file(&path, &[photon(1.0, -2.0, 1.0), heavy], 2.0);
+    let statistics = statistics(&path).unwrap();
+    assert_eq!(statistics.records, 2);
+    assert_eq!(statistics.energy.mean(), 2.5);
+    assert_eq!(statistics.energy.variance(), 0.75);
+    assert_eq!(statistics.x_cm.mean(), 1.0);
+    assert_eq!(statistics.y_cm.mean(), -0.5);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_statistics_match_sequential() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    // several chunks' worth, with a partial one at the end
+    let written: Vec<Record> = records(STATISTICS_CHUNK_RECORDS * 3 + 123)
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut record)| {
+            record.set_weight(1.0 + (i % 7) as f32);
+            record
+        })
+        .collect();
+    write_file(&path, &written, 1.0);
+    let sequential = statistics(&path).unwrap();
+    let parallel = statistics_parallel(&path).unwrap();
+    assert_eq!(parallel.records, sequential.records);
+    let pairs = [(parallel.energy, sequential.energy),
+                 (parallel.x_cm, sequential.x_cm),
+                 (parallel.y_cm, sequential.y_cm),
+                 (parallel.x_cos, sequential.x_cos),
+                 (parallel.y_cos, sequential.y_cos)];
+    for &(a, b) in pairs.iter() {
+        assert!((a.weight - b.weight).abs() <= 1e-9 * b.weight);
+        assert!((a.mean() - b.mean()).abs() <= 1e-9 * b.mean().abs().max(1.0));
+        assert!((a.variance() - b.variance()).abs() <= 1e-9 * b.variance().max(1.0));
+    }
+}
+
+#[test]
+fn resampling_hits_the_target_and_keeps_the_weight() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let mut written = records(50);
+    for (i, record) in written.iter_mut().enumerate() {
+        record.set_weight(0.5 + (i % 4) as f32);
+    }
+    // a zero weight record must never be picked
+    written[10].set_weight(0.0);
+    let zero_energy = written[10].total_energy();
+    write_file(&input, &written, 50.0);
+    let total: f64 = written.iter().map(|record| record.get_weight() as f64).sum();
+    for &target in [1, 17, 50, 333].iter() {
+        let output = dir.path("out.egsphsp1");
+        resample_to(&input, &output, target, 9).unwrap();
+        let resampled = read_file(&output);
+        assert_eq!(resampled.len(), target);
+        let weight: f64 = resampled.iter().map(|record| record.get_weight() as f64).sum();
+        assert!((weight - total).abs() < 1e-4 * total);
+        assert!(resampled.iter().all(|record| record.get_weight() == resampled[0].get_weight()));
+        assert!(resampled.iter().all(|record| record.total_energy() != zero_energy));
+    }
+}
+
+#[test]
+fn resampling_rejects_an_empty_target_or_weight() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let mut weightless = photon(1.0, 0.0, 0.0);
+    weightless.set_weight(0.0);
+    write_file(&input, &[weightless], 1.0);
+    assert!(matches!(resample_to(&input, &output, 0, 1), Err(EGSError::InvalidArgument(_))));
+    assert!(matches!(resample_to(&input, &output, 5, 1), Err(EGSError::ZeroWeight)));
+    assert!(!output.exists());
+}
+
+#[test]
+fn raw_copy_is_byte_identical() {
+    let dir = TempDir::new();
+    for &mode2 in [false, true].iter() {
+        let input = dir.path("in.egsphsp1");
+        let output = dir.path("out.egsphsp1");
+        if mode2 {
+            write_mode2_file(&input, &records(40), 40.0);
+        } else {
+            write_file(&input, &records(40), 40.0);
+        }
+        let mut reader = PHSPReader::open(&input).unwrap();
+        let record_size = reader.header.record_size as usize;
+        let mut writer = PHSPWriter::create(&output, &reader.header).unwrap();
+        for bytes in reader.raw_records() {
+            let bytes = bytes.unwrap();
+            // MODE0 records come zero padded
+            assert!(bytes[record_size..].iter().all(|&byte| byte == 0));
+            writer.write_raw(&bytes[..record_size]).unwrap();
+        }
+        writer.finalize().unwrap();
+        assert_eq!(fs::read(&output).unwrap(), fs::read(&input).unwrap());
+    }
+}
+
+#[test]
+fn combine_copies_records_byte_for_byte() {
+    let dir = TempDir::new();
+    let first = dir.path("a.egsphsp1");
+    let second = dir.path("b.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let all = records(30);
+    let header = write_mode2_file(&first, &all[..12], 12.0);
+    write_mode2_file(&second, &all[12..], 18.0);
+    combine(&[&first, &second], &output, false).unwrap();
+    let skip = header.record_size as usize;
+    let mut expected = fs::read(&first).unwrap()[skip..].to_vec();
+    expected.extend_from_slice(&fs::read(&second).unwrap()[skip..]);
+    assert_eq!(&fs::read(&output).unwrap()[skip..], &expected[..]);
+}
+
+#[test]
+fn write_raw_checks_the_length() {
+    let header = Header::new_mode0(0, 0, 1.0).unwrap();
+    let mut writer = PHSPWriter::in_memory(&header).unwrap();
+    for &length in [27, 32].iter() {
+        let bytes = vec![0u8; length];
+        let expected = EGSError::WrongRecordSize { expected: 28, actual: length as u64 };
+        let error = writer.write_raw(&bytes).unwrap_err();
+        assert_eq!(format!("{:?}", error), format!("{:?}", expected));
+    }
+    assert_eq!(writer.into_bytes().unwrap().len(), 28);
+}
+
+#[test]
+fn dry_run_combine_leaves_everything_as_it_was() {
+    let dir = TempDir::new();
+    let first = dir.path("a.egsphsp1");
+    let second = dir.path("b.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&first, &records(10), 10.0);
+    write_file(&second, &records(5), 5.0);
+    let before = (fs::read(&first).unwrap(), fs::read(&second).unwrap());
+    let regions = [None, None];
+    combine_tagged(&[&first, &second], &regions, &output, true, true, |_, _| ()).unwrap();
+    assert_eq!((fs::read(&first).unwrap(), fs::read(&second).unwrap()), before);
+    assert!(!output.exists());
+    // nor is anything left behind next to it
+    assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 2);
+}
+
+#[test]
+fn dry_run_sample_and_transform_write_nothing() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    write_file(&input, &records(20), 20.0);
+    let before = fs::read(&input).unwrap();
+    sample_with_progress(&[&input], &output, 2, 3, true, |_, _| ()).unwrap();
+    assert!(!output.exists());
+    let matrix = [[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+    transform_with_progress(&input, &output, &matrix, true, |_, _| ()).unwrap();
+    assert!(!output.exists());
+    transform_with_progress(&input, &input, &matrix, true, |_, _| ()).unwrap();
+    assert_eq!(fs::read(&input).unwrap(), before);
+    assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 1);
+}
+
+#[test]
+fn missing_source_particles_fall_back_to_the_particle_count() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let header = write_file(&input, &records(10), 0.0);
+    assert!(!header.has_source_particles());
+    assert_eq!(header.effective_source_particles(), 10.0);
+    let counted = write_file(&dir.path("counted.egsphsp1"), &records(10), 4.0);
+    assert_eq!(counted.effective_source_particles(), 4.0);
+    // so sampling still has something finite to normalise by
+    sample(&[&input], &output, 2, 1).unwrap();
+    let sampled = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(sampled.total_particles_in_source, 5.0);
+}
+
+#[test]
+fn z_direction_lives_in_the_weight_sign() {
+    let mut record = photon(1.0, 0.0, 0.0);
+    record.set_weight(2.5);
+    assert_eq!(record.z_direction(), 1.0);
+    record.set_z_direction(false);
+    assert!(!record.z_positive());
+    assert_eq!(record.z_direction(), -1.0);
+    assert_eq!(record.get_weight(), 2.5);
+    // setting the same direction twice changes nothing
+    record.set_z_direction(false);
+    assert_eq!(record.weight, -2.5);
+    record.set_z_direction(true);
+    assert!(record.z_positive());
+    assert_eq!(record.get_weight(), 2.5);
+    // and a weight change keeps the direction
+    record.set_z_direction(false);
+    record.set_weight(0.5);
+    assert_eq!(record.z_direction(), -1.0);
+}
+
+#[test]
+fn flipping_z_twice_restores_the_file() {
+    let dir = TempDir::new();
+    let once = dir.path("once.egsphsp1");
+    let twice = dir.path("twice.egsphsp1");
+    for &mode2 in [false, true].iter() {
+        let input = dir.path("in.egsphsp1");
+        let mut written = records(12);
+        written[3].set_z_direction(false);
+        if mode2 {
+            write_mode2_file(&input, &written, 12.0);
+        } else {
+            write_file(&input, &written, 12.0);
+        }
+        flip_z(&input, &once, true).unwrap();
+        for (original, flipped) in read_file(&input).iter().zip(read_file(&once).iter()) {
+            assert_eq!(flipped.z_positive(), !original.z_positive());
+            assert_eq!(flipped.get_weight(), original.get_weight());
+            assert_eq!(flipped.zlast, original.zlast.map(|zlast| -zlast));
+        }
+        flip_z(&once, &twice, true).unwrap();
+        assert_eq!(fs::read(&twice).unwrap(), fs::read(&input).unwrap());
+    }
+}
+
+#[test]
+fn tiny_buffers_read_and_write_the_same() {
+    let dir = TempDir::new();
+    let expected = read_file(&fixture());
+    let capacities = [1, 5, 33];
+    for &capacity in capacities.iter() {
+        let file = File::open(fixture()).unwrap();
+        let reader = PHSPReader::from_with_capacity(file, capacity).unwrap();
+        let read: Vec<Record> = reader.map(|record| record.unwrap()).collect();
+        assert_eq!(read, expected);
+        let output = dir.path("out.egsphsp1");
+        let header = Header::new_mode0(0, 0, 10.0).unwrap();
+        let file = File::create(&output).unwrap();
+        let mut writer = PHSPWriter::from_with_capacity(file, &header, capacity).unwrap();
+        for record in expected[..100].iter() {
+            writer.write(record).unwrap();
+        }
+        writer.finalize().unwrap();
+        assert_eq!(read_file(&output), &expected[..100]);
+    }
+}
+
+#[test]
+fn fluence_map_fills_known_cells() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let placed = [(-1.5, -1.0, 1.0),
+                  (0.5, 1.0, 2.0),
+                  (2.0, 2.0, 0.5),
+                  (5.0, -0.5, 4.0),
+                  (f32::NAN, 0.0, 8.0)];
+    let written: Vec<Record> = placed.iter()
+        .map(|&(x, y, weight)| {
+            let mut record = photon(1.0, x, y);
+            record.set_weight(weight);
+            record
+        })
+        .collect();
+    write_file(&input, &written, 5.0);
+    let discarded = fluence_map(&input, 4, 2, 2.0, ClampMode::Discard).unwrap();
+    assert_eq!(discarded,
+               vec![vec![1.0, 0.0], vec![0.0, 0.0], vec![0.0, 2.0], vec![0.0, 0.5]]);
+    // clamping moves the outsider to the nearest edge, NaN is still dropped
+    let clamped = fluence_map(&input, 4, 2, 2.0, ClampMode::Clamp).unwrap();
+    assert_eq!(clamped,
+               vec![vec![1.0, 0.0], vec![0.0, 0.0], vec![0.0, 2.0], vec![4.0, 0.5]]);
+    assert!(matches!(fluence_map(&input, 0, 2, 2.0, ClampMode::Clamp),
+                     Err(EGSError::InvalidArgument(_))));
+    assert!(matches!(fluence_map(&input, 4, 2, 0.0, ClampMode::Clamp),
+                     Err(EGSError::InvalidArgument(_))));
+}
+
+#[test]
+fn angular_histogram_bins_known_directions() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let degrees = |angle: f32| angle.to_radians().sin();
+    let mut backward = Record::new(0, 1.0, 0.0, 0.0, degrees(10.0), 0.0, 3.0);
+    backward.set_z_direction(false);
+    let written = [Record::new(0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0),
+                   Record::new(0, 1.0, 0.0, 0.0, degrees(45.0), 0.0, 2.0),
+                   Record::new(0, 1.0, 0.0, 0.0, 0.0, degrees(75.0), 0.5),
+                   Record::new(0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.25),
+                   Record::new(0, 1.0, 0.0, 0.0, 0.9, 0.9, 1.0),
+                   backward];
+    write_file(&input, &written, 6.0);
+    // three 30 degree bins, backward particles fold onto forward ones
+    let histogram = angular_histogram(&input, 3).unwrap();
+    assert_eq!(histogram.counts, vec![2, 1, 2]);
+    assert_eq!(histogram.weights, vec![4.0, 2.0, 0.75]);
+    assert_eq!(histogram.invalid, 1);
+    assert!(matches!(angular_histogram(&input, 0), Err(EGSError::InvalidArgument(_))));
+}
+
+#[test]
+fn latch_filter_matches_filtering_decoded_records() {
+    let all = read_file(&fixture());
+    let mut reader = PHSPReader::open(&fixture()).unwrap();
+    let charged: Vec<Record> = reader
+        .filter_latch(|latch| particle_type_of(latch) != ParticleType::Photon)
+        .map(|record| record.unwrap())
+        .collect();
+    let expected: Vec<Record> = all.iter()
+        .filter(|record| record.particle_type() != ParticleType::Photon)
+        .cloned()
+        .collect();
+    assert!(!charged.is_empty());
+    assert_eq!(charged, expected);
+    // the reader carries on from where the filter stopped, here the end
+    assert!(reader.next().is_none());
+    let mut reader = PHSPReader::open(&fixture()).unwrap();
+    let produced: Vec<Record> = reader.filter_latch(bremsstrahlung_or_annihilation_of)
+        .map(|record| record.unwrap())
+        .collect();
+    let expected: Vec<Record> = all.into_iter()
+        .filter(|record| record.bremsstrahlung_or_annihilation())
+        .collect();
+    assert_eq!(produced, expected);
+}
+
+#[test]
+fn appending_updates_the_header_counts() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    let all = records(9);
+    write_file(&path, &all[..5], 5.0);
+    let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    let mut writer = PHSPWriter::append(file).unwrap();
+    for record in all[5..].iter() {
+        writer.write(record).unwrap();
+    }
+    writer.finalize().unwrap();
+    let header = PHSPReader::open(&path).unwrap().header;
+    assert_eq!(header.total_particles, 9);
+    assert_eq!(header.total_photons, 6);
+    assert_eq!(header.min_energy, all[0].total_energy());
+    assert_eq!(header.max_energy, all[8].total_energy());
+    assert_eq!(read_file(&path), all);
+}
+
+#[test]
+fn appending_refuses_a_file_its_header_does_not_fit() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(3), 3.0);
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0; 5]).unwrap();
+    let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    assert!(matches!(PHSPWriter::append(file), Err(EGSError::BadLength)));
+}
+
+#[test]
+fn sanitizing_writer_refuses_non_finite_fields() {
+    let header = Header::new_mode2(0, 0, 1.0).unwrap();
+    let mut writer = PHSPWriter::in_memory(&header).unwrap().with_sanitize(true);
+    writer.write(&Record { zlast: Some(1.0), ..photon(1.0, 0.0, 0.0) }).unwrap();
+    let mut bad = Record { zlast: Some(1.0), ..photon(1.0, 0.0, 0.0) };
+    bad.x_cos = f32::NAN;
+    assert!(matches!(writer.write(&bad), Err(EGSError::NonFinite { record: 1, field: "x_cos" })));
+    let bad = Record { zlast: Some(f32::INFINITY), ..photon(1.0, 0.0, 0.0) };
+    assert!(matches!(writer.write(&bad), Err(EGSError::NonFinite { record: 1, field: "zlast" })));
+    // nothing of the rejected records reached the output
+    assert_eq!(writer.into_bytes().unwrap().len(), 64);
+    // and without sanitizing the NaN goes through
+    let mut writer = PHSPWriter::in_memory(&header).unwrap();
+    let mut bad = Record { zlast: Some(1.0), ..photon(1.0, 0.0, 0.0) };
+    bad.x_cos = f32::NAN;
+    writer.write(&bad).unwrap();
+}
+
+#[test]
+fn big_endian_files_decode_like_little_endian_ones() {
+    let dir = TempDir::new();
+    let little = dir.path("little.egsphsp1");
+    let big = dir.path("big.egsphsp1");
+    for &mode2 in [false, true].iter() {
+        let (header, zlast) = if mode2 {
+            (Header::new_mode2(0, 0, 20.0).unwrap(), Some(2.5))
+        } else {
+            (Header::new_mode0(0, 0, 20.0).unwrap(), None)
+        };
+        let written: Vec<Record> = records(20)
+            .into_iter()
+            .map(|record| Record { zlast, ..record })
+            .collect();
+        let outputs = [(&little, ByteOrderMode::Little), (&big, ByteOrderMode::Big)];
+        for &(path, order) in outputs.iter() {
+            let file = File::create(path).unwrap();
+            let mut writer = PHSPWriter::from_with_byte_order(file, &header, order).unwrap();
+            for record in written.iter() {
+                writer.write(record).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        let reader = PHSPReader::open(&big).unwrap();
+        assert_eq!(reader.byte_order(), ByteOrderMode::Big);
+        assert_eq!(format!("{:?}", reader.header),
+                   format!("{:?}", PHSPReader::open(&little).unwrap().header));
+        assert_eq!(read_file(&big), written);
+        assert_eq!(read_file(&little), written);
+        // every record word is the little endian one reversed
+        let skip = header.record_size as usize;
+        let (little_bytes, big_bytes) = (fs::read(&little).unwrap(), fs::read(&big).unwrap());
+        for (a, b) in little_bytes[skip..].chunks(4).zip(big_bytes[skip..].chunks(4)) {
+            assert!(a.iter().eq(b.iter().rev()));
+        }
+    }
+}
+
+#[test]
+fn reversed_reader_yields_records_last_first() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    // more records than one buffer holds, so the reader seeks back more than once
+    let count = BUFFER_CAPACITY / 28 + 1000;
+    let written = records(count);
+    write_file(&path, &written, count as f32);
+    let mut reader = PHSPReader::open(&path).unwrap();
+    // records read already make no difference
+    reader.next().unwrap().unwrap();
+    let reversed: Vec<Record> = reader.rev().map(|record| record.unwrap()).collect();
+    let expected: Vec<Record> = written.into_iter().rev().collect();
+    assert_eq!(reversed, expected);
+    let empty = dir.path("empty.egsphsp1");
+    write_file(&empty, &[], 1.0);
+    assert!(PHSPReader::open(&empty).unwrap().rev().next().is_none());
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn reversing_a_gzip_file_is_unsupported() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1.gz");
+    let mut writer = PHSPWriter::create(&path, &Header::new_mode0(1, 1, 1.0).unwrap()).unwrap();
+    writer.write(&photon(1.0, 0.0, 0.0)).unwrap();
+    writer.finish().unwrap();
+    let mut reversed = PHSPReader::open(&path).unwrap().rev();
+    assert!(matches!(reversed.next(), Some(Err(EGSError::Unsupported(_)))));
+    assert!(reversed.next().is_none());
+}
+
+#[test]
+fn photon_only_combine_matches_cropping_first() {
+    let dir = TempDir::new();
+    let all = records(30);
+    let inputs = [(dir.path("a.egsphsp1"), &all[..10], 10.0),
+                  (dir.path("b.egsphsp1"), &all[10..], 20.0)];
+    let mut cropped = Vec::new();
+    for (i, &(ref path, part, histories)) in inputs.iter().enumerate() {
+        write_file(path, part, histories);
+        let photons: Vec<Record> =
+            part.iter().filter(|record| !record.charged()).cloned().collect();
+        let crop = dir.path(&format!("crop{}.egsphsp1", i));
+        write_file(&crop, &photons, histories);
+        cropped.push(crop);
+    }
+    let output = dir.path("out.egsphsp1");
+    combine_filtered(&[&inputs[0].0, &inputs[1].0], &output, false, |record| !record.charged())
+        .unwrap();
+    let expected = dir.path("expected.egsphsp1");
+    combine(&[&cropped[0], &cropped[1]], &expected, false).unwrap();
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(header.total_particles, header.total_photons);
+    assert_eq!(header.total_particles, 20);
+    assert_eq!(header.total_particles_in_source, 30.0);
+    assert_eq!(fs::read(&output).unwrap(), fs::read(&expected).unwrap());
+}
+
+#[test]
+fn raw_energy_keeps_the_first_of_history_sign() {
+    let first = photon(-1.5, 0.0, 0.0);
+    assert!(first.first_scored_by_primary_history());
+    assert_eq!(first.raw_energy(), -1.5);
+    assert_eq!(first.total_energy(), 1.5);
+    let later = photon(1.5, 0.0, 0.0);
+    assert!(!later.first_scored_by_primary_history());
+    assert_eq!(later.raw_energy(), 1.5);
+    // and feeding it back to `Record::new` keeps the mark
+    let copy = Record::new(first.latch, first.raw_energy(), 0.0, 0.0, 0.0, 0.0, 1.0);
+    assert!(copy.first_scored_by_primary_history());
+}
+
+#[test]
+fn header_only_files_give_valid_empty_results() {
+    let dir = TempDir::new();
+    let empty = dir.path("empty.egsphsp1");
+    let other = dir.path("other.egsphsp1");
+    write_file(&empty, &[], 10.0);
+    write_file(&other, &[], 5.0);
+    let combined = dir.path("combined.egsphsp1");
+    combine(&[&empty, &other], &combined, false).unwrap();
+    let sampled = dir.path("sampled.egsphsp1");
+    sample(&[&empty], &sampled, 2, 1).unwrap();
+    let transformed = dir.path("transformed.egsphsp1");
+    let matrix = [[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+    transform(&empty, &transformed, &matrix).unwrap();
+    transform(&other, &other, &matrix).unwrap();
+    let outputs = [(&combined, 15.0), (&sampled, 5.0), (&transformed, 10.0), (&other, 5.0)];
+    for &(path, histories) in outputs.iter() {
+        let header = PHSPReader::open(path).unwrap().header;
+        assert_eq!((header.total_particles, header.total_photons), (0, 0));
+        assert_eq!((header.min_energy, header.max_energy), (0.0, 0.0));
+        assert_eq!(header.total_particles_in_source, histories);
+        assert_eq!(fs::metadata(path).unwrap().len(), header.record_size);
+        assert!(read_file(path).is_empty());
+    }
+    let statistics = statistics(&empty).unwrap();
+    assert_eq!(statistics.records, 0);
+    assert_eq!(statistics.energy.weight, 0.0);
+}
+
+#[test]
+fn empty_inputs_leave_the_energy_range_alone() {
+    let dir = TempDir::new();
+    let empty = dir.path("empty.egsphsp1");
+    let full = dir.path("full.egsphsp1");
+    write_file(&empty, &[], 1.0);
+    let written = [photon(2.0, 0.0, 0.0), photon(3.0, 0.0, 0.0)];
+    write_file(&full, &written, 2.0);
+    let combined = dir.path("combined.egsphsp1");
+    combine(&[&empty, &full], &combined, false).unwrap();
+    let header = PHSPReader::open(&combined).unwrap().header;
+    assert_eq!((header.min_energy, header.max_energy), (2.0, 3.0));
+    assert_eq!(header.total_particles, 2);
+}
+
+#[test]
+fn energy_extents_are_kept_per_particle_type() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let written = [photon(0.5, 0.0, 0.0),
+                   photon(4.0, 0.0, 0.0),
+                   charged(1.5, false),
+                   charged(2.5, false),
+                   charged(3.0, true),
+                   // a history's first particle is left out, like the header extents
+                   photon(-9.0, 0.0, 0.0),
+                   charged(-0.1, true)];
+    write_file(&input, &written, 7.0);
+    let extents = energy_extents_by_type(&input).unwrap();
+    assert_eq!(extents.len(), 3);
+    assert_eq!(extents[&ParticleType::Photon], (0.5, 4.0));
+    assert_eq!(extents[&ParticleType::Electron], (1.5, 2.5));
+    assert_eq!(extents[&ParticleType::Positron], (3.0, 3.0));
+}
+
+#[test]
+fn grid_thinning_caps_cells_and_keeps_their_weight() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    // a hot spot of 100 in one cell, 10 in another and 3 in a sparse one
+    let clusters = [(0.0, 0.0, 100), (5.0, 5.0, 10), (-1.0, 0.0, 3)];
+    let mut written = Vec::new();
+    for &(x, y, count) in clusters.iter() {
+        for i in 0..count {
+            let offset = 0.05 + 0.9 * i as f32 / count as f32;
+            let mut record = photon(1.0 + written.len() as f32 * 0.01, x + offset, y + offset);
+            record.set_weight(1.0 + (i % 5) as f32);
+            written.push(record);
+        }
+    }
+    write_file(&input, &written, 113.0);
+    grid_thin(&input, &output, 1.0, 8, 21).unwrap();
+    let thinned = read_file(&output);
+    let cell = |record: &Record| (record.x_cm.floor() as i64, record.y_cm.floor() as i64);
+    for &(x, y, count) in clusters.iter() {
+        let key = (x as i64, y as i64);
+        let before: Vec<&Record> = written.iter().filter(|record| cell(record) == key).collect();
+        let after: Vec<&Record> = thinned.iter().filter(|record| cell(record) == key).collect();
+        assert_eq!(after.len(), count.min(8));
+        let weight = |records: &[&Record]| -> f64 {
+            records.iter().map(|record| record.get_weight() as f64).sum()
+        };
+        assert!((weight(&after) - weight(&before)).abs() < 1e-4 * weight(&before));
+        if count <= 8 {
+            assert_eq!(after, before);
+        }
+    }
+    assert!(matches!(grid_thin(&input, &output, 0.0, 8, 1), Err(EGSError::InvalidArgument(_))));
+    assert!(matches!(grid_thin(&input, &output, 1.0, 0, 1), Err(EGSError::InvalidArgument(_))));
+}
+
+#[test]
+fn resumed_combine_matches_an_uninterrupted_one() {
+    let dir = TempDir::new();
+    let all = records(40);
+    let paths: Vec<PathBuf> = (0..4).map(|i| dir.path(&format!("{}.egsphsp1", i))).collect();
+    for (path, part) in paths.iter().zip(all.chunks(10)) {
+        write_file(path, part, 10.0);
+    }
+    let inputs: Vec<&Path> = paths.iter().map(|path| path.as_path()).collect();
+    let expected = dir.path("expected.egsphsp1");
+    combine_resumable(&inputs, &expected, false, |_, _| ()).unwrap();
+    // stop a run one record into the third input, after two checkpoints
+    let output = dir.path("out.egsphsp1");
+    let interrupted = panic::catch_unwind(AssertUnwindSafe(|| {
+        combine_resumable(&inputs, &output, false, |done, _| {
+            assert!(done <= 20, "interrupted");
+        })
+    }));
+    assert!(interrupted.is_err());
+    let partial = dir.path("out.egsphsp1.partial");
+    assert!(partial.exists() && dir.path("out.egsphsp1.progress").exists());
+    assert!(!output.exists());
+    // whatever was written past the checkpoint is cut away again
+    OpenOptions::new().append(true).open(&partial).unwrap().write_all(&[7; 45]).unwrap();
+    combine_resumable(&inputs, &output, false, |_, _| ()).unwrap();
+    assert_eq!(fs::read(&output).unwrap(), fs::read(&expected).unwrap());
+    assert_eq!(read_file(&output), all);
+    assert!(!partial.exists() && !dir.path("out.egsphsp1.progress").exists());
+}
+
+#[test]
+fn resuming_after_an_input_changed_is_refused() {
+    let dir = TempDir::new();
+    let first = dir.path("a.egsphsp1");
+    let second = dir.path("b.egsphsp1");
+    write_file(&first, &records(5), 5.0);
+    write_file(&second, &records(5), 5.0);
+    let output = dir.path("out.egsphsp1");
+    let interrupted = panic::catch_unwind(AssertUnwindSafe(|| {
+        combine_resumable(&[&first, &second], &output, false, |done, _| {
+            assert!(done <= 5, "interrupted");
+        })
+    }));
+    assert!(interrupted.is_err());
+    write_file(&second, &records(6), 6.0);
+    let resumed = combine_resumable(&[&first, &second], &output, false, |_, _| ());
+    assert!(matches!(resumed, Err(EGSError::StaleCheckpoint(_))));
+    assert!(!output.exists());
+}
+
+#[test]
+fn latch_is_described_bit_by_bit() {
+    // produced, bits 3 and 7, region 5, a positron that crossed more than once
+    let latch = 1 | 1 << 3 | 1 << 7 | 5 << REGION_SHIFT | 1 << 29 | 1 << 31;
+    let mut record = Record::new(latch, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0);
+    record.set_z_direction(false);
+    let expected = "latch:          0xa5000089 (1010 0101 0000 0000 0000 0000 1000 1001)\n\
+                    \x20 0      brems/annih:    true\n\
+                    \x20 1-23   bits set:       3, 7\n\
+                    \x20 24-28  region:         5\n\
+                    \x20 29     bit 29:         true\n\
+                    \x20 30     charged:        false\n\
+                    \x20 31     multiple cross: true\n\
+                    particle:       Positron\n\
+                    z direction:    -z\n";
+    assert_eq!(record.describe_latch(), expected);
+    let photon = photon(1.0, 0.0, 0.0).describe_latch();
+    assert!(photon.contains("  1-23   bits set:       none\n"));
+    assert!(photon.ends_with("particle:       Photon\nz direction:    +z\n"));
+}
+
+#[test]
+fn reader_works_over_a_cursor() {
+    let bytes = fs::read(fixture()).unwrap();
+    let expected = read_file(&fixture());
+    let reader = PHSPReader::from_reader(Cursor::new(bytes.clone())).unwrap();
+    assert_eq!(format!("{:?}", reader.header),
+               format!("{:?}", PHSPReader::open(&fixture()).unwrap().header));
+    let read: Vec<Record> = reader.map(|record| record.unwrap()).collect();
+    assert_eq!(read, expected);
+    // a cursor seeks, so random access works as it does for files
+    let mut reader = PHSPReader::from_reader(Cursor::new(bytes)).unwrap();
+    reader.seek_to_record(100).unwrap();
+    assert_eq!(reader.peek().unwrap().unwrap(), expected[100]);
+    assert_eq!(reader.next().unwrap().unwrap(), expected[100]);
+    let last: Vec<Record> = reader.rev().take(2).map(|record| record.unwrap()).collect();
+    assert_eq!(last, [expected[expected.len() - 1], expected[expected.len() - 2]]);
+}
+
+#[test]
+fn in_memory_round_trip() {
+    let written = records(7);
+    for &mode2 in [false, true].iter() {
+        let header = if mode2 {
+            Header::new_mode2(0, 0, 7.0).unwrap()
+        } else {
+            Header::new_mode0(0, 0, 7.0).unwrap()
+        };
+        let zlast = if mode2 { Some(-3.0) } else { None };
+        let mut writer = PHSPWriter::in_memory(&header).unwrap();
+        for record in written.iter() {
+            writer.write(&Record { zlast, ..*record }).unwrap();
+        }
+        let bytes = writer.into_bytes().unwrap();
+        assert_eq!(bytes.len() as u64, header.record_size * 8);
+        let reader = PHSPReader::from_bytes(&bytes).unwrap();
+        assert_eq!(reader.header.total_particles, 7);
+        assert_eq!(reader.header.total_photons, 5);
+        let read: Vec<Record> = reader.map(|record| record.unwrap()).collect();
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(written.iter()) {
+            assert!(read.similar_to(&Record { zlast, ..*written }));
+        }
+    }
+    // the known length is checked against the header
+    let mut writer = PHSPWriter::in_memory(&Header::new_mode0(0, 0, 1.0).unwrap()).unwrap();
+    writer.write(&photon(1.0, 0.0, 0.0)).unwrap();
+    let bytes = writer.into_bytes().unwrap();
+    assert!(PHSPReader::from_bytes(&bytes[..40]).is_err());
+}
+
+#[test]
+fn interleaved_combine_alternates_sources() {
+    let dir = TempDir::new();
+    let first = dir.path("a.egsphsp1");
+    let second = dir.path("b.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let all = records(8);
+    let (a, b) = all.split_at(3);
+    write_file(&first, a, 3.0);
+    write_file(&second, b, 5.0);
+    combine_interleaved(&[&first, &second], &output, false).unwrap();
+    // turn about until the shorter input runs out, then the rest of the longer one
+    let expected = [a[0], b[0], a[1], b[1], a[2], b[2], b[3], b[4]];
+    assert_eq!(read_file(&output), expected);
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(header.total_particles, 8);
+    assert_eq!(header.total_particles_in_source, 8.0);
+    assert!(first.exists() && second.exists());
+}
+
+#[test]
+fn weight_audit_finds_and_repair_fixes_zero_weights() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let weights = [1.0, 0.0, -0.0, f32::NAN, -2.0];
+    let written: Vec<Record> = weights.iter()
+        .map(|&weight| Record::new(0, 1.0, 0.0, 0.0, 0.0, 0.0, weight))
+        .collect();
+    write_file(&input, &written, 5.0);
+    let valid: Vec<bool> = written.iter().map(Record::has_valid_weight).collect();
+    assert_eq!(valid, [true, false, false, false, true]);
+    let audit = audit_weights(&input).unwrap();
+    assert_eq!((audit.records, audit.zero, audit.non_finite, audit.negative_z), (5, 2, 1, 2));
+    // the stored sign of the zero is kept unless a direction is given
+    assert_eq!(repair_weights(&input, &output, None).unwrap(), 2);
+    let repaired = read_file(&output);
+    assert!(repaired[1].has_valid_weight() && repaired[1].z_positive());
+    assert!(repaired[2].has_valid_weight() && !repaired[2].z_positive());
+    assert_eq!(repaired[2].get_weight(), f32::MIN_POSITIVE);
+    assert_eq!(audit_weights(&output).unwrap().zero, 0);
+    repair_weights(&input, &output, Some(true)).unwrap();
+    assert!(read_file(&output)[2].z_positive());
+}
+
+#[test]
+fn weighted_combine_mixes_in_the_given_ratio() {
+    let dir = TempDir::new();
+    let first = dir.path("a.egsphsp1");
+    let second = dir.path("b.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    // one particle per history in both, so each alone gives the same tally
+    write_file(&first, &records(10), 10.0);
+    write_file(&second, &records(20), 20.0);
+    combine_weighted(&[&first, &second], &[1.0, 3.0], &output, false).unwrap();
+    let combined = read_file(&output);
+    let sum = |records: &[Record]| -> f64 {
+        records.iter().map(|record| record.get_weight() as f64).sum()
+    };
+    let (a, b) = (sum(&combined[..10]), sum(&combined[10..]));
+    assert!((b / a - 3.0).abs() < 1e-6);
+    assert!((a + b - 30.0).abs() < 1e-4);
+    assert_eq!(PHSPReader::open(&output).unwrap().header.total_particles_in_source, 30.0);
+    // in the ratio of the histories it is a plain combine
+    let plain = dir.path("plain.egsphsp1");
+    combine(&[&first, &second], &plain, false).unwrap();
+    combine_weighted(&[&first, &second], &[1.0, 2.0], &output, false).unwrap();
+    assert_eq!(fs::read(&output).unwrap(), fs::read(&plain).unwrap());
+    // a zero weight leaves an input out, histories and all
+    combine_weighted(&[&first, &second], &[0.0, 1.0], &output, false).unwrap();
+    assert_eq!(read_file(&output), records(20));
+    assert_eq!(PHSPReader::open(&output).unwrap().header.total_particles_in_source, 20.0);
+    let bad = combine_weighted(&[&first, &second], &[1.0], &output, false);
+    assert!(matches!(bad, Err(EGSError::InvalidArgument(_))));
+}
+
+#[test]
+fn records_compare_and_hash_by_their_bits() {
+    use std::collections::hash_map::DefaultHasher;
+    let hash = |record: &Record| {
+        let mut hasher = DefaultHasher::new();
+        record.hash(&mut hasher);
+        hasher.finish()
+    };
+    let a = Record::new(3, 1.5, 2.0, -1.0, 0.1, 0.2, 0.5);
+    let b = Record::new(3, 1.5, 2.0, -1.0, 0.1, 0.2, 0.5);
+    assert_eq!(a, b);
+    assert_eq!(hash(&a), hash(&b));
+    // a NaN equals itself, but not a NaN with other bits
+    let nan = Record::new(3, 1.5, f32::NAN, -1.0, 0.1, 0.2, 0.5);
+    let quiet = f32::from_bits(f32::NAN.to_bits() | 1);
+    let other_nan = Record::new(3, 1.5, quiet, -1.0, 0.1, 0.2, 0.5);
+    assert_eq!(nan, nan);
+    assert_ne!(nan, other_nan);
+    // nor is a zero weight heading the other way
+    assert_ne!(Record::new(0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0),
+               Record::new(0, 1.0, 0.0, 0.0, 0.0, 0.0, -0.0));
+    assert_ne!(Record { zlast: Some(1.0), ..a }, a);
+    let set: HashSet<Record> = [a, b, nan, nan, other_nan].iter().cloned().collect();
+    assert_eq!(set.len(), 3);
+}
+
+#[test]
+fn expected_sizes_match_the_files_written() {
+    let dir = TempDir::new();
+    let first = dir.path("a.egsphsp1");
+    let second = dir.path("b.egsphsp1");
+    for &count in [0, 1, 250].iter() {
+        let header = write_file(&first, &records(count), 1.0);
+        assert_eq!(header.expected_file_size(), fs::metadata(&first).unwrap().len());
+        let header = write_mode2_file(&first, &records(count), 1.0);
+        assert_eq!(header.expected_file_size(), fs::metadata(&first).unwrap().len());
+    }
+    write_file(&first, &records(3000), 3000.0);
+    write_file(&second, &records(17), 17.0);
+    let output = dir.path("out.egsphsp1");
+    let estimate = combined_size(&[&first, &second]).unwrap();
+    combine(&[&first, &second], &output, false).unwrap();
+    assert_eq!(estimate, fs::metadata(&output).unwrap().len());
+    // a sample is only known on average
+    let estimate = sampled_size(&[&first], 4, 4).unwrap() as f64;
+    sample(&[&first], &output, 4, 5).unwrap();
+    let actual = fs::metadata(&output).unwrap().len() as f64;
+    assert!((actual - estimate).abs() < 0.1 * estimate);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn shared_reads_from_many_threads_match_sequential_ones() {
+    let expected = read_file(&fixture());
+    let shared = SharedPHSP::open(&fixture()).unwrap();
+    assert_eq!(shared.len(), expected.len() as u64);
+    let threads = 4;
+    // every thread takes its own stride of records from the one mapping
+    let read: Vec<Vec<(u64, Record)>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|thread| {
+                let shared = &shared;
+                scope.spawn(move || {
+                    (thread..shared.len())
+                        .step_by(threads as usize)
+                        .map(|index| (index, shared.record(index).unwrap()))
+                        .collect()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+    assert_eq!(read.iter().map(Vec::len).sum::<usize>(), expected.len());
+    for &(index, record) in read.iter().flatten() {
+        assert_eq!(record, expected[index as usize]);
+    }
+    // clones share the mapping and can be moved to other threads
+    let clone = shared.clone();
+    let last = std::thread::spawn(move || clone.record(clone.len() - 1).unwrap()).join().unwrap();
+    assert_eq!(last, expected[expected.len() - 1]);
+    let out_of_range = shared.record(shared.len());
+    assert!(matches!(out_of_range, Err(EGSError::RecordOutOfRange(_))));
+}
+
+#[test]
+fn stratified_sampling_keeps_each_band_at_its_rate() {
+    let dir = TempDir::new();
+    let input = dir.path("in.egsphsp1");
+    let output = dir.path("out.egsphsp1");
+    let mut written = Vec::new();
+    for &(energy, count) in [(0.5, 4000), (2.0, 4000), (10.0, 1000)].iter() {
+        written.extend((0..count).map(|_| photon(energy, 0.0, 0.0)));
+    }
+    write_file(&input, &written, 9000.0);
+    // 2 MeV sits in both of the last bands, the first one wins
+    let bands = [(0.0, 1.0, 2), (1.0, 5.0, 10), (1.5, 3.0, 1)];
+    sample_stratified(&input, &output, &bands, 13).unwrap();
+    let sampled = read_file(&output);
+    let kept = |energy: f32| sampled.iter().filter(move |record| record.total_energy() == energy);
+    let (low, high) = (kept(0.5).count() as f64, kept(2.0).count() as f64);
+    // about five standard deviations either way
+    assert!((low - 2000.0).abs() < 160.0);
+    assert!((high - 400.0).abs() < 100.0);
+    assert_eq!(kept(10.0).count(), 0);
+    // weights make up for the rate
+    assert!(kept(0.5).all(|record| record.get_weight() == 2.0));
+    assert!(kept(2.0).all(|record| record.get_weight() == 10.0));
+    let header = PHSPReader::open(&output).unwrap().header;
+    assert_eq!(header.total_particles as usize, sampled.len());
+    assert_eq!((header.min_energy, header.max_energy), (0.5, 2.0));
+    assert_eq!(header.total_particles_in_source, 9000.0);
+    let bad = sample_stratified(&input, &output, &[(0.0, 1.0, 0)], 1);
+    assert!(matches!(bad, Err(EGSError::InvalidArgument(_))));
+}
+
+#[test]
+fn verified_count_reports_an_overcounting_header() {
+    let dir = TempDir::new();
+    let path = dir.path("in.egsphsp1");
+    write_file(&path, &records(6), 6.0);
+    let counted = count_records(&path).unwrap();
+    assert!(counted.particles_match() && counted.photons_match());
+    // a header claiming ten records, eight of them photons, over six and four
+    let mut bytes = fs::read(&path).unwrap();
+    LittleEndian::write_i32(&mut bytes[5..9], 10);
+    LittleEndian::write_i32(&mut bytes[9..13], 8);
+    fs::write(&path, &bytes).unwrap();
+    let counted = count_records(&path).unwrap();
+    assert_eq!((counted.particles, counted.header_particles), (6, 10));
+    assert_eq!((counted.photons, counted.header_photons), (4, 8));
+    assert!(!counted.particles_match() && !counted.photons_match());
+    let parsed: Value = serde_json::from_str(&counted.to_json()).unwrap();
+    assert_eq!(parsed["particles"], Value::from(6));
+    assert_eq!(parsed["header_particles"], Value::from(10));
+}